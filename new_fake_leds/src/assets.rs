@@ -0,0 +1,139 @@
+use crate::error::ApiError;
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, Response, StatusCode},
+    response::IntoResponse,
+};
+use std::{
+    borrow::Cow,
+    fs,
+    hash::{DefaultHasher, Hasher},
+    path::{Component, Path, PathBuf},
+};
+
+/// Includes files of the repository, by their path relative to its root.
+macro_rules! embed {
+    ($($path:literal),* $(,)?) => {
+        &[$(($path, include_bytes!(concat!("../../", $path)) as &[u8])),*]
+    };
+}
+
+/// The web interface, compiled into the binary so it works from any directory.
+const EMBEDDED: &[(&str, &[u8])] = embed![
+    "templates/docs.html",
+    "templates/index.html",
+    "templates/viewer.html",
+    "static/script/capture_unidirectional.js",
+    "static/script/docs.js",
+    "static/script/effects.js",
+    "static/script/main.js",
+    "static/script/merge_directions.js",
+    "static/script/ui.js",
+    "static/script/viewer.js",
+];
+
+/// The files of the web interface.
+#[derive(Clone, Default)]
+pub struct Assets {
+    /// files in here take precedence over the embedded ones, so the web
+    /// interface can be changed without recompiling
+    dir: Option<PathBuf>,
+}
+
+impl Assets {
+    /// `dir` is laid out like the repository root, with `templates/` and `static/`.
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    /// The contents of `path`, relative to the repository root.
+    pub fn get(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        if let Some(dir) = &self.dir {
+            // never leave the directory
+            let relative = Path::new(path);
+            if relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                if let Ok(contents) = fs::read(dir.join(relative)) {
+                    return Some(Cow::Owned(contents));
+                }
+            }
+        }
+        EMBEDDED
+            .iter()
+            .find(|(name, _)| *name == path)
+            .map(|(_, contents)| Cow::Borrowed(*contents))
+    }
+
+    /// Serves `path` with its MIME type and an ETag, answers with 304 if the
+    /// client already has this version and 404 if there is no such file.
+    pub fn response(&self, path: &str, headers: &HeaderMap) -> Response<Body> {
+        let Some(contents) = self.get(path) else {
+            return ApiError::not_found(format!("there is no file {path}")).into_response();
+        };
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&contents);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        let cached = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|tags| tags.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+
+        let headers = [
+            (header::CONTENT_TYPE, mime_type(path).to_string()),
+            (header::ETAG, etag),
+            // always ask whether the file changed, it's cheap thanks to the ETag
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ];
+        if cached {
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+        (headers, contents.into_owned()).into_response()
+    }
+}
+
+fn mime_type(path: &str) -> &'static str {
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    match extension.map(|e| e.to_ascii_lowercase()).as_deref() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_dir_takes_precedence() {
+        let dir = std::env::temp_dir().join(format!("led_sim_assets_{}", std::process::id()));
+        fs::create_dir_all(dir.join("static/script")).unwrap();
+        fs::write(dir.join("static/script/effects.js"), "// changed").unwrap();
+        fs::write(dir.join("static/script/new.js"), "// new").unwrap();
+        let assets = Assets::new(Some(dir.clone()));
+
+        assert_eq!(
+            &*assets.get("static/script/effects.js").unwrap(),
+            b"// changed"
+        );
+        assert_eq!(&*assets.get("static/script/new.js").unwrap(), b"// new");
+        // everything else still comes from the binary
+        assert!(assets
+            .get("templates/index.html")
+            .unwrap()
+            .starts_with(b"<!DOCTYPE html>"));
+        assert!(assets.get("../Cargo.toml").is_none());
+        assert!(assets.get("static/script/missing.js").is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,523 @@
+use crate::state::AppState;
+use parking_lot::Mutex;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpListener,
+};
+use tracing::{info, warn};
+
+/// Samples per analyzed window.
+const WINDOW: usize = 1024;
+/// Samples between the starts of two windows.
+const HOP: usize = WINDOW / 2;
+/// Number of frequency bands, spaced logarithmically from `LOWEST_HZ` up.
+pub const BANDS: usize = 8;
+const LOWEST_HZ: f32 = 40.0;
+const HIGHEST_HZ: f32 = 16000.0;
+/// The lowest bands, up to about 140 Hz at any sample rate above 11 kHz.
+const BASS_BANDS: usize = 2;
+/// Seconds for the level a band is scaled by to fall to half, so quiet songs light up too.
+const GAIN_HALF_LIFE: f32 = 2.0;
+/// Below this a band counts as silent, instead of being scaled up.
+const GAIN_FLOOR: f32 = 0.01;
+/// Onsets are compared to the average of this many seconds before them.
+const ONSET_HISTORY: f32 = 0.5;
+/// An onset is a beat if it's this much stronger than the average ...
+const BEAT_THRESHOLD: f32 = 1.5;
+/// ... and at least this strong, so noise doesn't make beats ...
+const BEAT_MIN_ONSET: f32 = 0.005;
+/// ... and this many seconds have passed since the last beat.
+const BEAT_MIN_INTERVAL: f32 = 0.2;
+
+/// The `audio` section of the config file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// played along with the effect clock, starting at time zero
+    pub wav: Option<PathBuf>,
+    /// listen for a live stream, replacing `wav` while one is connected
+    pub pcm: Option<PcmConfig>,
+}
+
+/// A raw stream of signed 16 bit little endian samples over TCP, for example
+/// `ffmpeg -re -i song.mp3 -f s16le -ac 1 -ar 44100 tcp://127.0.0.1:9001`.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct PcmConfig {
+    pub bind: SocketAddr,
+    pub sample_rate: u32,
+    /// interleaved channels, they are mixed down to one
+    pub channels: u16,
+}
+
+impl Default for PcmConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 9001)),
+            sample_rate: 44100,
+            channels: 1,
+        }
+    }
+}
+
+/// What the effects know about the music at some time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AudioFeatures {
+    /// loudness of the last window, from 0 to 1
+    pub rms: f32,
+    /// energy per band, from low to high, each from 0 to 1 relative to its recent peak
+    pub bands: [f32; BANDS],
+    /// how suddenly the sound changed, from 0 up
+    pub onset: f32,
+    /// number of beats so far, changes with every beat
+    pub beats: u64,
+    /// seconds since the last beat, `None` before the first one
+    pub since_beat: Option<f32>,
+}
+
+impl AudioFeatures {
+    /// Energy of the lowest bands, from 0 to 1.
+    pub fn bass(&self) -> f32 {
+        self.bands[..BASS_BANDS].iter().sum::<f32>() / BASS_BANDS as f32
+    }
+}
+
+/// The analysis of one window.
+#[derive(Clone, Copy, Debug, Default)]
+struct Frame {
+    rms: f32,
+    bands: [f32; BANDS],
+    onset: f32,
+    beat: bool,
+}
+
+/// Turns samples into frames, one every `HOP` samples.
+pub struct Analyzer {
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    /// Hann window
+    window: Vec<f32>,
+    /// first FFT bin of every band, and the end of the last one
+    band_bins: [usize; BANDS + 1],
+    /// samples not analyzed yet, at least the last `WINDOW - HOP` analyzed ones are kept
+    pending: Vec<f32>,
+    /// `None` before the first window, which has nothing to compare to
+    previous_spectrum: Option<Vec<f32>>,
+    band_peaks: [f32; BANDS],
+    onset_peak: f32,
+    onsets: VecDeque<f32>,
+    frames_since_beat: usize,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32) -> Self {
+        let window = (0..WINDOW)
+            .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / WINDOW as f32).cos())
+            .collect();
+        let bin_hz = sample_rate as f32 / WINDOW as f32;
+        let highest = HIGHEST_HZ.min(sample_rate as f32 / 2.0);
+        let mut band_bins = [0; BANDS + 1];
+        for (i, bin) in band_bins.iter_mut().enumerate() {
+            let hz = LOWEST_HZ * (highest / LOWEST_HZ).powf(i as f32 / BANDS as f32);
+            *bin = ((hz / bin_hz).round() as usize).clamp(1, WINDOW / 2);
+        }
+        Self {
+            sample_rate,
+            fft: FftPlanner::new().plan_fft_forward(WINDOW),
+            window,
+            band_bins,
+            pending: Vec::with_capacity(WINDOW * 2),
+            previous_spectrum: None,
+            band_peaks: [GAIN_FLOOR; BANDS],
+            onset_peak: BEAT_MIN_ONSET,
+            onsets: VecDeque::new(),
+            frames_since_beat: usize::MAX,
+        }
+    }
+
+    fn frames_per_second(&self) -> f32 {
+        self.sample_rate as f32 / HOP as f32
+    }
+
+    /// Analyzes every complete window in `samples` and what was left of earlier calls.
+    fn push(&mut self, samples: &[f32]) -> Vec<Frame> {
+        self.pending.extend_from_slice(samples);
+        let mut frames = Vec::new();
+        let mut start = 0;
+        while self.pending.len() - start >= WINDOW {
+            let window = self.pending[start..start + WINDOW].to_vec();
+            frames.push(self.analyze(&window));
+            start += HOP;
+        }
+        self.pending.drain(..start);
+        frames
+    }
+
+    fn analyze(&mut self, samples: &[f32]) -> Frame {
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / WINDOW as f32).sqrt();
+
+        let mut buffer: Vec<Complex<f32>> = samples
+            .iter()
+            .zip(&self.window)
+            .map(|(s, w)| Complex::new(s * w, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+        // a full scale sine comes out at about 1 in its bin
+        let spectrum: Vec<f32> = buffer[..WINDOW / 2]
+            .iter()
+            .map(|c| c.norm() / (WINDOW as f32 / 4.0))
+            .collect();
+
+        let decay = 0.5f32.powf(1.0 / (GAIN_HALF_LIFE * self.frames_per_second()));
+        let mut bands = [0.0; BANDS];
+        for (i, band) in bands.iter_mut().enumerate() {
+            let bins =
+                &spectrum[self.band_bins[i]..self.band_bins[i + 1].max(self.band_bins[i] + 1)];
+            // summed, so a single tone counts as much in a wide band as in a narrow one
+            let energy = bins.iter().sum::<f32>();
+            self.band_peaks[i] = (self.band_peaks[i] * decay).max(energy).max(GAIN_FLOOR);
+            *band = energy / self.band_peaks[i];
+        }
+
+        // spectral flux: how much louder the bins got, compressed so loud bins don't drown the rest
+        let compress = |m: f32| (1.0 + 10.0 * m).ln();
+        let previous = self.previous_spectrum.as_ref().unwrap_or(&spectrum);
+        let flux = spectrum
+            .iter()
+            .zip(previous)
+            .map(|(now, before)| (compress(*now) - compress(*before)).max(0.0))
+            .sum::<f32>()
+            / spectrum.len() as f32;
+        self.previous_spectrum = Some(spectrum);
+
+        let average = self.onsets.iter().sum::<f32>() / self.onsets.len().max(1) as f32;
+        let min_interval = (BEAT_MIN_INTERVAL * self.frames_per_second()) as usize;
+        let beat = flux > BEAT_MIN_ONSET
+            && flux > average * BEAT_THRESHOLD
+            && self.frames_since_beat >= min_interval;
+        self.frames_since_beat = if beat {
+            0
+        } else {
+            self.frames_since_beat.saturating_add(1)
+        };
+        self.onsets.push_back(flux);
+        if self.onsets.len() > (ONSET_HISTORY * self.frames_per_second()) as usize {
+            self.onsets.pop_front();
+        }
+        self.onset_peak = (self.onset_peak * decay).max(flux);
+
+        Frame {
+            rms: rms.min(1.0),
+            bands,
+            onset: flux / self.onset_peak,
+            beat,
+        }
+    }
+}
+
+/// A whole recording, analyzed up front so it can be looked up at any time of the clock.
+pub struct Track {
+    sample_rate: u32,
+    frames: Vec<Frame>,
+    /// indices of the frames with a beat
+    beats: Vec<usize>,
+}
+
+impl Track {
+    pub fn analyze(samples: &[f32], sample_rate: u32) -> Self {
+        let frames = Analyzer::new(sample_rate).push(samples);
+        let beats = (0..frames.len()).filter(|&i| frames[i].beat).collect();
+        Self {
+            sample_rate,
+            frames,
+            beats,
+        }
+    }
+
+    /// Reads any WAV file hound understands, channels are mixed down to one.
+    pub fn from_wav(path: &Path) -> Result<Self, String> {
+        let error = |e: hound::Error| format!("could not read {}: {e}", path.display());
+        let mut reader = hound::WavReader::open(path).map_err(error)?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect()
+            }
+        }
+        .map_err(error)?;
+        let samples = to_mono(&samples, spec.channels);
+        let track = Self::analyze(&samples, spec.sample_rate);
+        info!(
+            "analyzed {}: {:.1} s, {} beats",
+            path.display(),
+            samples.len() as f32 / spec.sample_rate as f32,
+            track.beats.len()
+        );
+        Ok(track)
+    }
+
+    /// When frame `i` is complete.
+    fn frame_time(&self, i: usize) -> f32 {
+        (i * HOP + WINDOW) as f32 / self.sample_rate as f32
+    }
+
+    /// The last frame completed at `time`, silence before the first and after the last.
+    pub fn features(&self, time: Duration) -> AudioFeatures {
+        let samples = (time.as_secs_f64() * self.sample_rate as f64) as usize;
+        let Some(i) = samples.checked_sub(WINDOW).map(|s| s / HOP) else {
+            return AudioFeatures::default();
+        };
+        let beats = self.beats.partition_point(|&b| b <= i);
+        let since_beat = beats
+            .checked_sub(1)
+            .map(|b| time.as_secs_f32() - self.frame_time(self.beats[b]));
+        let Some(frame) = self.frames.get(i) else {
+            return AudioFeatures {
+                beats: beats as u64,
+                since_beat,
+                ..AudioFeatures::default()
+            };
+        };
+        AudioFeatures {
+            rms: frame.rms,
+            bands: frame.bands,
+            onset: frame.onset,
+            beats: beats as u64,
+            since_beat,
+        }
+    }
+}
+
+/// A stream being analyzed as it comes in.
+pub struct Live {
+    analyzer: Analyzer,
+    latest: Frame,
+    beats: u64,
+    last_beat: Option<Instant>,
+}
+
+impl Live {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            analyzer: Analyzer::new(sample_rate),
+            latest: Frame::default(),
+            beats: 0,
+            last_beat: None,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in self.analyzer.push(samples) {
+            if frame.beat {
+                self.beats += 1;
+                self.last_beat = Some(Instant::now());
+            }
+            self.latest = frame;
+        }
+    }
+
+    /// The latest frame, it's live so the effect clock doesn't matter.
+    pub fn features(&self) -> AudioFeatures {
+        AudioFeatures {
+            rms: self.latest.rms,
+            bands: self.latest.bands,
+            onset: self.latest.onset,
+            beats: self.beats,
+            since_beat: self.last_beat.map(|t| t.elapsed().as_secs_f32()),
+        }
+    }
+}
+
+/// Where the effects get their music from.
+#[derive(Clone, Default)]
+pub enum Audio {
+    #[default]
+    Silent,
+    Track(Arc<Track>),
+    Live(Arc<Mutex<Live>>),
+}
+
+impl Audio {
+    /// The music at time `now` of the effect clock.
+    pub fn features(&self, now: Duration) -> AudioFeatures {
+        match self {
+            Audio::Silent => AudioFeatures::default(),
+            Audio::Track(track) => track.features(now),
+            Audio::Live(live) => live.lock().features(),
+        }
+    }
+}
+
+/// Averages interleaved `channels` into one.
+fn to_mono(samples: &[f32], channels: u16) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Feeds the stream into `live` until it ends.
+async fn read_pcm(
+    mut stream: impl AsyncRead + Unpin,
+    live: &Mutex<Live>,
+    channels: u16,
+) -> std::io::Result<()> {
+    let mut buffer = vec![0; 8192];
+    let mut pending = Vec::new();
+    loop {
+        let len = stream.read(&mut buffer).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buffer[..len]);
+        // a sample of every channel makes a frame, keep the incomplete one for the next read
+        let frame_size = 2 * channels.max(1) as usize;
+        let whole = pending.len() / frame_size * frame_size;
+        let samples: Vec<f32> = pending[..whole]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect();
+        pending.drain(..whole);
+        live.lock().push(&to_mono(&samples, channels));
+    }
+}
+
+/// Accepts PCM streams one after the other, the effects follow the one that
+/// is connected and go silent when it ends.
+pub async fn receive_pcm(state: Arc<Mutex<AppState>>, config: PcmConfig) {
+    let listener = match TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("could not listen for PCM on {}: {e}", config.bind);
+            return;
+        }
+    };
+    info!("listening for PCM on {}", config.bind);
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("could not accept a PCM stream: {e}");
+                continue;
+            }
+        };
+        info!("receiving PCM from {from}");
+        let live = Arc::new(Mutex::new(Live::new(config.sample_rate)));
+        let previous = std::mem::replace(&mut state.lock().audio, Audio::Live(live.clone()));
+        if let Err(e) = read_pcm(stream, &live, config.channels).await {
+            warn!("PCM stream from {from} failed: {e}");
+        }
+        info!("PCM stream from {from} ended");
+        state.lock().audio = previous;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three seconds at 11025 Hz: a kick drum every half second, starting at
+    /// 0.25 s, over a quiet 3 kHz tone.
+    fn fixture() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/kick_120bpm.wav")
+    }
+
+    const KICKS: [f32; 6] = [0.25, 0.75, 1.25, 1.75, 2.25, 2.75];
+
+    #[test]
+    fn finds_the_kicks() {
+        let track = Track::from_wav(&fixture()).unwrap();
+        assert_eq!(track.beats.len(), KICKS.len());
+        for (&beat, kick) in track.beats.iter().zip(KICKS) {
+            let time = track.frame_time(beat);
+            assert!(
+                (kick..kick + 0.15).contains(&time),
+                "beat at {time} s, kick at {kick} s"
+            );
+        }
+    }
+
+    #[test]
+    fn follows_the_clock() {
+        let track = Track::from_wav(&fixture()).unwrap();
+        let at = |secs: f32| track.features(Duration::from_secs_f32(secs));
+
+        // before the first window is complete
+        assert_eq!(at(0.05), AudioFeatures::default());
+        let before = at(0.2);
+        assert_eq!(before.beats, 0);
+        assert!(before.bass() < 0.2, "{before:?}");
+        // the tone is always there
+        assert!(before.bands[BANDS - 1] > 0.5, "{before:?}");
+
+        let kick = at(0.35);
+        assert_eq!(kick.beats, 1);
+        assert!(kick.since_beat.unwrap() < 0.15);
+        assert!(kick.bass() > 0.5, "{kick:?}");
+        assert!(kick.rms > before.rms);
+
+        // seeking back and forth gives the same answers
+        assert_eq!(at(1.4), at(1.4));
+        assert_eq!(at(1.4).beats, 3);
+        assert_eq!(at(0.35), kick);
+
+        // after the end the beats are still counted, but it's quiet
+        let after = at(10.0);
+        assert_eq!(after.beats, KICKS.len() as u64);
+        assert_eq!(after.rms, 0.0);
+    }
+
+    #[test]
+    fn silence_has_no_beats() {
+        let track = Track::analyze(&vec![0.0; 44100], 44100);
+        assert!(track.beats.is_empty());
+        assert_eq!(
+            track.features(Duration::from_millis(500)),
+            AudioFeatures::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn analyzes_a_pcm_stream() {
+        let reader = hound::WavReader::open(fixture()).unwrap();
+        let sample_rate = reader.spec().sample_rate;
+        // as stereo, to check the channels are mixed down
+        let bytes: Vec<u8> = reader
+            .into_samples::<i16>()
+            .flat_map(|s| {
+                let s = s.unwrap().to_le_bytes();
+                [s, s].concat()
+            })
+            .collect();
+
+        let live = Mutex::new(Live::new(sample_rate));
+        // odd chunks, so samples are split across reads
+        let (mut writer, reader) = tokio::io::duplex(333);
+        let send = async move {
+            tokio::io::AsyncWriteExt::write_all(&mut writer, &bytes)
+                .await
+                .unwrap();
+        };
+        let (_, result) = tokio::join!(send, read_pcm(reader, &live, 2));
+        result.unwrap();
+
+        let features = live.lock().features();
+        assert_eq!(features.beats, KICKS.len() as u64);
+        assert!(features.since_beat.is_some());
+    }
+}
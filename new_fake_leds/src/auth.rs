@@ -0,0 +1,455 @@
+use crate::error::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::debug;
+use utoipa::{
+    openapi::{
+        security::{
+            ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme,
+        },
+        ContentBuilder, OpenApi, PathItemType, Ref, ResponseBuilder,
+    },
+    Modify,
+};
+
+/// Name of the cookie that keeps the token of a browser.
+const COOKIE: &str = "led_token";
+
+/// What a token allows, every role may do everything the roles before it may.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// watch the tree: all `GET` routes and `/ws/leds`
+    Viewer,
+    /// pick effects and colors, set the brightness and drive the clock
+    Operator,
+    /// everything, including calibration and changing the LEDs
+    Admin,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Token {
+    pub token: String,
+    pub role: Role,
+    /// who the token was handed to, for the log
+    #[serde(default)]
+    pub name: String,
+}
+
+/// The `auth` section of the config file. Without any tokens everyone may do everything.
+///
+/// The tokens only guard the HTTP API and its WebSockets. OSC and MQTT don't
+/// check them: anyone who can send UDP to the OSC `bind` address, which listens
+/// on all interfaces by default, or publish to the broker controls the tree.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub tokens: Vec<Token>,
+    /// role of requests without a token, they may do nothing but load the web interface if not set
+    pub anonymous: Option<Role>,
+}
+
+/// The role needed for a request, `None` if anyone may make it.
+///
+/// The web interface itself is public, so a browser can load it and then pass
+/// its token with the API calls.
+pub fn required_role(method: &Method, path: &str) -> Option<Role> {
+    let public = ["/", "/viewer", "/docs", "/openapi.json"];
+    if public.contains(&path) || path.starts_with("/static/") {
+        return None;
+    }
+    // every tree has the same routes, under `/trees/{id}`
+    let path = match path.strip_prefix("/trees/").map(|rest| rest.find('/')) {
+        Some(Some(slash)) => &path["/trees/".len() + slash..],
+        _ => path,
+    };
+    if path == "/ws/control" {
+        return Some(Role::Admin);
+    }
+    if method == Method::GET || method == Method::HEAD {
+        return Some(Role::Viewer);
+    }
+    if path.starts_with("/effects/") || path == "/clock" || path == "/brightness" {
+        return Some(Role::Operator);
+    }
+    // defining groups is up to admins, using them is not
+    if path.starts_with("/groups/") && (path.ends_with("/color") || path.ends_with("/effect")) {
+        return Some(Role::Operator);
+    }
+    Some(Role::Admin)
+}
+
+/// The token of a request: `Authorization: Bearer <token>`, `?token=<token>`
+/// (for links and WebSockets) or the cookie set by an earlier `?token=`.
+fn request_token(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let cookie = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(COOKIE)?.strip_prefix('='))
+        .and_then(|v| percent_decode_str(v).decode_utf8().ok())
+        .map(|v| v.into_owned());
+    bearer
+        .or_else(|| query_token(query))
+        .or(cookie)
+        .map(|t| t.trim().to_string())
+}
+
+/// The percent-decoded `token` parameter of the query string.
+fn query_token(query: Option<&str>) -> Option<String> {
+    form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == "token")
+        .map(|(_, token)| token.into_owned())
+}
+
+/// Compares without stopping at the first difference, so the time taken tells nothing about the token.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+/// Middleware rejecting requests whose token doesn't allow them, see `required_role`.
+pub async fn require_role(
+    State(config): State<Arc<AuthConfig>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if config.tokens.is_empty() {
+        return next.run(request).await;
+    }
+
+    let token = request_token(request.headers(), request.uri().query());
+    let known = token
+        .as_deref()
+        .and_then(|t| config.tokens.iter().find(|k| same_token(&k.token, t)));
+    if let Some(required) = required_role(request.method(), request.uri().path()) {
+        let role = match (&token, known) {
+            (Some(_), None) => return unauthorized("unknown token"),
+            (_, Some(known)) => Some(known.role),
+            (None, None) => config.anonymous,
+        };
+        match role {
+            None => return unauthorized("a token is needed, as a bearer token, ?token= or cookie"),
+            Some(role) if role < required => {
+                return ApiError::forbidden(format!("this needs the role {required:?}"))
+                    .into_response();
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(known) = known {
+        debug!(
+            "{} {} by {}",
+            request.method(),
+            request.uri().path(),
+            known.name
+        );
+    }
+
+    // remember a token given in the URL, so the page opened with it can use the API,
+    // encoded like in the URL as cookie values can't hold every character
+    let cookie = known
+        .filter(|k| query_token(request.uri().query()).as_deref() == Some(k.token.as_str()))
+        .and_then(|k| {
+            HeaderValue::from_str(&format!(
+                "{COOKIE}={}; Path=/; HttpOnly; SameSite=Strict",
+                utf8_percent_encode(&k.token, NON_ALPHANUMERIC)
+            ))
+            .ok()
+        });
+    let mut response = next.run(request).await;
+    if let Some(cookie) = cookie {
+        response.headers_mut().append(header::SET_COOKIE, cookie);
+    }
+    response
+}
+
+/// Adds the tokens to the OpenAPI document: how they are passed, and the 401
+/// and 403 responses of every route that needs one.
+pub struct ApiSecurity;
+
+impl Modify for ApiSecurity {
+    fn modify(&self, openapi: &mut OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        let bearer = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some(
+                "only needed if the server has tokens in the `auth` section of its config",
+            ))
+            .build();
+        components.add_security_scheme("bearer", SecurityScheme::Http(bearer));
+        components.add_security_scheme(
+            "query",
+            SecurityScheme::ApiKey(ApiKey::Query(ApiKeyValue::new("token"))),
+        );
+        components.add_security_scheme(
+            "cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(COOKIE))),
+        );
+
+        let error = |description: String| {
+            let body = ContentBuilder::new()
+                .schema(Ref::from_schema_name("ErrorBody"))
+                .build();
+            ResponseBuilder::new()
+                .description(description)
+                .content("application/json", body)
+                .build()
+        };
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            for (kind, operation) in item.operations.iter_mut() {
+                let method = match kind {
+                    PathItemType::Get => Method::GET,
+                    PathItemType::Head => Method::HEAD,
+                    _ => Method::POST,
+                };
+                let Some(role) = required_role(&method, path) else {
+                    continue;
+                };
+                // any one of them
+                operation.security = Some(
+                    ["bearer", "query", "cookie"]
+                        .map(|scheme| SecurityRequirement::new(scheme, Vec::<String>::new()))
+                        .to_vec(),
+                );
+                let responses = &mut operation.responses.responses;
+                responses.insert(
+                    "401".to_string(),
+                    error("there is no token, or an unknown one".to_string()).into(),
+                );
+                responses.insert(
+                    "403".to_string(),
+                    error(format!("the token doesn't have the role {role:?}")).into(),
+                );
+            }
+        }
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    let mut response = ApiError::unauthorized(message).into_response();
+    response
+        .headers_mut()
+        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::StatusCode,
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    fn app(anonymous: Option<Role>) -> Router {
+        let config = AuthConfig {
+            tokens: [
+                ("v", Role::Viewer),
+                ("o", Role::Operator),
+                ("a", Role::Admin),
+                ("o p+q/r=s;t", Role::Operator),
+            ]
+            .into_iter()
+            .map(|(token, role)| Token {
+                token: token.to_string(),
+                role,
+                name: String::new(),
+            })
+            .collect(),
+            anonymous,
+        };
+        let ok = || async { "ok" };
+        Router::new()
+            .route("/", get(ok))
+            .route("/get_num_leds", get(ok))
+            .route("/effects/blink", post(ok))
+            .route("/set_num_leds", post(ok))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(config),
+                require_role,
+            ))
+    }
+
+    async fn status(app: &Router, method: &str, uri: &str, header: Option<(&str, &str)>) -> u16 {
+        let mut request = axum::http::Request::builder().method(method).uri(uri);
+        if let Some((name, value)) = header {
+            request = request.header(name, value);
+        }
+        let response = app
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.status().as_u16()
+    }
+
+    #[test]
+    fn roles() {
+        assert_eq!(required_role(&Method::GET, "/"), None);
+        assert_eq!(required_role(&Method::GET, "/static/script/ui.js"), None);
+        assert_eq!(required_role(&Method::GET, "/ws/leds"), Some(Role::Viewer));
+        assert_eq!(
+            required_role(&Method::GET, "/ws/control"),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/effects/blink"),
+            Some(Role::Operator)
+        );
+        assert_eq!(required_role(&Method::POST, "/clock"), Some(Role::Operator));
+        assert_eq!(
+            required_role(&Method::POST, "/brightness"),
+            Some(Role::Operator)
+        );
+        assert_eq!(required_role(&Method::POST, "/power"), Some(Role::Admin));
+        assert_eq!(
+            required_role(&Method::POST, "/groups/star/color"),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            required_role(&Method::DELETE, "/groups/star/color"),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/groups/star"),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/trees/lobby/effects/blink"),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            required_role(&Method::GET, "/trees/lobby/ws/control"),
+            Some(Role::Admin)
+        );
+        assert_eq!(required_role(&Method::GET, "/trees"), Some(Role::Viewer));
+        assert_eq!(
+            required_role(&Method::POST, "/set_num_leds"),
+            Some(Role::Admin)
+        );
+        assert_eq!(
+            required_role(&Method::POST, "/configure_leds"),
+            Some(Role::Admin)
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_grant_their_role() {
+        let app = app(None);
+        let bearer = |token| Some(("authorization", token));
+
+        assert_eq!(status(&app, "GET", "/", None).await, 200);
+        assert_eq!(status(&app, "GET", "/get_num_leds", None).await, 401);
+        assert_eq!(
+            status(&app, "GET", "/get_num_leds", bearer("Bearer x")).await,
+            401
+        );
+
+        assert_eq!(
+            status(&app, "GET", "/get_num_leds", bearer("Bearer v")).await,
+            200
+        );
+        assert_eq!(
+            status(&app, "POST", "/effects/blink", bearer("Bearer v")).await,
+            403
+        );
+
+        assert_eq!(
+            status(&app, "POST", "/effects/blink", bearer("Bearer o")).await,
+            200
+        );
+        assert_eq!(
+            status(&app, "POST", "/set_num_leds", bearer("Bearer o")).await,
+            403
+        );
+
+        assert_eq!(
+            status(&app, "POST", "/set_num_leds", bearer("Bearer a")).await,
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn anonymous_role() {
+        let app = app(Some(Role::Operator));
+        assert_eq!(status(&app, "POST", "/effects/blink", None).await, 200);
+        assert_eq!(status(&app, "POST", "/set_num_leds", None).await, 403);
+    }
+
+    #[tokio::test]
+    async fn query_token_is_kept_in_a_cookie() {
+        let app = app(None);
+        let request = axum::http::Request::builder()
+            .uri("/?token=o")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap();
+        assert_eq!(cookie, "led_token=o");
+
+        let header = Some(("cookie", cookie));
+        assert_eq!(status(&app, "POST", "/effects/blink", header).await, 200);
+        assert_eq!(
+            status(&app, "POST", "/effects/blink?token=a", None).await,
+            200
+        );
+        assert_eq!(status(&app, "POST", "/set_num_leds", header).await, 403);
+
+        // tokens are decoded, and encoded again in the cookie
+        let request = axum::http::Request::builder()
+            .uri("/?token=o+p%2Bq%2Fr%3Ds%3Bt")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap();
+        assert_eq!(cookie, "led_token=o%20p%2Bq%2Fr%3Ds%3Bt");
+        let header = Some(("cookie", cookie));
+        assert_eq!(status(&app, "POST", "/effects/blink", header).await, 200);
+        assert_eq!(
+            status(
+                &app,
+                "POST",
+                "/effects/blink?token=o%20p%2Bq%2Fr%3Ds%3Bt",
+                None
+            )
+            .await,
+            200
+        );
+        assert_eq!(
+            status(&app, "POST", "/effects/blink?token=o+p+q/r=s;t", None).await,
+            401
+        );
+    }
+
+    #[tokio::test]
+    async fn no_tokens_no_checks() {
+        let app = Router::new()
+            .route("/set_num_leds", post(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(AuthConfig::default()),
+                require_role,
+            ));
+        assert_eq!(status(&app, "POST", "/set_num_leds", None).await, 200);
+    }
+}
@@ -0,0 +1,483 @@
+use crate::state::{AppState, Vec3};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// Number of LEDs listed as worst offenders in a report.
+const NUM_WORST: usize = 10;
+/// Number of bins of the error histogram.
+const NUM_BINS: usize = 10;
+
+/// Similarity transform `p -> scale * rotation * p + translation`.
+#[derive(Clone, Copy, Serialize, ToSchema)]
+pub struct Similarity {
+    pub scale: f32,
+    /// row major 3x3 rotation matrix
+    #[schema(value_type = Vec<Vec<f32>>)]
+    pub rotation: [[f32; 3]; 3],
+    pub translation: Vec3,
+}
+
+impl Similarity {
+    pub fn apply(&self, p: Vec3) -> Vec3 {
+        let r = &self.rotation;
+        let rotated = Vec3 {
+            x: r[0][0] * p.x + r[0][1] * p.y + r[0][2] * p.z,
+            y: r[1][0] * p.x + r[1][1] * p.y + r[1][2] * p.z,
+            z: r[2][0] * p.x + r[2][1] * p.y + r[2][2] * p.z,
+        };
+        rotated * self.scale + self.translation
+    }
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct LedError {
+    pub index: usize,
+    /// `determined_position` mapped into the space of `actual_position`
+    pub aligned_position: Vec3,
+    /// distance between the aligned and the actual position
+    pub error: f32,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+pub struct HistogramBin {
+    pub from: f32,
+    pub to: f32,
+    pub count: usize,
+}
+
+/// How well the calibrated `determined_position`s match the `actual_position`s,
+/// after undoing the arbitrary scale, rotation and offset of the calibration.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct Report {
+    pub transform: Similarity,
+    pub num_leds: usize,
+    /// enabled LEDs left out because their position is still the origin
+    pub undetermined: usize,
+    pub rms_error: f32,
+    pub mean_error: f32,
+    pub max_error: f32,
+    /// relative to the size of the tree, the rms distance of the LEDs from their centroid
+    pub relative_rms_error: f32,
+    pub worst: Vec<LedError>,
+    pub histogram: Vec<HistogramBin>,
+    pub per_led: Vec<LedError>,
+}
+
+/// Compares determined and actual positions of all enabled LEDs.
+///
+/// LEDs still at the origin, where `set_num_leds` puts them, haven't been
+/// determined and are left out of the fit, `Report::undetermined` counts them.
+/// Fails if fewer than three LEDs are left.
+pub fn report(state: &AppState) -> Result<Report, String> {
+    let enabled = state.leds.iter().filter(|l| l.enabled).count();
+    let leds: Vec<(usize, Vec3, Vec3)> = state
+        .leds
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| l.enabled && l.determined_position != Vec3::ZERO)
+        .map(|(i, l)| (i, l.determined_position, l.actual_position))
+        .collect();
+    if leds.len() < 3 {
+        return Err(format!(
+            "at least three enabled LEDs with a determined position are needed for a report, found {}",
+            leds.len()
+        ));
+    }
+
+    let source: Vec<Vec3> = leds.iter().map(|(_, d, _)| *d).collect();
+    let target: Vec<Vec3> = leds.iter().map(|(_, _, a)| *a).collect();
+    let transform = align(&source, &target)
+        .ok_or_else(|| "LED positions have not been determined yet".to_string())?;
+
+    let per_led: Vec<LedError> = leds
+        .iter()
+        .map(|&(index, determined, actual)| {
+            let aligned_position = transform.apply(determined);
+            LedError {
+                index,
+                aligned_position,
+                error: (aligned_position - actual).length(),
+            }
+        })
+        .collect();
+
+    let n = per_led.len() as f32;
+    let rms_error = (per_led.iter().map(|e| e.error * e.error).sum::<f32>() / n).sqrt();
+    let mean_error = per_led.iter().map(|e| e.error).sum::<f32>() / n;
+    let max_error = per_led.iter().map(|e| e.error).fold(0.0, f32::max);
+
+    let center = Vec3::centroid(target.iter().copied());
+    let spread = (target
+        .iter()
+        .map(|p| (*p - center).dot(*p - center))
+        .sum::<f32>()
+        / n)
+        .sqrt();
+    let relative_rms_error = if spread > 0.0 {
+        rms_error / spread
+    } else {
+        0.0
+    };
+
+    let mut worst = per_led.clone();
+    worst.sort_by(|a, b| b.error.total_cmp(&a.error));
+    worst.truncate(NUM_WORST);
+
+    let bin_width = if max_error > 0.0 {
+        max_error / NUM_BINS as f32
+    } else {
+        1.0
+    };
+    let mut histogram: Vec<HistogramBin> = (0..NUM_BINS)
+        .map(|i| HistogramBin {
+            from: i as f32 * bin_width,
+            to: (i + 1) as f32 * bin_width,
+            count: 0,
+        })
+        .collect();
+    for e in &per_led {
+        let bin = ((e.error / bin_width) as usize).min(NUM_BINS - 1);
+        histogram[bin].count += 1;
+    }
+
+    Ok(Report {
+        transform,
+        num_leds: per_led.len(),
+        undetermined: enabled - per_led.len(),
+        rms_error,
+        mean_error,
+        max_error,
+        relative_rms_error,
+        worst,
+        histogram,
+        per_led,
+    })
+}
+
+/// Finds the similarity transform that maps `source` onto `target` with the
+/// least squared error (Umeyama). The rotation is found with Horn's quaternion
+/// method, so it is always a proper rotation and never a reflection.
+///
+/// Returns `None` if the source points all coincide.
+pub fn align(source: &[Vec3], target: &[Vec3]) -> Option<Similarity> {
+    let source_center = Vec3::centroid(source.iter().copied());
+    let target_center = Vec3::centroid(target.iter().copied());
+
+    // cross covariance and variance of the centered point clouds
+    let mut s = [[0.0f64; 3]; 3];
+    let mut source_variance = 0.0f64;
+    for (a, b) in source.iter().zip(target) {
+        let a = *a - source_center;
+        let b = *b - target_center;
+        let a = [a.x as f64, a.y as f64, a.z as f64];
+        let b = [b.x as f64, b.y as f64, b.z as f64];
+        for i in 0..3 {
+            for j in 0..3 {
+                s[i][j] += a[i] * b[j];
+            }
+        }
+        source_variance += a.iter().map(|v| v * v).sum::<f64>();
+    }
+    if source_variance < 1e-12 {
+        return None;
+    }
+
+    // Horn's symmetric 4x4 matrix, its dominant eigenvector is the rotation quaternion
+    let n = [
+        [
+            s[0][0] + s[1][1] + s[2][2],
+            s[1][2] - s[2][1],
+            s[2][0] - s[0][2],
+            s[0][1] - s[1][0],
+        ],
+        [
+            s[1][2] - s[2][1],
+            s[0][0] - s[1][1] - s[2][2],
+            s[0][1] + s[1][0],
+            s[2][0] + s[0][2],
+        ],
+        [
+            s[2][0] - s[0][2],
+            s[0][1] + s[1][0],
+            -s[0][0] + s[1][1] - s[2][2],
+            s[1][2] + s[2][1],
+        ],
+        [
+            s[0][1] - s[1][0],
+            s[2][0] + s[0][2],
+            s[1][2] + s[2][1],
+            -s[0][0] - s[1][1] + s[2][2],
+        ],
+    ];
+    let (eigenvalues, eigenvectors) = jacobi_eigen(n);
+    let best = (0..4)
+        .max_by(|&a, &b| eigenvalues[a].total_cmp(&eigenvalues[b]))
+        .unwrap();
+    let [w, x, y, z] = [0, 1, 2, 3].map(|i| eigenvectors[i][best]);
+
+    let r = [
+        [
+            w * w + x * x - y * y - z * z,
+            2.0 * (x * y - w * z),
+            2.0 * (x * z + w * y),
+        ],
+        [
+            2.0 * (x * y + w * z),
+            w * w - x * x + y * y - z * z,
+            2.0 * (y * z - w * x),
+        ],
+        [
+            2.0 * (x * z - w * y),
+            2.0 * (y * z + w * x),
+            w * w - x * x - y * y + z * z,
+        ],
+    ];
+
+    // trace(R^T S^T) = sum of b . R a over all point pairs
+    let mut correlation = 0.0;
+    for i in 0..3 {
+        for j in 0..3 {
+            correlation += r[i][j] * s[j][i];
+        }
+    }
+    let scale = (correlation / source_variance) as f32;
+
+    let mut transform = Similarity {
+        scale,
+        rotation: r.map(|row| row.map(|v| v as f32)),
+        translation: Vec3::ZERO,
+    };
+    transform.translation = target_center - transform.apply(source_center);
+    Some(transform)
+}
+
+/// Eigen decomposition of a symmetric 4x4 matrix with the cyclic Jacobi method.
+/// Returns the eigenvalues and a matrix with the eigenvectors as columns.
+fn jacobi_eigen(mut a: [[f64; 4]; 4]) -> ([f64; 4], [[f64; 4]; 4]) {
+    let mut v = [[0.0; 4]; 4];
+    for (i, row) in v.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+
+    for _sweep in 0..50 {
+        let off_diagonal: f64 = (0..4)
+            .flat_map(|i| (0..4).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j] * a[i][j])
+            .sum();
+        if off_diagonal < 1e-20 {
+            break;
+        }
+
+        for p in 0..4 {
+            for q in (p + 1)..4 {
+                if a[p][q].abs() < 1e-30 {
+                    continue;
+                }
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                // a = J^T a J, with J the rotation in the p-q plane
+                for row in a.iter_mut() {
+                    let akp = row[p];
+                    let akq = row[q];
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (row_p, row_q) = (a[p], a[q]);
+                for k in 0..4 {
+                    a[p][k] = c * row_p[k] - s * row_q[k];
+                    a[q][k] = s * row_p[k] + c * row_q[k];
+                }
+                for row in v.iter_mut() {
+                    let vkp = row[p];
+                    let vkq = row[q];
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2], a[3][3]], v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Layout, LayoutConfig};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// Rotation by `angle` radians about the unit vector `axis` (Rodrigues).
+    fn rotation(axis: Vec3, angle: f32) -> [[f32; 3]; 3] {
+        let (s, c) = angle.sin_cos();
+        let [x, y, z] = [axis.x, axis.y, axis.z];
+        let t = 1.0 - c;
+        [
+            [t * x * x + c, t * x * y - s * z, t * x * z + s * y],
+            [t * x * y + s * z, t * y * y + c, t * y * z - s * x],
+            [t * x * z - s * y, t * y * z + s * x, t * z * z + c],
+        ]
+    }
+
+    fn known() -> Similarity {
+        let axis = Vec3 {
+            x: 1.0,
+            y: -2.0,
+            z: 0.5,
+        };
+        Similarity {
+            scale: 2.5,
+            rotation: rotation(axis * (1.0 / axis.length()), 2.0),
+            translation: Vec3 {
+                x: 1.0,
+                y: -3.0,
+                z: 0.25,
+            },
+        }
+    }
+
+    fn assert_near(a: f32, b: f32, tolerance: f32) {
+        assert!((a - b).abs() <= tolerance, "{a} is not {b} ± {tolerance}");
+    }
+
+    fn assert_transform(found: &Similarity, wanted: &Similarity, tolerance: f32) {
+        assert_near(found.scale, wanted.scale, tolerance);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert_near(found.rotation[i][j], wanted.rotation[i][j], tolerance);
+            }
+        }
+        let t = found.translation - wanted.translation;
+        assert_near(t.length(), 0.0, tolerance);
+    }
+
+    fn random_points(n: usize, rng: &mut StdRng) -> Vec<Vec3> {
+        (0..n)
+            .map(|_| Vec3 {
+                x: rng.random_range(-1.0..1.0),
+                y: rng.random_range(-1.0..1.0),
+                z: rng.random_range(0.0..2.0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_a_known_transform() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let source = random_points(40, &mut rng);
+        let wanted = known();
+        let target: Vec<Vec3> = source.iter().map(|p| wanted.apply(*p)).collect();
+
+        let found = align(&source, &target).unwrap();
+        assert_transform(&found, &wanted, 1e-4);
+    }
+
+    #[test]
+    fn report_of_a_noisy_calibration() {
+        let layout = LayoutConfig {
+            layout: Layout::RandomCone,
+            seed: 2,
+        };
+        let mut state = AppState::new(100, layout, 2).unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        // the calibration sees the tree scaled, rotated and moved, with up to
+        // 0.01 of noise in each coordinate
+        let seen = known();
+        for led in &mut state.leds {
+            let noise = Vec3 {
+                x: rng.random_range(-0.01..0.01),
+                y: rng.random_range(-0.01..0.01),
+                z: rng.random_range(-0.01..0.01),
+            };
+            led.determined_position = seen.apply(led.actual_position) + noise;
+        }
+
+        let report = report(&state).unwrap();
+        assert_eq!(report.num_leds, 100);
+        assert_eq!(report.undetermined, 0);
+        // the report maps back, with the inverse of what the calibration saw
+        assert_near(report.transform.scale, 1.0 / seen.scale, 1e-3);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert_near(report.transform.rotation[i][j], seen.rotation[j][i], 1e-2);
+            }
+        }
+        for led in &state.leds {
+            let back = report.transform.apply(seen.apply(led.actual_position));
+            assert_near((back - led.actual_position).length(), 0.0, 1e-2);
+        }
+        // the noise, shrunk by the scale: at most sqrt(3) * 0.01 / 2.5
+        assert!(report.rms_error > 0.0);
+        assert!(report.rms_error < 0.007, "{}", report.rms_error);
+        assert!(report.max_error >= report.rms_error);
+        assert_eq!(report.histogram.iter().map(|b| b.count).sum::<usize>(), 100);
+        assert_eq!(report.worst[0].error, report.max_error);
+    }
+
+    #[test]
+    fn undetermined_leds_are_left_out() {
+        let layout = LayoutConfig {
+            layout: Layout::RandomCone,
+            seed: 2,
+        };
+        let mut state = AppState::new(20, layout, 2).unwrap();
+        assert!(report(&state).is_err());
+
+        // a perfect calibration of all but the first 5 LEDs, which are still at the origin
+        let seen = known();
+        for led in &mut state.leds[5..] {
+            led.determined_position = seen.apply(led.actual_position);
+        }
+        state.leds[19].enabled = false;
+        let report = report(&state).unwrap();
+        assert_eq!(report.num_leds, 14);
+        assert_eq!(report.undetermined, 5);
+        assert!(report.max_error < 1e-4, "{}", report.max_error);
+        assert!(report.per_led.iter().all(|e| (5..19).contains(&e.index)));
+    }
+
+    #[test]
+    fn coplanar_points() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let source: Vec<Vec3> = random_points(20, &mut rng)
+            .into_iter()
+            .map(|p| Vec3 { z: 1.0, ..p })
+            .collect();
+        let wanted = known();
+        let target: Vec<Vec3> = source.iter().map(|p| wanted.apply(*p)).collect();
+
+        // a plane still fixes the rotation, a reflection doesn't count
+        let found = align(&source, &target).unwrap();
+        assert_transform(&found, &wanted, 1e-4);
+    }
+
+    #[test]
+    fn collinear_points() {
+        let direction = Vec3 {
+            x: 0.6,
+            y: 0.0,
+            z: 0.8,
+        };
+        let source: Vec<Vec3> = (0..10).map(|i| direction * i as f32).collect();
+        let wanted = known();
+        let target: Vec<Vec3> = source.iter().map(|p| wanted.apply(*p)).collect();
+
+        // the rotation about the line can't be known, but the points are mapped
+        // onto their targets by a proper rotation
+        let found = align(&source, &target).unwrap();
+        assert_near(found.scale, wanted.scale, 1e-4);
+        for (s, t) in source.iter().zip(&target) {
+            assert_near((found.apply(*s) - *t).length(), 0.0, 1e-3);
+        }
+        let r = found.rotation;
+        let det = r[0][0] * (r[1][1] * r[2][2] - r[1][2] * r[2][1])
+            - r[0][1] * (r[1][0] * r[2][2] - r[1][2] * r[2][0])
+            + r[0][2] * (r[1][0] * r[2][1] - r[1][1] * r[2][0]);
+        assert_near(det, 1.0, 1e-4);
+
+        // all in one point there is nothing to align
+        assert!(align(&[direction; 4], &target[..4]).is_none());
+    }
+}
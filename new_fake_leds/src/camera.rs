@@ -0,0 +1,211 @@
+use crate::state::{AppState, Vec3};
+use egui::Color32;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+/// Largest `blur`, beyond it rendering the light spots of the LEDs takes too long.
+const MAX_BLUR: f32 = 50.0;
+
+/// Parameters of the simulated camera, as passed to `GET /sim/camera`.
+///
+/// The camera orbits the centroid of the tree. With `yaw = 0` it looks along
+/// +y, so the image x axis is the world x axis. With `yaw = 90` it looks along
+/// -x and the image x axis is the world y axis. These are the two views the
+/// web UI captures in its X and Y tabs. Positive `pitch` looks down onto the
+/// tree. Angles are in degrees.
+#[derive(Clone, Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct CameraParams {
+    pub yaw: f32,
+    pub pitch: f32,
+    pub w: u32,
+    pub h: u32,
+    /// vertical field of view in degrees
+    pub fov: f32,
+    /// distance of the camera from the center of the tree
+    pub distance: f32,
+    /// standard deviation of the light spot of a single LED, in pixels
+    pub blur: f32,
+    /// standard deviation of the per pixel sensor noise, in 0..255 units
+    pub noise: f32,
+    /// multiplier for the brightness of the LEDs
+    pub exposure: f32,
+    /// uniform background brightness, in 0..255 units
+    pub ambient: f32,
+    /// seed of the sensor noise, drawn from the simulator's random numbers if not given
+    pub seed: Option<u64>,
+}
+
+impl Default for CameraParams {
+    fn default() -> Self {
+        Self {
+            yaw: 0.0,
+            pitch: 0.0,
+            w: 640,
+            h: 480,
+            fov: 60.0,
+            distance: 4.0,
+            blur: 2.0,
+            noise: 3.0,
+            exposure: 1.0,
+            ambient: 20.0,
+            seed: None,
+        }
+    }
+}
+
+/// An RGB image, 3 bytes per pixel, rows top to bottom.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl CameraParams {
+    /// Rejects parameters that can't be rendered in reasonable time.
+    pub fn validate(&self) -> Result<(), String> {
+        let check = |name: &str, value: f32, range: std::ops::RangeInclusive<f32>| {
+            if range.contains(&value) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{name} must be between {} and {}, not {value}",
+                    range.start(),
+                    range.end()
+                ))
+            }
+        };
+        check("yaw", self.yaw, -360.0..=360.0)?;
+        check("pitch", self.pitch, -90.0..=90.0)?;
+        check("fov", self.fov, 1.0..=170.0)?;
+        check("distance", self.distance, 0.01..=1000.0)?;
+        check("blur", self.blur, 0.0..=MAX_BLUR)?;
+        check("noise", self.noise, 0.0..=255.0)?;
+        check("exposure", self.exposure, 0.0..=100.0)?;
+        check("ambient", self.ambient, 0.0..=255.0)
+    }
+}
+
+/// What the camera sees of the tree, taken while the state is locked so the
+/// image can be rendered without holding it.
+pub struct Scene {
+    leds: Vec<(Vec3, Color32)>,
+    seed: u64,
+}
+
+impl Scene {
+    pub fn new(state: &mut AppState, params: &CameraParams) -> Self {
+        Self {
+            leds: state
+                .leds
+                .iter()
+                .map(|l| (l.actual_position, state.output_color(l)))
+                .collect(),
+            seed: params.seed.unwrap_or_else(|| state.rng.random()),
+        }
+    }
+}
+
+/// Renders what a camera would see of the LEDs at their `actual_position`.
+/// `params` must have passed `validate`.
+pub fn render(scene: &Scene, params: &CameraParams) -> Image {
+    // refuse absurd sizes instead of allocating gigabytes
+    let width = params.w.clamp(1, 4096);
+    let height = params.h.clamp(1, 4096);
+    let leds = &scene.leds;
+
+    // look at the centroid of the tree
+    let target = Vec3::centroid(leds.iter().map(|(p, _)| *p));
+
+    let yaw = params.yaw.to_radians();
+    let pitch = params.pitch.to_radians();
+    let forward = Vec3 {
+        x: -yaw.sin() * pitch.cos(),
+        y: yaw.cos() * pitch.cos(),
+        z: -pitch.sin(),
+    };
+    let right = Vec3 {
+        x: yaw.cos(),
+        y: yaw.sin(),
+        z: 0.0,
+    };
+    let up = right.cross(forward);
+    let camera = target - forward * params.distance;
+
+    let focal = (height as f32 / 2.0) / (params.fov.to_radians() / 2.0).tan();
+    let sigma = params.blur.clamp(0.1, MAX_BLUR);
+    let reach = (sigma * 3.0).ceil();
+
+    let mut buffer = vec![params.ambient; (width * height * 3) as usize];
+
+    for &(p, color) in leds {
+        if color == Color32::BLACK {
+            continue;
+        }
+        let v = p - camera;
+        let depth = v.dot(forward);
+        if depth <= 0.01 {
+            continue; // behind the camera
+        }
+        let cx = width as f32 / 2.0 + focal * v.dot(right) / depth;
+        let cy = height as f32 / 2.0 - focal * v.dot(up) / depth;
+        if !(-reach..width as f32 + reach).contains(&cx)
+            || !(-reach..height as f32 + reach).contains(&cy)
+        {
+            continue; // its light doesn't reach the image
+        }
+
+        let rgb = [color.r(), color.g(), color.b()].map(|c| c as f32 * params.exposure);
+        let rows = (cy - reach).max(0.0) as u32..(cy + reach + 1.0).min(height as f32) as u32;
+        for py in rows {
+            let columns = (cx - reach).max(0.0) as u32..(cx + reach + 1.0).min(width as f32) as u32;
+            for px in columns {
+                let dx = px as f32 + 0.5 - cx;
+                let dy = py as f32 + 0.5 - cy;
+                let falloff = (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp();
+                let i = ((py * width + px) * 3) as usize;
+                for c in 0..3 {
+                    buffer[i + c] += rgb[c] * falloff;
+                }
+            }
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(scene.seed);
+    let pixels = buffer
+        .into_iter()
+        .map(|v| (v + gaussian_noise(params.noise, &mut rng)).clamp(0.0, 255.0) as u8)
+        .collect();
+
+    Image {
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// Box-Muller transform, returns a sample of N(0, std_dev^2)
+fn gaussian_noise(std_dev: f32, rng: &mut StdRng) -> f32 {
+    if std_dev <= 0.0 {
+        return 0.0;
+    }
+    let u1: f32 = rng.random_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.random_range(0.0..1.0);
+    std_dev * (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos()
+}
+
+pub fn encode_png(image: &Image) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut out, image.width, image.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().expect("writing to a Vec can't fail");
+        writer
+            .write_image_data(&image.pixels)
+            .expect("buffer size matches the image dimensions");
+    }
+    out
+}
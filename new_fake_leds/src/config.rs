@@ -0,0 +1,110 @@
+use crate::{
+    auth::AuthConfig,
+    render::RenderArgs,
+    trees::{self, TreeConfig},
+};
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::{fs, path::PathBuf};
+
+#[derive(Parser)]
+#[command(about = "Simulates an LED strip on a christmas tree")]
+pub struct Cli {
+    /// JSON config file, options given on the command line take precedence
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// seed for all randomness, so layouts and effects can be reproduced
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// number of simulated LEDs of the first tree
+    #[arg(long)]
+    pub num_leds: Option<usize>,
+
+    /// serve the web interface from this directory (laid out like the repository
+    /// root) instead of the copy built into the binary, for working on it
+    #[arg(long)]
+    pub assets_dir: Option<PathBuf>,
+
+    /// run only the web server and the effects, without opening a window
+    #[arg(long)]
+    pub headless: bool,
+
+    /// without a command the GUI and the web server are started
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Render an effect to an animated GIF/PNG or a directory of PNG frames
+    Render(RenderArgs),
+}
+
+/// Settings of the simulator, read from the file given with `--config`.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// picked at random on startup if not set
+    pub seed: Option<u64>,
+    /// the first tree
+    #[serde(flatten)]
+    pub tree: TreeConfig,
+    /// more trees, served under `/trees/{id}`
+    pub trees: Vec<TreeConfig>,
+    /// see `Cli::assets_dir`
+    pub assets_dir: Option<PathBuf>,
+    /// tokens for the web server, it's open to everyone without
+    pub auth: AuthConfig,
+}
+
+impl Config {
+    /// Reads the config file named on the command line, if any, and applies
+    /// the command line overrides.
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+                serde_json::from_str(&contents)
+                    .map_err(|e| format!("could not parse {}: {e}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        if let Some(seed) = cli.seed {
+            config.seed = Some(seed);
+        }
+        if let Some(num_leds) = cli.num_leds {
+            config.tree.num_leds = num_leds;
+        }
+        if let Some(dir) = &cli.assets_dir {
+            config.assets_dir = Some(dir.clone());
+        }
+        for tree in config.all_trees() {
+            tree.midi
+                .validate()
+                .map_err(|e| format!("tree {:?}: {e}", tree.id))?;
+        }
+        trees::check_connections(config.all_trees())?;
+        Ok(config)
+    }
+
+    /// The first tree, then the others.
+    pub fn all_trees(&self) -> impl Iterator<Item = &TreeConfig> {
+        std::iter::once(&self.tree).chain(&self.trees)
+    }
+
+    /// The configured seed, or a random one that is logged so a run can be repeated.
+    pub fn seed(&self) -> u64 {
+        match self.seed {
+            Some(seed) => seed,
+            None => {
+                let seed = rand::random();
+                tracing::info!("using random seed {seed}, pass --seed {seed} to repeat this run");
+                seed
+            }
+        }
+    }
+}
@@ -1,51 +1,159 @@
-use std::{f32, time::Instant};
+use std::{f32, time::Duration};
 
 use crate::{
-    hsv_to_rgb,
+    groups, hsv_to_rgb, power,
     state::{AppState, Effect},
 };
 use egui::Color32;
+use rand::Rng;
+
+/// A short burst of light on part of the tree, drawn over the running effect
+/// and fading out over `length`.
+#[derive(Clone, Debug)]
+pub struct Flash {
+    pub region: Region,
+    pub color: Color32,
+    /// time of the clock the flash starts at
+    pub start: Duration,
+    pub length: Duration,
+}
+
+/// A part of the tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    All,
+    /// the LEDs with an index in the range, which is cut off at the last LED
+    Leds {
+        start: usize,
+        end: usize,
+    },
+    /// the LEDs between two heights, as fractions of the height of the tree (0 at the bottom, 1 at the top)
+    Height {
+        low: f32,
+        high: f32,
+    },
+}
+
+/// Renders the current effect at time `now` of the state's clock.
+pub fn update_effects(state: &mut AppState, now: Duration) {
+    // the song may start an effect, so it goes first
+    if let Some(mut player) = state.midi.take() {
+        player.play(state, now);
+        state.midi = Some(player);
+    }
+
+    if state.leds.is_empty() {
+        return;
+    }
+
+    // the clock was set back, restart the current cycle from there
+    if now < state.effect_start {
+        state.effect_start = now;
+    }
+
+    // effects only light the white LEDs where they show the base color
+    if state.effect != Effect::None {
+        for led in &mut state.leds {
+            led.white = 0;
+        }
+    }
 
-pub fn update_effects(state: &mut AppState) {
     match state.effect {
-        Effect::Blink => blink(state),
+        Effect::Blink => blink(state, now),
         Effect::AllOn => {
             for led in &mut state.leds {
                 if led.enabled {
                     led.color = state.base_color;
+                    led.white = state.base_white;
                 }
             }
         }
-        Effect::SweepingPlane => sweeping_plane(state),
+        Effect::SweepingPlane => sweeping_plane(state, now),
         Effect::SweepingPlaneX | Effect::SweepingPlaneY | Effect::SweepingPlaneZ => {
-            sweeping_plane_xyz(state)
+            sweeping_plane_xyz(state, now)
         }
-        Effect::ConcentricColor => concentric_color(state),
+        Effect::ConcentricColor => concentric_color(state, now),
+        Effect::BeatPulse => beat_pulse(state, now),
+        Effect::BassPlane => bass_plane(state, now),
         Effect::None => {}
     }
+    groups::apply(state);
+    draw_flashes(state, now);
+    power::limit(state);
+}
+
+/// Sets `Led::flash` from the flashes that are on at `now`, and forgets the others.
+fn draw_flashes(state: &mut AppState, now: Duration) {
+    // flashes from the future are gone too, the clock was set back before them
+    state
+        .flashes
+        .retain(|f| f.start <= now && now < f.start + f.length);
+    for led in &mut state.leds {
+        led.flash = Color32::BLACK;
+    }
+    if state.flashes.is_empty() {
+        return;
+    }
+
+    let (min_z, max_z) = state
+        .leds
+        .iter()
+        .map(|l| l.determined_position.z)
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), z| {
+            (lo.min(z), hi.max(z))
+        });
+    let height = (max_z - min_z).max(f32::EPSILON);
+
+    for flash in &state.flashes {
+        let fade = 1.0 - (now - flash.start).as_secs_f32() / flash.length.as_secs_f32();
+        let scale = |c: u8| (c as f32 * fade).round() as u8;
+        let color = Color32::from_rgb(
+            scale(flash.color.r()),
+            scale(flash.color.g()),
+            scale(flash.color.b()),
+        );
+        for (i, led) in state.leds.iter_mut().enumerate() {
+            let inside = match flash.region {
+                Region::All => true,
+                Region::Leds { start, end } => (start..end).contains(&i),
+                Region::Height { low, high } => {
+                    let z = (led.determined_position.z - min_z) / height;
+                    (low..=high).contains(&z)
+                }
+            };
+            if inside {
+                led.flash = Color32::from_rgb(
+                    led.flash.r().max(color.r()),
+                    led.flash.g().max(color.g()),
+                    led.flash.b().max(color.b()),
+                );
+            }
+        }
+    }
 }
 
-fn blink(state: &mut AppState) {
-    let elapsed = state.effect_start.elapsed().as_secs();
-    let on = elapsed % 2 == 0;
+fn blink(state: &mut AppState, now: Duration) {
+    let elapsed = now.saturating_sub(state.effect_start).as_secs();
+    let on = elapsed.is_multiple_of(2);
 
     for led in &mut state.leds {
         if led.enabled && on {
             led.color = state.base_color;
+            led.white = state.base_white;
         } else {
             led.color = Color32::BLACK;
         }
     }
 }
 
-fn sweeping_plane(state: &mut AppState) {
-    fn reset_sweeping_plane(state: &mut AppState) {
-        state.effect_start = Instant::now();
+fn sweeping_plane(state: &mut AppState, now: Duration) {
+    fn reset_sweeping_plane(state: &mut AppState, now: Duration) {
+        state.effect_start = now;
 
-        let theta: f32 = rand::random_range(0.0..f32::consts::TAU);
+        let theta: f32 = state.rng.random_range(0.0..f32::consts::TAU);
         let cos_theta = theta.cos();
         let sin_theta = theta.sin();
-        let alpha: f32 = rand::random_range(0.0..f32::consts::TAU);
+        let alpha: f32 = state.rng.random_range(0.0..f32::consts::TAU);
         let cos_alpha = alpha.cos();
         let sin_alpha = alpha.sin();
 
@@ -66,17 +174,17 @@ fn sweeping_plane(state: &mut AppState) {
         }
 
         // new hue
-        state.sweeping_plane_hue = rand::random_range(0.0..360.0);
+        state.sweeping_plane_hue = state.rng.random_range(0.0..360.0);
     }
 
-    let elapsed_ms = state.effect_start.elapsed().as_millis() as f32;
+    let elapsed_ms = now.saturating_sub(state.effect_start).as_millis() as f32;
 
     // color
     let color = hsv_to_rgb(state.sweeping_plane_hue, 1.00, 0.30);
 
     // initialize z positions on first run
     if state.sweeping_plane_z.len() != state.leds.len() {
-        reset_sweeping_plane(state);
+        reset_sweeping_plane(state, now);
     }
 
     // find max z
@@ -101,13 +209,13 @@ fn sweeping_plane(state: &mut AppState) {
 
     // reset when finished
     if &&plane_z > max_z {
-        reset_sweeping_plane(state);
+        reset_sweeping_plane(state, now);
     }
 }
 
-fn sweeping_plane_xyz(state: &mut AppState) {
-    fn reset_sweeping_plane_xyz(state: &mut AppState) {
-        state.effect_start = Instant::now();
+fn sweeping_plane_xyz(state: &mut AppState, now: Duration) {
+    fn reset_sweeping_plane_xyz(state: &mut AppState, now: Duration) {
+        state.effect_start = now;
         state.sweeping_plane_z = Vec::new();
 
         let mut min_z = f32::INFINITY;
@@ -132,11 +240,11 @@ fn sweeping_plane_xyz(state: &mut AppState) {
         }
     }
 
-    let elapsed_ms = state.effect_start.elapsed().as_millis() as f32;
+    let elapsed_ms = now.saturating_sub(state.effect_start).as_millis() as f32;
 
     // initialize z positions on first run
     if state.sweeping_plane_z.len() != state.leds.len() {
-        reset_sweeping_plane_xyz(state);
+        reset_sweeping_plane_xyz(state, now);
     }
 
     // find max z
@@ -154,6 +262,7 @@ fn sweeping_plane_xyz(state: &mut AppState) {
         let z = state.sweeping_plane_z[i];
         if (z - 0.1) < plane_z && plane_z < (z + 0.1) && led.enabled {
             led.color = state.base_color;
+            led.white = state.base_white;
         } else {
             led.color = Color32::BLACK;
         }
@@ -161,16 +270,26 @@ fn sweeping_plane_xyz(state: &mut AppState) {
 
     // reset when finished
     if &&plane_z > max_z {
-        reset_sweeping_plane_xyz(state);
+        reset_sweeping_plane_xyz(state, now);
     }
 }
 
-fn concentric_color(state: &mut AppState) {
-    let elapsed_ms = state.effect_start.elapsed().as_millis() as f32;
+/// Average height of the enabled LEDs.
+fn center_z(state: &AppState) -> f32 {
+    let sum_z: f32 = state
+        .leds
+        .iter()
+        .filter(|l| l.enabled)
+        .map(|l| l.determined_position.z)
+        .sum();
+    let count = state.leds.iter().filter(|l| l.enabled).count().max(1);
+    sum_z / count as f32
+}
+
+fn concentric_color(state: &mut AppState, now: Duration) {
+    let elapsed_ms = now.saturating_sub(state.effect_start).as_millis() as f32;
 
-    let sum_z: f32 = state.leds.iter().map(|l| l.determined_position.z).sum();
-    let count = state.leds.iter().filter(|l| l.enabled).count();
-    let center_z: f32 = sum_z / count as f32;
+    let center_z = center_z(state);
 
     // calculate color
     let color = hsv_to_rgb(state.concentric_color_hue, 1.0, 0.30);
@@ -197,8 +316,288 @@ fn concentric_color(state: &mut AppState) {
     }
 
     // if this color covers all, reset with new color
-    if radius_reached > max_radius_squared {
-        state.concentric_color_hue = rand::random_range(0.0..360.0);
-        state.effect_start = Instant::now();
+    if radius_reached_squared > max_radius_squared {
+        state.concentric_color_hue = state.rng.random_range(0.0..360.0);
+        state.effect_start = now;
+    }
+}
+
+/// Like `concentric_color`, but every beat of the music sends out a sphere of a
+/// new color that fades as it grows.
+fn beat_pulse(state: &mut AppState, now: Duration) {
+    // how long a pulse lasts, and how fast it grows (units per second)
+    const PULSE_SECS: f32 = 0.5;
+    const PULSE_SPEED: f32 = 4.0;
+
+    let audio = state.audio.features(now);
+    let (radius, value) = match audio.since_beat {
+        Some(t) if t < PULSE_SECS => (t * PULSE_SPEED, 0.30 * (1.0 - t / PULSE_SECS)),
+        _ => (0.0, 0.0),
+    };
+    // derived from the beat, not the random number generator, so seeking the clock gives the same colors
+    let color = hsv_to_rgb((audio.beats as f32 * 137.5) % 360.0, 1.0, value);
+
+    let center_z = center_z(state);
+    for led in &mut state.leds {
+        let p = led.determined_position;
+        let radius_squared = p.x.powi(2) + p.y.powi(2) + (p.z - center_z).powi(2);
+        if led.enabled && radius_squared < radius * radius {
+            led.color = color;
+        } else {
+            led.color = Color32::BLACK;
+        }
+    }
+}
+
+/// A horizontal plane in the base color, as high up the tree as the bass is loud.
+fn bass_plane(state: &mut AppState, now: Duration) {
+    // below this it's dark, so the plane doesn't sit at the bottom while it's quiet
+    const QUIET: f32 = 0.05;
+
+    let bass = state.audio.features(now).bass();
+
+    let (min_z, max_z) = state
+        .leds
+        .iter()
+        .filter(|l| l.enabled)
+        .map(|l| l.determined_position.z)
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), z| {
+            (lo.min(z), hi.max(z))
+        });
+    let plane_z = min_z + bass * (max_z - min_z);
+
+    for led in &mut state.leds {
+        let z = led.determined_position.z;
+        if led.enabled && bass > QUIET && (z - plane_z).abs() < 0.1 {
+            led.color = state.base_color;
+            led.white = state.base_white;
+        } else {
+            led.color = Color32::BLACK;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audio::{Audio, Track},
+        clock::Clock,
+        geometry::{Layout, LayoutConfig},
+    };
+    use serde::{Deserialize, Serialize};
+    use std::{fs, path::PathBuf, sync::Arc};
+
+    /// Milliseconds between two effect updates, about the GUI's frame rate.
+    const STEP_MS: u64 = 100;
+    /// Times at which the frames are compared to the golden files.
+    const SNAPSHOT_MS: [u64; 10] = [0, 500, 1000, 1500, 2000, 3000, 4500, 6000, 8000, 10000];
+    /// Allowed difference per color channel, to absorb float rounding.
+    const TOLERANCE: i16 = 2;
+
+    #[derive(Serialize, Deserialize)]
+    struct Golden {
+        effect: String,
+        /// one entry per snapshot time, with a space separated `#rrggbb` per LED
+        frames: Vec<String>,
+    }
+
+    fn kick_track() -> Arc<Track> {
+        let fixture =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/kick_120bpm.wav");
+        Arc::new(Track::from_wav(&fixture).unwrap())
+    }
+
+    /// A fixed, perfectly calibrated tree with a manual clock, playing a kick
+    /// drum on every beat at 120 bpm for the audio effects.
+    fn fixed_state(effect: Effect) -> AppState {
+        let layout = LayoutConfig {
+            layout: Layout::SpiralCone { turns: 5.0 },
+            seed: 7,
+        };
+        let mut state = AppState::new(40, layout, 42).unwrap();
+        for led in &mut state.leds {
+            led.determined_position = led.actual_position;
+        }
+        state.base_color = Color32::from_rgb(200, 120, 40);
+        state.clock = Clock::manual(Duration::ZERO);
+        state.audio = Audio::Track(kick_track());
+        state.effect = effect;
+        state
+    }
+
+    fn render_snapshots(effect: Effect) -> Vec<Vec<Color32>> {
+        let mut state = fixed_state(effect);
+        let mut snapshots = Vec::new();
+        let last = *SNAPSHOT_MS.last().unwrap();
+        for ms in (0..=last).step_by(STEP_MS as usize) {
+            let now = state.clock.now();
+            update_effects(&mut state, now);
+            if SNAPSHOT_MS.contains(&ms) {
+                snapshots.push(state.leds.iter().map(|l| l.color).collect());
+            }
+            state.clock.advance(Duration::from_millis(STEP_MS));
+        }
+        snapshots
+    }
+
+    fn to_hex(c: Color32) -> String {
+        format!("#{:02x}{:02x}{:02x}", c.r(), c.g(), c.b())
+    }
+
+    fn from_hex(s: &str) -> Color32 {
+        let channel = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).unwrap();
+        Color32::from_rgb(channel(1), channel(3), channel(5))
+    }
+
+    fn golden_path(effect: Effect) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.json", effect.name()))
+    }
+
+    /// Compares the effect against its golden file.
+    /// Run with `UPDATE_GOLDEN=1` to write the golden files instead.
+    fn check_golden(effect: Effect) {
+        let snapshots = render_snapshots(effect);
+        let path = golden_path(effect);
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            let golden = Golden {
+                effect: effect.name().to_string(),
+                frames: snapshots
+                    .iter()
+                    .map(|frame| {
+                        let colors: Vec<String> = frame.iter().map(|c| to_hex(*c)).collect();
+                        colors.join(" ")
+                    })
+                    .collect(),
+            };
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, serde_json::to_string_pretty(&golden).unwrap() + "\n").unwrap();
+            return;
+        }
+
+        let contents = fs::read_to_string(&path).unwrap_or_else(|e| {
+            panic!(
+                "missing golden file {} ({e}), run with UPDATE_GOLDEN=1 to create it",
+                path.display()
+            )
+        });
+        let golden: Golden = serde_json::from_str(&contents).unwrap();
+        assert_eq!(golden.frames.len(), snapshots.len());
+
+        for ((expected, actual), ms) in golden.frames.iter().zip(&snapshots).zip(SNAPSHOT_MS) {
+            let expected: Vec<&str> = expected.split_whitespace().collect();
+            assert_eq!(expected.len(), actual.len());
+            for (i, (e, a)) in expected.iter().zip(actual).enumerate() {
+                let e = from_hex(e);
+                let close = [(e.r(), a.r()), (e.g(), a.g()), (e.b(), a.b())]
+                    .iter()
+                    .all(|(e, a)| (*e as i16 - *a as i16).abs() <= TOLERANCE);
+                assert!(
+                    close,
+                    "{}: LED {i} at {ms} ms is {}, expected {}",
+                    effect.name(),
+                    to_hex(*a),
+                    to_hex(e)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn golden_frames() {
+        for effect in Effect::ALL {
+            check_golden(effect);
+        }
+    }
+
+    #[test]
+    fn effects_are_reproducible() {
+        for effect in Effect::ALL {
+            assert_eq!(render_snapshots(effect), render_snapshots(effect));
+        }
+    }
+
+    #[test]
+    fn masked_leds_stay_dark() {
+        for effect in Effect::ALL {
+            let mut state = fixed_state(effect);
+            state.leds[3].enabled = false;
+            for _ in 0..50 {
+                let now = state.clock.now();
+                update_effects(&mut state, now);
+                assert_eq!(state.leds[3].color, Color32::BLACK, "{}", effect.name());
+                state.clock.advance(Duration::from_millis(STEP_MS));
+            }
+        }
+    }
+
+    #[test]
+    fn no_leds_is_fine() {
+        for effect in Effect::ALL {
+            let mut state = fixed_state(effect);
+            state.set_num_leds(0).unwrap();
+            state.effect = effect;
+            update_effects(&mut state, Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn concentric_color_restarts_once_all_leds_are_reached() {
+        let mut state = fixed_state(Effect::ConcentricColor);
+        let hue = state.concentric_color_hue;
+        let center_z =
+            state.leds.iter().map(|l| l.actual_position.z).sum::<f32>() / state.leds.len() as f32;
+        let max_radius = state
+            .leds
+            .iter()
+            .map(|l| {
+                let p = l.actual_position;
+                (p.x * p.x + p.y * p.y + (p.z - center_z).powi(2)).sqrt()
+            })
+            .fold(0.0, f32::max);
+        let reached_all = Duration::from_secs_f32(max_radius + 0.1);
+
+        update_effects(&mut state, reached_all);
+
+        assert!(state.leds.iter().all(|l| l.color != Color32::BLACK));
+        assert_ne!(state.concentric_color_hue, hue);
+        assert_eq!(state.effect_start, reached_all);
+    }
+
+    #[test]
+    fn white_only_where_the_base_color_is() {
+        let mut state = fixed_state(Effect::AllOn);
+        state.base_white = 80;
+        update_effects(&mut state, Duration::ZERO);
+        assert!(state.leds.iter().all(|l| l.white == 80));
+        let out = state.output_pixel(&state.leds[0]);
+        assert_eq!(out.white, 80);
+        assert_eq!(out.color, state.base_color);
+
+        state.start_effect(Effect::ConcentricColor);
+        update_effects(&mut state, Duration::from_secs(1));
+        assert!(state.leds.iter().all(|l| l.white == 0));
+    }
+
+    #[test]
+    fn audio_effects_follow_the_music() {
+        for effect in [Effect::BeatPulse, Effect::BassPlane] {
+            let mut state = fixed_state(effect);
+            let mut lit = |secs: f32| {
+                update_effects(&mut state, Duration::from_secs_f32(secs));
+                state
+                    .leds
+                    .iter()
+                    .filter(|l| l.color != Color32::BLACK)
+                    .count()
+            };
+            // before the first kick, shortly after the second one, and after the track ended
+            assert_eq!(lit(0.2), 0, "{}", effect.name());
+            assert!(lit(1.0) > 0, "{}", effect.name());
+            assert_eq!(lit(3.5), 0, "{}", effect.name());
+        }
     }
 }
@@ -0,0 +1,483 @@
+use crate::state::Vec3;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::TAU, fs};
+use utoipa::ToSchema;
+
+/// Height of the generated trees. They all stand on the z = 0 plane, centered on the z axis.
+const TREE_HEIGHT: f32 = 2.5;
+/// Radius of the generated trees at their base.
+const TREE_RADIUS: f32 = 1.0;
+/// Most turns of a spiral or helix, each turn is a polyline of 200 points.
+const MAX_TURNS: f32 = 1000.0;
+/// Most branches of `Layout::Branches`, over all tiers.
+const MAX_BRANCHES: usize = 10_000;
+
+/// The shapes in which the simulated LEDs can be arranged.
+///
+/// Except for `RandomCone` the LEDs are placed in strip order, so neighboring
+/// indices are neighbors in space as well, like on a real strip.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Layout {
+    /// uniformly random points inside a cone, not ordered at all
+    RandomCone,
+    /// a strip wound around the surface of a cone from the bottom to the top
+    SpiralCone {
+        #[serde(default = "default_turns")]
+        turns: f32,
+    },
+    /// a strip running out along each branch and back to the trunk, tier by tier
+    Branches {
+        #[serde(default = "default_tiers")]
+        tiers: usize,
+        #[serde(default = "default_branches_per_tier")]
+        branches_per_tier: usize,
+    },
+    /// a flat matrix in the x-z plane, wired in serpentine rows starting at the bottom
+    Matrix {
+        /// defaults to a roughly square matrix
+        #[serde(default)]
+        columns: Option<usize>,
+    },
+    /// points evenly distributed on the surface of a sphere, from bottom to top
+    Sphere,
+    /// a strip wound around a cylinder
+    Helix {
+        #[serde(default = "default_turns")]
+        turns: f32,
+    },
+    /// points loaded from a file, either a JSON list of `[x, y, z]` or one
+    /// `x y z` triple per line, separated by spaces or commas. Only allowed in
+    /// the config file, not over HTTP.
+    PointFile { path: String },
+}
+
+impl Layout {
+    /// Rejects layouts too big to generate.
+    pub fn validate(&self) -> Result<(), String> {
+        let ok = match self {
+            Layout::SpiralCone { turns } | Layout::Helix { turns } => {
+                turns.is_finite() && turns.abs() <= MAX_TURNS
+            }
+            Layout::Branches {
+                tiers,
+                branches_per_tier,
+            } => tiers.saturating_mul(*branches_per_tier) <= MAX_BRANCHES,
+            _ => true,
+        };
+        if !ok {
+            return Err(format!(
+                "the layout is too big, it can have at most {MAX_TURNS} turns or {MAX_BRANCHES} branches"
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_turns() -> f32 {
+    8.0
+}
+
+fn default_tiers() -> usize {
+    6
+}
+
+fn default_branches_per_tier() -> usize {
+    5
+}
+
+/// A layout together with the seed for its random parts, so it can be recreated exactly.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct LayoutConfig {
+    #[serde(flatten)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        Self {
+            layout: Layout::RandomCone,
+            seed: 0,
+        }
+    }
+}
+
+/// Generates the positions of `num_leds` LEDs.
+pub fn generate(config: &LayoutConfig, num_leds: usize) -> Result<Vec<Vec3>, String> {
+    config.layout.validate()?;
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let points = match &config.layout {
+        Layout::RandomCone => random_cone(num_leds, &mut rng),
+        Layout::SpiralCone { turns } => {
+            let path = spiral(*turns, |t| TREE_RADIUS * (1.0 - t));
+            jitter(along_path(&path, num_leds), 0.02, &mut rng)
+        }
+        Layout::Branches {
+            tiers,
+            branches_per_tier,
+        } => {
+            let path = branches(*tiers, *branches_per_tier, &mut rng);
+            jitter(along_path(&path, num_leds), 0.02, &mut rng)
+        }
+        Layout::Matrix { columns } => matrix(num_leds, *columns),
+        Layout::Sphere => jitter(sphere(num_leds), 0.01, &mut rng),
+        Layout::Helix { turns } => {
+            let path = spiral(*turns, |_| TREE_RADIUS / 2.0);
+            jitter(along_path(&path, num_leds), 0.01, &mut rng)
+        }
+        Layout::PointFile { path } => point_file(path, num_leds)?,
+    };
+    Ok(points)
+}
+
+/// Uniformly random points in a cone.
+/// Base: square from -1..1 in x/y, height z: 0..2.5
+fn random_cone(num_leds: usize, rng: &mut StdRng) -> Vec<Vec3> {
+    let mut leds = Vec::with_capacity(num_leds);
+
+    while leds.len() < num_leds {
+        let z: f32 = rng.random_range(0.0..TREE_HEIGHT);
+        let x: f32 = rng.random_range(-TREE_RADIUS..TREE_RADIUS);
+        let y: f32 = rng.random_range(-TREE_RADIUS..TREE_RADIUS);
+
+        let radius = (x * x + y * y).sqrt();
+        let max_allowed_radius = TREE_RADIUS * (1.0 - (z / TREE_HEIGHT));
+        if radius < max_allowed_radius {
+            leds.push(Vec3 { x, y, z });
+        }
+    }
+
+    leds
+}
+
+/// Fine polyline of a spiral from the bottom to the top of the tree.
+/// `radius` gives the radius at the relative height `t` in 0..1.
+fn spiral(turns: f32, radius: impl Fn(f32) -> f32) -> Vec<Vec3> {
+    let steps = ((turns.abs() * 200.0) as usize).max(100);
+    (0..=steps)
+        .map(|i| {
+            let t = i as f32 / steps as f32;
+            let angle = t * turns * TAU;
+            Vec3 {
+                x: radius(t) * angle.cos(),
+                y: radius(t) * angle.sin(),
+                z: t * TREE_HEIGHT,
+            }
+        })
+        .collect()
+}
+
+/// Polyline of a strip that runs out along every branch and back in,
+/// the branches get shorter towards the top like on a real tree.
+fn branches(tiers: usize, branches_per_tier: usize, rng: &mut StdRng) -> Vec<Vec3> {
+    let tiers = tiers.max(1);
+    let branches_per_tier = branches_per_tier.max(1);
+    let mut path = vec![Vec3::ZERO];
+    for tier in 0..tiers {
+        let t = (tier as f32 + 0.5) / tiers as f32;
+        let z = t * TREE_HEIGHT;
+        let length = TREE_RADIUS * (1.0 - t);
+        let trunk = Vec3 { x: 0.0, y: 0.0, z };
+        let offset: f32 = rng.random_range(0.0..TAU);
+        for branch in 0..branches_per_tier {
+            let angle = offset + branch as f32 / branches_per_tier as f32 * TAU;
+            // the strip goes out on one side of the branch and comes back on the other
+            let spread = 0.15;
+            let droop = length * 0.2;
+            for a in [angle - spread, angle + spread] {
+                path.push(trunk);
+                path.push(Vec3 {
+                    x: length * a.cos(),
+                    y: length * a.sin(),
+                    z: z - droop,
+                });
+            }
+            path.push(trunk);
+        }
+    }
+    path
+}
+
+/// Places `num_leds` points at equal distances along the polyline.
+fn along_path(path: &[Vec3], num_leds: usize) -> Vec<Vec3> {
+    let mut cumulative = Vec::with_capacity(path.len());
+    let mut total = 0.0;
+    cumulative.push(0.0);
+    for pair in path.windows(2) {
+        total += (pair[1] - pair[0]).length();
+        cumulative.push(total);
+    }
+
+    let mut points = Vec::with_capacity(num_leds);
+    let mut segment = 0;
+    for i in 0..num_leds {
+        let distance = if num_leds > 1 {
+            total * i as f32 / (num_leds - 1) as f32
+        } else {
+            0.0
+        };
+        while segment + 2 < path.len() && cumulative[segment + 1] < distance {
+            segment += 1;
+        }
+        let (start, end) = (path[segment], path[(segment + 1).min(path.len() - 1)]);
+        let length = cumulative[(segment + 1).min(path.len() - 1)] - cumulative[segment];
+        let fraction = if length > 0.0 {
+            ((distance - cumulative[segment]) / length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        points.push(start + (end - start) * fraction);
+    }
+    points
+}
+
+fn matrix(num_leds: usize, columns: Option<usize>) -> Vec<Vec3> {
+    let columns = columns
+        .unwrap_or_else(|| (num_leds as f32).sqrt().ceil() as usize)
+        .max(1);
+    let rows = num_leds.div_ceil(columns).max(1);
+    let spacing = TREE_HEIGHT / rows.max(columns) as f32;
+    (0..num_leds)
+        .map(|i| {
+            let row = i / columns;
+            let mut column = i % columns;
+            if row % 2 == 1 {
+                column = columns - 1 - column;
+            }
+            Vec3 {
+                x: (column as f32 - (columns - 1) as f32 / 2.0) * spacing,
+                y: 0.0,
+                z: row as f32 * spacing,
+            }
+        })
+        .collect()
+}
+
+/// Fibonacci sphere, sitting on the z = 0 plane.
+fn sphere(num_leds: usize) -> Vec<Vec3> {
+    let radius = TREE_HEIGHT / 2.0;
+    let golden_angle = TAU * (1.0 - 1.0 / ((1.0 + 5f32.sqrt()) / 2.0));
+    (0..num_leds)
+        .map(|i| {
+            let z = -1.0 + (2.0 * i as f32 + 1.0) / num_leds as f32;
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            let angle = i as f32 * golden_angle;
+            Vec3 {
+                x: radius * r * angle.cos(),
+                y: radius * r * angle.sin(),
+                z: radius * (z + 1.0),
+            }
+        })
+        .collect()
+}
+
+/// Displaces every point by a random offset of up to `amount` in each direction.
+fn jitter(points: Vec<Vec3>, amount: f32, rng: &mut StdRng) -> Vec<Vec3> {
+    points
+        .into_iter()
+        .map(|p| {
+            p + Vec3 {
+                x: rng.random_range(-amount..=amount),
+                y: rng.random_range(-amount..=amount),
+                z: rng.random_range(-amount..=amount),
+            }
+        })
+        .collect()
+}
+
+fn point_file(path: &str, num_leds: usize) -> Result<Vec<Vec3>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("could not read point file {path}: {e}"))?;
+
+    let points: Vec<Vec3> = if contents.trim_start().starts_with('[') {
+        let list: Vec<[f32; 3]> = serde_json::from_str(&contents)
+            .map_err(|e| format!("could not parse point file {path}: {e}"))?;
+        list.into_iter().map(|[x, y, z]| Vec3 { x, y, z }).collect()
+    } else {
+        let mut points = Vec::new();
+        for (line_nr, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let values: Vec<f32> = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|v| !v.is_empty())
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("{path}:{}: {e}", line_nr + 1))?;
+            let [x, y, z] = values[..] else {
+                return Err(format!(
+                    "{path}:{}: expected 3 coordinates, found {}",
+                    line_nr + 1,
+                    values.len()
+                ));
+            };
+            points.push(Vec3 { x, y, z });
+        }
+        points
+    };
+
+    if let Some(i) = points
+        .iter()
+        .position(|p| !(p.x.is_finite() && p.y.is_finite() && p.z.is_finite()))
+    {
+        return Err(format!(
+            "point file {path}: point {i} is not a finite position"
+        ));
+    }
+    if points.len() < num_leds {
+        return Err(format!(
+            "point file {path} contains {} points, but {num_leds} LEDs are needed",
+            points.len()
+        ));
+    }
+    Ok(points.into_iter().take(num_leds).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(layout: Layout) -> LayoutConfig {
+        LayoutConfig { layout, seed: 1 }
+    }
+
+    #[test]
+    fn huge_layouts_are_rejected() {
+        for turns in [1e12, -2000.0, f32::INFINITY, f32::NAN] {
+            assert!(generate(&layout(Layout::SpiralCone { turns }), 10).is_err());
+            assert!(generate(&layout(Layout::Helix { turns }), 10).is_err());
+        }
+        for (tiers, branches_per_tier) in [(10_001, 1), (200, 200), (usize::MAX, 2)] {
+            let branches = Layout::Branches {
+                tiers,
+                branches_per_tier,
+            };
+            assert!(generate(&layout(branches), 10).is_err());
+        }
+
+        assert_eq!(
+            generate(&layout(Layout::SpiralCone { turns: 1000.0 }), 10)
+                .unwrap()
+                .len(),
+            10
+        );
+        let branches = Layout::Branches {
+            tiers: 100,
+            branches_per_tier: 100,
+        };
+        assert_eq!(generate(&layout(branches), 10).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn the_seed_decides_the_positions() {
+        let seeded = |seed| LayoutConfig {
+            layout: Layout::RandomCone,
+            seed,
+        };
+        let first = generate(&seeded(7), 50).unwrap();
+        assert_eq!(first, generate(&seeded(7), 50).unwrap());
+        assert_ne!(first, generate(&seeded(8), 50).unwrap());
+
+        let spiral = |seed| LayoutConfig {
+            layout: Layout::SpiralCone { turns: 3.0 },
+            seed,
+        };
+        assert_eq!(
+            generate(&spiral(7), 50).unwrap(),
+            generate(&spiral(7), 50).unwrap()
+        );
+        assert_ne!(
+            generate(&spiral(7), 50).unwrap(),
+            generate(&spiral(8), 50).unwrap()
+        );
+    }
+
+    #[test]
+    fn layouts_have_their_shape() {
+        // spiral and helix climb along the strip, up to the jitter of two neighbors
+        for (spiral, jitter) in [
+            (Layout::SpiralCone { turns: 2.0 }, 0.02),
+            (Layout::Helix { turns: 2.0 }, 0.01),
+        ] {
+            let points = generate(&layout(spiral), 100).unwrap();
+            for pair in points.windows(2) {
+                assert!(pair[1].z > pair[0].z - 2.0 * jitter);
+            }
+            assert!(points[0].z.abs() <= jitter);
+            assert!((points[99].z - TREE_HEIGHT).abs() <= jitter);
+        }
+        for p in generate(&layout(Layout::SpiralCone { turns: 2.0 }), 100).unwrap() {
+            let radius = (p.x * p.x + p.y * p.y).sqrt();
+            assert!(radius <= TREE_RADIUS * (1.0 - p.z / TREE_HEIGHT) + 0.05);
+        }
+        for p in generate(&layout(Layout::Helix { turns: 2.0 }), 100).unwrap() {
+            let radius = (p.x * p.x + p.y * p.y).sqrt();
+            assert!((radius - TREE_RADIUS / 2.0).abs() <= 0.02);
+        }
+
+        // 10 LEDs in 4 columns is 3 rows, the second one wired backwards
+        let points = generate(&layout(Layout::Matrix { columns: Some(4) }), 10).unwrap();
+        let spacing = TREE_HEIGHT / 4.0;
+        for (i, p) in points.iter().enumerate() {
+            let row = i / 4;
+            let column = if row % 2 == 1 { 3 - i % 4 } else { i % 4 };
+            assert_eq!(p.y, 0.0);
+            assert!((p.x - (column as f32 - 1.5) * spacing).abs() < 1e-5);
+            assert!((p.z - row as f32 * spacing).abs() < 1e-5);
+        }
+
+        let center = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: TREE_HEIGHT / 2.0,
+        };
+        for p in generate(&layout(Layout::Sphere), 200).unwrap() {
+            assert!(((p - center).length() - TREE_HEIGHT / 2.0).abs() <= 0.02);
+        }
+    }
+
+    #[test]
+    fn bad_point_files_are_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("led_sim_geometry_{}.txt", std::process::id()));
+        let points = |contents: &str| {
+            fs::write(&path, contents).unwrap();
+            let layout = layout(Layout::PointFile {
+                path: path.to_string_lossy().into_owned(),
+            });
+            generate(&layout, 2)
+        };
+
+        assert_eq!(
+            points("0 0 0\n# comment\n1, 2, 3\n4 5 6\n").unwrap(),
+            [
+                Vec3::ZERO,
+                Vec3 {
+                    x: 1.0,
+                    y: 2.0,
+                    z: 3.0
+                }
+            ]
+        );
+        assert_eq!(points("[[0, 0, 0], [1, 2, 3]]").unwrap().len(), 2);
+
+        for contents in [
+            "0 0 0\n",
+            "[[0, 0, 0]]",
+            "0 0 0\n1 2\n",
+            "0 0 0\n1 2 3 4\n",
+            "0 0 0\n1 x 3\n",
+            "0 0 0\n1 nan 3\n",
+            "0 0 0\ninf 2 3\n",
+            "[[0, 0, 0], [1, 2]]",
+        ] {
+            assert!(points(contents).is_err(), "{contents:?}");
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
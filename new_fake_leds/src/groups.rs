@@ -0,0 +1,321 @@
+use crate::{
+    output::Rgbw,
+    state::{AppState, Effect, Led, Vec3},
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use utoipa::ToSchema;
+
+/// How a group picks its LEDs. Positions are the determined ones, so the
+/// regions only make sense once the tree is calibrated.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Selector {
+    /// the LEDs with an index in `start..end`
+    Range { start: usize, end: usize },
+    /// the LEDs between two heights, as fractions of the height of the tree,
+    /// e.g. 0.8 to 1 for the top 20%
+    Height { low: f32, high: f32 },
+    /// the LEDs inside a box
+    Box { min: Vec3, max: Vec3 },
+    /// the LEDs inside a sphere
+    Sphere { center: Vec3, radius: f32 },
+    /// exactly these LEDs
+    Leds { leds: Vec<usize> },
+}
+
+/// A named part of the tree, e.g. the star at the top.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct Group {
+    pub name: String,
+    #[serde(flatten)]
+    pub selector: Selector,
+}
+
+impl Selector {
+    pub fn validate(&self) -> Result<(), String> {
+        let ok = match self {
+            Selector::Range { start, end } => start < end,
+            Selector::Height { low, high } => low.is_finite() && high.is_finite() && low <= high,
+            Selector::Box { min, max } => min.x <= max.x && min.y <= max.y && min.z <= max.z,
+            Selector::Sphere { radius, .. } => radius.is_finite() && *radius >= 0.0,
+            Selector::Leds { .. } => true,
+        };
+        if !ok {
+            return Err(format!("{self:?} selects nothing"));
+        }
+        Ok(())
+    }
+
+    /// For each LED, whether it's selected. LEDs beyond the last one are ignored.
+    pub fn members(&self, leds: &[Led]) -> Vec<bool> {
+        let mut members = vec![false; leds.len()];
+        match self {
+            Selector::Range { start, end } => {
+                for m in members.iter_mut().take(*end).skip(*start) {
+                    *m = true;
+                }
+            }
+            Selector::Leds { leds } => {
+                for &i in leds {
+                    if let Some(m) = members.get_mut(i) {
+                        *m = true;
+                    }
+                }
+            }
+            Selector::Height { low, high } => {
+                let (min_z, max_z) = leds
+                    .iter()
+                    .map(|l| l.determined_position.z)
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), z| {
+                        (lo.min(z), hi.max(z))
+                    });
+                let height = (max_z - min_z).max(f32::EPSILON);
+                for (m, led) in members.iter_mut().zip(leds) {
+                    let z = (led.determined_position.z - min_z) / height;
+                    *m = (*low..=*high).contains(&z);
+                }
+            }
+            Selector::Box { min, max } => {
+                for (m, led) in members.iter_mut().zip(leds) {
+                    let p = led.determined_position;
+                    *m = (min.x..=max.x).contains(&p.x)
+                        && (min.y..=max.y).contains(&p.y)
+                        && (min.z..=max.z).contains(&p.z);
+                }
+            }
+            Selector::Sphere { center, radius } => {
+                for (m, led) in members.iter_mut().zip(leds) {
+                    let d = led.determined_position - *center;
+                    *m = d.dot(d) <= radius * radius;
+                }
+            }
+        }
+        members
+    }
+}
+
+/// The groups saved in `path`, none if there is no such file yet.
+pub fn load(path: &Path) -> Result<Vec<Group>, String> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+    let groups: Vec<Group> = serde_json::from_str(&contents)
+        .map_err(|e| format!("could not parse {}: {e}", path.display()))?;
+    for g in &groups {
+        g.selector
+            .validate()
+            .map_err(|e| format!("group {:?} in {}: {e}", g.name, path.display()))?;
+    }
+    Ok(groups)
+}
+
+pub fn save(path: &Path, groups: &[Group]) -> Result<(), String> {
+    let contents = serde_json::to_string_pretty(groups).unwrap() + "\n";
+    fs::write(path, contents).map_err(|e| format!("could not write {}: {e}", path.display()))
+}
+
+/// Confines the running effect to its group and paints the groups that have a
+/// color of their own, over the effect. Runs after the effects.
+pub fn apply(state: &mut AppState) {
+    for led in &mut state.leds {
+        led.group_color = None;
+    }
+
+    let target = state
+        .effect_group
+        .as_ref()
+        .and_then(|name| state.groups.iter().find(|g| &g.name == name));
+    if let (Some(group), true) = (target, state.effect != Effect::None) {
+        let members = group.selector.members(&state.leds);
+        for (led, inside) in state.leds.iter_mut().zip(members) {
+            if !inside {
+                led.color = egui::Color32::BLACK;
+                led.white = 0;
+            }
+        }
+    }
+
+    for group in &state.groups {
+        let Some(&color) = state.group_colors.get(&group.name) else {
+            continue;
+        };
+        let members = group.selector.members(&state.leds);
+        for (led, inside) in state.leds.iter_mut().zip(members) {
+            if inside {
+                led.group_color = Some(color);
+            }
+        }
+    }
+}
+
+/// Replaces the group `group.name`, or adds it.
+pub fn upsert(groups: &mut Vec<Group>, group: Group) {
+    match groups.iter_mut().find(|g| g.name == group.name) {
+        Some(g) => *g = group,
+        None => groups.push(group),
+    }
+}
+
+/// The color of a group, replacing what the effects draw there.
+pub fn set_color(state: &mut AppState, name: &str, color: Option<Rgbw>) {
+    match color {
+        Some(color) => state.group_colors.insert(name.to_string(), color),
+        None => state.group_colors.remove(name),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        effects::update_effects,
+        geometry::{Layout, LayoutConfig},
+    };
+    use egui::Color32;
+    use std::time::Duration;
+
+    /// 20 LEDs on a spiral from the bottom to the top of the tree.
+    fn calibrated() -> AppState {
+        let layout = LayoutConfig {
+            layout: Layout::SpiralCone { turns: 2.0 },
+            seed: 1,
+        };
+        let mut s = AppState::new(20, layout, 1).unwrap();
+        for led in &mut s.leds {
+            led.determined_position = led.actual_position;
+        }
+        s
+    }
+
+    fn count(members: &[bool]) -> usize {
+        members.iter().filter(|m| **m).count()
+    }
+
+    #[test]
+    fn selectors() {
+        let s = calibrated();
+        let range = Selector::Range { start: 18, end: 25 };
+        assert_eq!(count(&range.members(&s.leds)), 2);
+        let leds = Selector::Leds {
+            leds: vec![0, 5, 99],
+        };
+        assert_eq!(count(&leds.members(&s.leds)), 2);
+
+        let top = Selector::Height {
+            low: 0.8,
+            high: 1.0,
+        };
+        let members = top.members(&s.leds);
+        let top_z = s
+            .leds
+            .iter()
+            .map(|l| l.determined_position.z)
+            .fold(f32::MIN, f32::max);
+        assert!(count(&members) >= 1 && count(&members) < 10);
+        for (led, inside) in s.leds.iter().zip(&members) {
+            if led.determined_position.z == top_z {
+                assert!(inside);
+            }
+        }
+
+        let everything = Selector::Box {
+            min: Vec3 {
+                x: -100.0,
+                y: -100.0,
+                z: -100.0,
+            },
+            max: Vec3 {
+                x: 100.0,
+                y: 100.0,
+                z: 100.0,
+            },
+        };
+        assert_eq!(count(&everything.members(&s.leds)), 20);
+
+        let p = s.leds[3].determined_position;
+        let sphere = Selector::Sphere {
+            center: p,
+            radius: 0.0,
+        };
+        assert_eq!(
+            sphere.members(&s.leds),
+            (0..20).map(|i| i == 3).collect::<Vec<_>>()
+        );
+
+        assert!(Selector::Range { start: 3, end: 3 }.validate().is_err());
+        assert!(Selector::Sphere {
+            center: p,
+            radius: -1.0
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn groups_are_saved() {
+        let path = std::env::temp_dir().join(format!("led_sim_groups_{}.json", std::process::id()));
+        assert_eq!(load(&path).unwrap(), []);
+        let groups = vec![
+            Group {
+                name: "star".to_string(),
+                selector: Selector::Height {
+                    low: 0.9,
+                    high: 1.0,
+                },
+            },
+            Group {
+                name: "picked".to_string(),
+                selector: Selector::Leds { leds: vec![1, 2] },
+            },
+        ];
+        save(&path, &groups).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(r#""kind": "height""#), "{contents}");
+        assert_eq!(load(&path).unwrap(), groups);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn effects_target_groups() {
+        let mut s = calibrated();
+        s.groups = vec![
+            Group {
+                name: "body".to_string(),
+                selector: Selector::Range { start: 0, end: 15 },
+            },
+            Group {
+                name: "star".to_string(),
+                selector: Selector::Range { start: 15, end: 20 },
+            },
+        ];
+        s.base_color = Color32::WHITE;
+        s.start_effect(Effect::AllOn);
+        s.effect_group = Some("body".to_string());
+        let gold = Color32::from_rgb(255, 200, 0);
+        set_color(
+            &mut s,
+            "star",
+            Some(Rgbw {
+                color: gold,
+                white: 0,
+            }),
+        );
+        update_effects(&mut s, Duration::ZERO);
+
+        assert_eq!(s.output_color(&s.leds[0]), Color32::WHITE);
+        assert_eq!(s.output_color(&s.leds[19]), gold);
+
+        // without its color the star is outside the effect's group
+        set_color(&mut s, "star", None);
+        update_effects(&mut s, Duration::ZERO);
+        assert_eq!(s.output_color(&s.leds[19]), Color32::BLACK);
+
+        // starting an effect without a group lights the whole tree again
+        s.start_effect(Effect::AllOn);
+        update_effects(&mut s, Duration::ZERO);
+        assert_eq!(s.output_color(&s.leds[19]), Color32::WHITE);
+    }
+}
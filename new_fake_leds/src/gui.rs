@@ -1,33 +1,86 @@
-use crate::{effects::update_effects, rotate_point, state::AppState};
-use egui::{Color32, Pos2};
-use parking_lot::Mutex;
-use std::{sync::Arc, time::Duration};
+use crate::{
+    calibration::{self, Report},
+    effects::update_effects,
+    power::PowerEstimate,
+    rotate_point,
+    state::{AppState, StateEvent},
+    trees::Trees,
+};
+use egui::{Color32, Pos2, Stroke};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+/// Step size of the timeline's step button, about one GUI frame.
+const STEP: Duration = Duration::from_millis(100);
 
 pub struct LedApp {
-    state: Arc<Mutex<AppState>>,
+    trees: Trees,
+    /// index of the tree that is shown, all of them run their effects
+    selected: usize,
     last_drag: Option<egui::Pos2>,
     last_pan: Option<egui::Pos2>,
+    show_report: bool,
+    /// calibration report of the selected tree, `None` until it's shown or after the positions changed
+    report: Option<Result<Report, String>>,
+    /// events of each tree, to notice when the report is out of date
+    events: Vec<broadcast::Receiver<StateEvent>>,
+    /// draw what the strip shows after the output stage instead of the effects' colors
+    preview_output: bool,
+    /// frame of each tree drawn by the last `update`, it's on screen once `update` is called again
+    drawn_frames: Vec<Option<u64>>,
 }
 
 impl LedApp {
-    pub fn new(state: Arc<Mutex<AppState>>) -> Self {
+    pub fn new(trees: Trees) -> Self {
         Self {
-            state,
+            drawn_frames: vec![None; trees.len()],
+            events: trees
+                .iter()
+                .map(|(_, t)| t.lock().events.subscribe())
+                .collect(),
+            trees,
+            selected: 0,
             last_drag: None,
             last_pan: None,
+            show_report: false,
+            report: None,
+            preview_output: false,
         }
     }
 }
 
 impl eframe::App for LedApp {
     fn update(&mut self, ctx: &egui::Context, _: &mut eframe::Frame) {
-        let mut state = self.state.lock();
-        update_effects(&mut state);
-
-        if state.egui_context.is_none() {
-            state.egui_context = Some(ctx.clone());
+        for (i, (((_, tree), drawn), events)) in self
+            .trees
+            .iter()
+            .zip(&mut self.drawn_frames)
+            .zip(&mut self.events)
+            .enumerate()
+        {
+            if i == self.selected && calibration_changed(events) {
+                self.report = None;
+            }
+            let mut state = tree.lock();
+            if let Some(frame) = drawn.take() {
+                state.end_frame(frame);
+            }
+            let now = state.clock.now();
+            update_effects(&mut state, now);
+            *drawn = Some(state.begin_frame());
+            if state.frame_shown.receiver_count() > 0 {
+                // someone waits for this frame, don't make them wait for the next regular repaint
+                ctx.request_repaint();
+            }
+            if state.egui_context.is_none() {
+                state.egui_context = Some(ctx.clone());
+            }
         }
 
+        let trees = self.trees.clone();
+        let (selected_id, tree) = trees.iter().nth(self.selected).unwrap();
+        let mut state = tree.lock();
+
         let pointer = ctx.input(|i| i.pointer.clone());
 
         // --- Rotation with left mouse ---
@@ -61,19 +114,92 @@ impl eframe::App for LedApp {
             }
         }
 
+        egui::TopBottomPanel::top("toolbar").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if self.trees.len() > 1 {
+                    egui::ComboBox::from_id_salt("tree")
+                        .selected_text(selected_id)
+                        .show_ui(ui, |ui| {
+                            for (i, (id, _)) in self.trees.iter().enumerate() {
+                                if ui.selectable_value(&mut self.selected, i, id).changed() {
+                                    self.report = None;
+                                }
+                            }
+                        });
+                }
+                ui.toggle_value(&mut self.show_report, "Calibration report");
+                ui.toggle_value(&mut self.preview_output, "Hardware preview");
+                ui.label(format!("seed: {}", state.seed));
+                ui.separator();
+                let mut brightness = state.brightness;
+                if ui
+                    .add(egui::Slider::new(&mut brightness, 0..=255).text("brightness"))
+                    .changed()
+                {
+                    state.set_brightness(brightness);
+                }
+                ui.label(power_label(&state.power));
+            });
+        });
+
+        egui::TopBottomPanel::bottom("timeline").show(ctx, |ui| {
+            timeline_ui(ui, &mut state);
+        });
+
+        if !self.show_report {
+            self.report = None;
+        }
+        let report = match &self.report {
+            Some(report) => report,
+            None if self.show_report => self.report.insert(calibration::report(&state)),
+            None => &Err(String::new()),
+        };
+        if self.show_report {
+            egui::Window::new("Calibration report")
+                .open(&mut self.show_report)
+                .show(ctx, |ui| match report {
+                    Ok(report) => report_ui(ui, report),
+                    Err(msg) => {
+                        ui.label(msg);
+                    }
+                });
+        }
+
         // Draw LEDs
         egui::CentralPanel::default().show(ctx, |ui| {
             let rect = ui.available_rect_before_wrap();
             let center = rect.center();
 
-            for led in &state.leds {
+            let colors = if self.preview_output {
+                state.preview_colors()
+            } else {
+                state.output_colors()
+            };
+            for (led, &color) in state.leds.iter().zip(&colors) {
                 let rotated = rotate_point(led.actual_position, state.rotation_x, state.rotation_y);
                 let p = Pos2 {
                     x: center.x + rotated.x * 200.0 + state.offset_x,
                     y: center.y - rotated.y * 200.0 + state.offset_y,
                 };
-                if led.enabled && led.color != Color32::from_rgb(0, 0, 0) {
-                    ui.painter().circle_filled(p, 4.0, led.color);
+                if color != Color32::BLACK {
+                    ui.painter().circle_filled(p, 4.0, color);
+                }
+            }
+
+            // error of each LED, from the actual to the aligned determined position
+            if let Ok(report) = report {
+                let project = |v| {
+                    let rotated = rotate_point(v, state.rotation_x, state.rotation_y);
+                    Pos2 {
+                        x: center.x + rotated.x * 200.0 + state.offset_x,
+                        y: center.y - rotated.y * 200.0 + state.offset_y,
+                    }
+                };
+                for e in &report.per_led {
+                    let actual = project(state.leds[e.index].actual_position);
+                    let aligned = project(e.aligned_position);
+                    ui.painter()
+                        .line_segment([actual, aligned], Stroke::new(1.0, Color32::RED));
                 }
             }
         });
@@ -81,3 +207,134 @@ impl eframe::App for LedApp {
         ctx.request_repaint_after(Duration::from_millis(100));
     }
 }
+
+/// Whether a tree's events since the last call could change its calibration report.
+fn calibration_changed(events: &mut broadcast::Receiver<StateEvent>) -> bool {
+    let mut changed = false;
+    loop {
+        match events.try_recv() {
+            Ok(
+                StateEvent::PositionsChanged
+                | StateEvent::NumLedsChanged { .. }
+                | StateEvent::MasksChanged { .. },
+            ) => changed = true,
+            Ok(_) => {}
+            Err(TryRecvError::Lagged(_)) => changed = true,
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return changed,
+        }
+    }
+}
+
+fn report_ui(ui: &mut egui::Ui, report: &Report) {
+    egui::Grid::new("report_summary").show(ui, |ui| {
+        ui.label("LEDs");
+        ui.label(report.num_leds.to_string());
+        ui.end_row();
+        ui.label("Left out");
+        ui.label(format!("{} without a position", report.undetermined));
+        ui.end_row();
+        ui.label("RMS error");
+        ui.label(format!(
+            "{:.4} ({:.1}% of tree size)",
+            report.rms_error,
+            report.relative_rms_error * 100.0
+        ));
+        ui.end_row();
+        ui.label("Mean error");
+        ui.label(format!("{:.4}", report.mean_error));
+        ui.end_row();
+        ui.label("Max error");
+        ui.label(format!("{:.4}", report.max_error));
+        ui.end_row();
+        ui.label("Scale");
+        ui.label(format!("{:.4}", report.transform.scale));
+        ui.end_row();
+    });
+
+    ui.separator();
+    ui.label("Histogram");
+    let max_count = report
+        .histogram
+        .iter()
+        .map(|b| b.count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(240.0, 80.0), egui::Sense::hover());
+    let bar_width = rect.width() / report.histogram.len() as f32;
+    for (i, bin) in report.histogram.iter().enumerate() {
+        let height = rect.height() * bin.count as f32 / max_count as f32;
+        let bar = egui::Rect::from_min_max(
+            Pos2 {
+                x: rect.left() + i as f32 * bar_width + 1.0,
+                y: rect.bottom() - height,
+            },
+            Pos2 {
+                x: rect.left() + (i + 1) as f32 * bar_width - 1.0,
+                y: rect.bottom(),
+            },
+        );
+        ui.painter().rect_filled(bar, 0.0, Color32::LIGHT_BLUE);
+    }
+    ui.label(format!("0 .. {:.4}", report.max_error));
+
+    ui.separator();
+    ui.label("Worst LEDs");
+    egui::Grid::new("report_worst").show(ui, |ui| {
+        for e in &report.worst {
+            ui.label(format!("LED {}", e.index));
+            ui.label(format!("{:.4}", e.error));
+            ui.end_row();
+        }
+    });
+}
+
+/// The estimated current, and how much the frame is dimmed to stay within the budget.
+fn power_label(power: &PowerEstimate) -> String {
+    let amps = power.drawn_ma / 1000.0;
+    match power.budget_ma {
+        Some(budget) if power.scale < 1.0 => format!(
+            "{amps:.2} A of {:.2} A, limited to {:.0}%",
+            budget / 1000.0,
+            power.scale * 100.0
+        ),
+        Some(budget) => format!("{amps:.2} A of {:.2} A", budget / 1000.0),
+        None => format!("{amps:.2} A"),
+    }
+}
+
+/// Pause/resume, stepping, playback speed and scrubbing of the effect clock.
+fn timeline_ui(ui: &mut egui::Ui, state: &mut AppState) {
+    ui.horizontal(|ui| {
+        let clock = &mut state.clock;
+        if clock.is_paused() {
+            if ui.button("Resume").clicked() {
+                clock.resume();
+            }
+            if ui.button("Step").clicked() {
+                clock.advance(STEP);
+            }
+        } else if ui.button("Pause").clicked() {
+            clock.pause();
+        }
+
+        let mut speed = clock.speed();
+        if ui
+            .add(egui::Slider::new(&mut speed, 0.0..=4.0).text("speed"))
+            .changed()
+        {
+            clock.set_speed(speed);
+        }
+
+        let now = clock.now().as_secs_f32();
+        let mut time = now;
+        let end = (now + 30.0).max(60.0);
+        ui.style_mut().spacing.slider_width = (ui.available_width() - 80.0).max(100.0);
+        if ui
+            .add(egui::Slider::new(&mut time, 0.0..=end).suffix(" s"))
+            .changed()
+        {
+            clock.seek(Duration::from_secs_f32(time));
+        }
+    });
+}
@@ -1,35 +1,36 @@
+mod assets;
+mod audio;
+mod auth;
+mod calibration;
+mod camera;
+mod clock;
+mod config;
 mod effects;
+mod error;
+mod geometry;
+mod groups;
 mod gui;
+mod midi;
+mod mqtt;
+mod osc;
+mod output;
+mod power;
+mod render;
 mod state;
+mod trees;
 mod web;
+mod ws;
 
 use crate::state::Vec3;
+use assets::Assets;
+use clap::Parser;
+use config::{Cli, Command, Config};
 use egui::Color32;
 use gui::LedApp;
 use parking_lot::Mutex;
-use state::AppState;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tracing_subscriber::{fmt, EnvFilter};
-
-/// Generates `num_leds` points in a cone.
-/// Base: square from -1..1 in x/y, height z: 0..2.5
-pub fn generate_cone_leds(num_leds: usize) -> Vec<Vec3> {
-    let mut leds = Vec::with_capacity(num_leds);
-
-    while leds.len() < num_leds {
-        let z: f32 = rand::random_range(0.0..2.5);
-        let x: f32 = rand::random_range(-1.0..1.0);
-        let y: f32 = rand::random_range(-1.0..1.0);
-
-        let radius = (x * x + y * y).sqrt();
-        let max_allowed_radius = 1.0 - (z / 2.5);
-        if radius < max_allowed_radius {
-            leds.push(Vec3 { x, y, z });
-        }
-    }
-
-    leds
-}
+use trees::Trees;
 
 pub fn rotate_point(p: Vec3, rot_x: f32, rot_y: f32) -> Vec3 {
     // Rotation around X axis
@@ -75,7 +76,22 @@ fn hsv_to_rgb(h: f32, s: f32, v: f32) -> Color32 {
         (1.0, 1.0, 1.0)
     };
 
-    return Color32::from_rgb((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8);
+    Color32::from_rgb((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8)
+}
+
+/// Updates the effects of all trees without a GUI, at about the GUI's frame rate.
+fn run_headless(trees: Trees) {
+    loop {
+        for (_, state) in trees.iter() {
+            let mut s = state.lock();
+            let now = s.clock.now();
+            effects::update_effects(&mut s, now);
+            // there is nothing to draw, the frame is out as soon as it's computed
+            let frame = s.begin_frame();
+            s.end_frame(frame);
+        }
+        std::thread::sleep(Duration::from_millis(33));
+    }
 }
 
 fn main() {
@@ -84,20 +100,101 @@ fn main() {
 
     fmt().with_env_filter(filter).init();
 
-    let state = Arc::new(Mutex::new(AppState::new(50)));
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{msg}");
+            std::process::exit(1);
+        }
+    };
+    let seed = config.seed();
+
+    if let Some(Command::Render(args)) = &cli.command {
+        let rendered = config
+            .tree
+            .create(seed)
+            .and_then(|state| render::run(state, args));
+        if let Err(msg) = rendered {
+            eprintln!("{msg}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut trees = Vec::new();
+    for (i, tree) in config.all_trees().enumerate() {
+        // each tree gets a layout of its own
+        match tree.create(seed.wrapping_add(i as u64)) {
+            Ok(state) => trees.push((tree.id.clone(), Arc::new(Mutex::new(state)))),
+            Err(msg) => {
+                eprintln!("tree {:?}: {msg}", tree.id);
+                std::process::exit(1);
+            }
+        }
+    }
+    let trees = match Trees::new(trees) {
+        Ok(trees) => trees,
+        Err(msg) => {
+            eprintln!("{msg}");
+            std::process::exit(1);
+        }
+    };
 
-    // web server
+    for (tree, (_, state)) in config.all_trees().zip(trees.iter()) {
+        let Some(port) = &tree.midi.alsa_port else {
+            continue;
+        };
+        #[cfg(feature = "alsa")]
+        if let Err(msg) = midi::listen_alsa(state.clone(), port, tree.midi.mappings.clone()) {
+            eprintln!("tree {:?}: {msg}", tree.id);
+            std::process::exit(1);
+        }
+        #[cfg(not(feature = "alsa"))]
+        {
+            let _ = state;
+            tracing::warn!("not listening on ALSA port {port}, this build has no `alsa` feature");
+        }
+    }
+
+    // web server, and MQTT, OSC and live audio of every tree
     {
-        let s = state.clone();
+        let connections: Vec<_> = config
+            .all_trees()
+            .zip(trees.iter())
+            .map(|(tree, (_, state))| {
+                let (mqtt, osc, pcm) =
+                    (tree.mqtt.clone(), tree.osc.clone(), tree.audio.pcm.clone());
+                (state.clone(), mqtt, osc, pcm)
+            })
+            .collect();
+        let t = trees.clone();
+        let assets = Assets::new(config.assets_dir.clone());
+        let auth = config.auth.clone();
         std::thread::spawn(|| {
-            tokio::runtime::Runtime::new()
-                .unwrap()
-                .block_on(web::serve(s));
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            for (s, mqtt, osc, pcm) in connections {
+                if let Some(mqtt) = mqtt {
+                    rt.spawn(mqtt::run(s.clone(), mqtt));
+                }
+                if let Some(osc) = osc {
+                    rt.spawn(osc::run(s.clone(), osc));
+                }
+                if let Some(pcm) = pcm {
+                    rt.spawn(audio::receive_pcm(s, pcm));
+                }
+            }
+            rt.block_on(web::serve(t, assets, auth));
         });
     }
 
+    if cli.headless {
+        run_headless(trees);
+        return;
+    }
+
     // GUI
-    let app = LedApp::new(state);
+    let app = LedApp::new(trees);
     eframe::run_native(
         "LED Strip Simulator",
         eframe::NativeOptions::default(),
@@ -0,0 +1,662 @@
+use crate::{
+    clock::MAX_SPEED,
+    effects::{Flash, Region},
+    state::{AppState, Effect, MAX_LEDS},
+};
+use egui::Color32;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use serde::{Deserialize, Deserializer};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+/// The `midi` section of the config file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MidiConfig {
+    /// Standard MIDI File played along with the effect clock, starting at time zero
+    pub file: Option<PathBuf>,
+    /// create an ALSA sequencer port with this name and follow what is sent to
+    /// it, needs the `alsa` feature
+    pub alsa_port: Option<String>,
+    /// what the notes and controllers do, all mappings that match an event are applied
+    pub mappings: Vec<Mapping>,
+}
+
+/// The MIDI messages the tree reacts to. Note offs (and note ons without
+/// velocity) are left out, flashes have a length of their own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiEvent {
+    Note {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    Control {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+}
+
+impl MidiEvent {
+    fn from_message(channel: u8, message: MidiMessage) -> Option<Self> {
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel > 0 => Some(MidiEvent::Note {
+                channel,
+                key: key.as_int(),
+                velocity: vel.as_int(),
+            }),
+            MidiMessage::Controller { controller, value } => Some(MidiEvent::Control {
+                channel,
+                controller: controller.as_int(),
+                value: value.as_int(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Velocity or value, from 0 to 1.
+    fn amount(self) -> f32 {
+        match self {
+            MidiEvent::Note { velocity, .. } => velocity as f32 / 127.0,
+            MidiEvent::Control { value, .. } => value as f32 / 127.0,
+        }
+    }
+
+    /// Notes always trigger, controllers from the middle up, like the buttons
+    /// of controllers that send 127 when pressed and 0 when released.
+    fn pressed(self) -> bool {
+        match self {
+            MidiEvent::Note { .. } => true,
+            MidiEvent::Control { value, .. } => value >= 64,
+        }
+    }
+}
+
+/// What a note or controller does, for example
+/// `{"note": 60, "action": "flash", "height": [0.0, 0.3], "color": [255, 0, 0]}`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Mapping {
+    /// 0 to 15, any channel if not set
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// reacts to this note, or to all notes if neither `note` nor `cc` is set
+    #[serde(default)]
+    pub note: Option<u8>,
+    /// reacts to this controller
+    #[serde(default)]
+    pub cc: Option<u8>,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// starts the effect, named as in the `/effects/...` routes
+    Effect {
+        #[serde(deserialize_with = "effect_by_name")]
+        effect: Effect,
+    },
+    Stop,
+    /// lights a region, as bright as the note is loud; the region is the whole
+    /// tree unless `leds` (first and last index) or `height` (from 0 to 1) is given
+    Flash {
+        #[serde(default)]
+        leds: Option<[usize; 2]>,
+        #[serde(default)]
+        height: Option<[f32; 2]>,
+        /// defaults to the base color
+        #[serde(default)]
+        color: Option<[u8; 3]>,
+        #[serde(default = "default_flash_seconds")]
+        seconds: f32,
+    },
+    /// the master brightness follows the velocity or value
+    Brightness,
+    /// the speed of the effect clock follows the velocity or value, up to `max`
+    Speed {
+        #[serde(default = "default_max_speed")]
+        max: f32,
+    },
+}
+
+/// Longest flash, in seconds.
+const MAX_FLASH_SECONDS: f32 = 3600.0;
+
+fn default_flash_seconds() -> f32 {
+    0.3
+}
+
+fn default_max_speed() -> f32 {
+    2.0
+}
+
+fn effect_by_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Effect, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    Effect::from_name(&name)
+        .filter(|e| *e != Effect::None)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown effect {name:?}")))
+}
+
+impl MidiConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            mapping
+                .validate()
+                .map_err(|e| format!("MIDI mapping {}: {e}", i + 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl Mapping {
+    fn validate(&self) -> Result<(), String> {
+        if self.channel.is_some_and(|c| c > 15) {
+            return Err("the channel must be between 0 and 15".to_string());
+        }
+        if self.note.is_some_and(|n| n > 127) || self.cc.is_some_and(|c| c > 127) {
+            return Err("notes and controllers must be between 0 and 127".to_string());
+        }
+        match self.action {
+            Action::Flash {
+                leds,
+                height,
+                seconds,
+                ..
+            } => {
+                if leds.is_some_and(|[first, last]| first > last || last >= MAX_LEDS) {
+                    return Err(format!(
+                        "the LEDs must be a first and a last index below {MAX_LEDS}"
+                    ));
+                }
+                if height.is_some_and(|[low, high]| {
+                    !(low.is_finite() && high.is_finite() && low <= high)
+                }) {
+                    return Err("the height must be a low and a high fraction".to_string());
+                }
+                if !(0.0..=MAX_FLASH_SECONDS).contains(&seconds) {
+                    return Err(format!(
+                        "a flash lasts from 0 to {MAX_FLASH_SECONDS} seconds, not {seconds}"
+                    ));
+                }
+            }
+            Action::Speed { max } => {
+                if !(0.0..=MAX_SPEED).contains(&max) {
+                    return Err(format!("the speed must be between 0 and {MAX_SPEED}"));
+                }
+            }
+            Action::Effect { .. } | Action::Stop | Action::Brightness => {}
+        }
+        Ok(())
+    }
+
+    fn matches(&self, event: MidiEvent) -> bool {
+        let (channel, note, cc) = match event {
+            MidiEvent::Note { channel, key, .. } => (channel, Some(key), None),
+            MidiEvent::Control {
+                channel,
+                controller,
+                ..
+            } => (channel, None, Some(controller)),
+        };
+        if self.channel.is_some_and(|c| c != channel) {
+            return false;
+        }
+        match (self.note, self.cc) {
+            (None, None) => note.is_some(),
+            (Some(_), _) if note.is_some() => self.note == note,
+            (_, Some(_)) if cc.is_some() => self.cc == cc,
+            _ => false,
+        }
+    }
+}
+
+/// Applies every mapping that matches `event`, which happened at `time` of the clock.
+pub fn apply(s: &mut AppState, mappings: &[Mapping], event: MidiEvent, time: Duration) {
+    for mapping in mappings.iter().filter(|m| m.matches(event)) {
+        match mapping.action {
+            Action::Effect { effect } => {
+                if event.pressed() {
+                    s.start_effect(effect);
+                }
+            }
+            Action::Stop => {
+                if event.pressed() {
+                    s.stop_effects();
+                }
+            }
+            Action::Flash {
+                leds,
+                height,
+                color,
+                seconds,
+            } => {
+                if !event.pressed() {
+                    continue;
+                }
+                let region = match (leds, height) {
+                    (Some([first, last]), _) => Region::Leds {
+                        start: first,
+                        end: last + 1,
+                    },
+                    (None, Some([low, high])) => Region::Height { low, high },
+                    (None, None) => Region::All,
+                };
+                let color = color.map_or(s.base_color, |[r, g, b]| Color32::from_rgb(r, g, b));
+                let scale = |c: u8| (c as f32 * event.amount()).round() as u8;
+                s.flashes.push(Flash {
+                    region,
+                    color: Color32::from_rgb(scale(color.r()), scale(color.g()), scale(color.b())),
+                    start: time,
+                    length: Duration::from_secs_f32(seconds.max(0.0)),
+                });
+            }
+            Action::Brightness => s.set_brightness((event.amount() * 255.0).round() as u8),
+            Action::Speed { max } => s.clock.set_speed(event.amount() * max),
+        }
+    }
+}
+
+/// The events of a MIDI file with the time they happen at.
+pub struct Song {
+    events: Vec<(Duration, MidiEvent)>,
+}
+
+impl Song {
+    /// All tracks are played at the same time, as in the usual format 1 files.
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let smf = Smf::parse(bytes).map_err(|e| format!("not a MIDI file: {e}"))?;
+        let no_division = match smf.header.timing {
+            Timing::Metrical(per_beat) => per_beat.as_int() == 0,
+            Timing::Timecode(_, per_frame) => per_frame == 0,
+        };
+        if no_division {
+            return Err("the header gives zero ticks per beat or frame".to_string());
+        }
+
+        // absolute ticks, so the tracks can be merged
+        let mut timed = Vec::new();
+        for track in &smf.tracks {
+            let mut tick = 0u64;
+            for event in track {
+                tick += event.delta.as_int() as u64;
+                timed.push((tick, event.kind));
+            }
+        }
+        // stable, so events at the same tick keep their order
+        timed.sort_by_key(|(tick, _)| *tick);
+
+        let mut events = Vec::new();
+        let (mut last_tick, mut seconds) = (0, 0.0);
+        // a quarter note lasts half a second until the first tempo event
+        let mut tempo = 500_000.0;
+        for (tick, kind) in timed {
+            let ticks = (tick - last_tick) as f64;
+            seconds += match smf.header.timing {
+                Timing::Metrical(per_beat) => ticks * tempo / 1e6 / per_beat.as_int() as f64,
+                Timing::Timecode(fps, per_frame) => {
+                    ticks / (fps.as_f32() as f64 * per_frame as f64)
+                }
+            };
+            last_tick = tick;
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo = t.as_int() as f64,
+                TrackEventKind::Midi { channel, message } => {
+                    if let Some(event) = MidiEvent::from_message(channel.as_int(), message) {
+                        events.push((Duration::from_secs_f64(seconds), event));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { events })
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self, String> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        Self::parse(&bytes).map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
+
+/// Plays a song along with the effect clock.
+#[derive(Clone)]
+pub struct Player {
+    song: Arc<Song>,
+    mappings: Arc<Vec<Mapping>>,
+    /// index of the first event not played yet
+    next: usize,
+    /// time of the clock up to which the events are played
+    position: Duration,
+}
+
+impl Player {
+    pub fn new(song: Song, mappings: Vec<Mapping>) -> Self {
+        Self {
+            song: Arc::new(song),
+            mappings: Arc::new(mappings),
+            next: 0,
+            position: Duration::ZERO,
+        }
+    }
+
+    /// Applies the events up to `now`. If the clock was set back, the song is
+    /// played again from the start, so the effect and parameters are what they
+    /// would have been; the flashes of the past are over by then anyway.
+    pub fn play(&mut self, s: &mut AppState, now: Duration) {
+        if now < self.position {
+            self.next = 0;
+        }
+        self.position = now;
+        while let Some(&(time, event)) = self.song.events.get(self.next) {
+            if time > now {
+                break;
+            }
+            apply(s, &self.mappings, event, time);
+            self.next += 1;
+        }
+    }
+}
+
+/// Follows a port of the ALSA sequencer that other programs (keyboards,
+/// sequencers, `aconnect`) can send to, on a thread of its own.
+#[cfg(feature = "alsa")]
+pub fn listen_alsa(
+    state: Arc<parking_lot::Mutex<AppState>>,
+    name: &str,
+    mappings: Vec<Mapping>,
+) -> Result<(), String> {
+    use alsa::seq::{EvCtrl, EvNote, EventType, PortCap, PortType, Seq};
+    use std::ffi::CString;
+
+    let error = |e: alsa::Error| format!("ALSA sequencer: {e}");
+    let seq = Seq::open(None, Some(alsa::Direction::Capture), false).map_err(error)?;
+    let name = CString::new(name).map_err(|_| "the port name contains a 0 byte".to_string())?;
+    seq.set_client_name(&name).map_err(error)?;
+    let port = seq
+        .create_simple_port(
+            &name,
+            PortCap::WRITE | PortCap::SUBS_WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )
+        .map_err(error)?;
+    tracing::info!(
+        "listening for MIDI on ALSA sequencer port {}:{port}",
+        seq.client_id().map_err(error)?
+    );
+
+    std::thread::spawn(move || {
+        let mut input = seq.input();
+        loop {
+            let event = match input.event_input() {
+                Ok(event) => event,
+                Err(e) => {
+                    tracing::warn!("could not read from the ALSA sequencer: {e}");
+                    continue;
+                }
+            };
+            let event = match event.get_type() {
+                EventType::Noteon => event.get_data::<EvNote>().and_then(|n| {
+                    (n.velocity > 0).then_some(MidiEvent::Note {
+                        channel: n.channel,
+                        key: n.note,
+                        velocity: n.velocity,
+                    })
+                }),
+                EventType::Controller => event.get_data::<EvCtrl>().map(|c| MidiEvent::Control {
+                    channel: c.channel,
+                    controller: c.param as u8,
+                    value: c.value.clamp(0, 127) as u8,
+                }),
+                _ => None,
+            };
+            if let Some(event) = event {
+                let mut s = state.lock();
+                let now = s.clock.now();
+                apply(&mut s, &mappings, event, now);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::Clock, effects::update_effects, geometry::LayoutConfig};
+    use midly::{
+        num::{u15, u24, u28, u4, u7},
+        Format, Fps, Header, TrackEvent,
+    };
+
+    fn test_state() -> AppState {
+        let mut s = AppState::new(20, LayoutConfig::default(), 1).unwrap();
+        for led in &mut s.leds {
+            led.determined_position = led.actual_position;
+        }
+        s.clock = Clock::manual(Duration::ZERO);
+        s
+    }
+
+    fn mappings(json: &str) -> Vec<Mapping> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn note(channel: u8, key: u8, velocity: u8) -> MidiEvent {
+        MidiEvent::Note {
+            channel,
+            key,
+            velocity,
+        }
+    }
+
+    fn lit(s: &AppState) -> Vec<usize> {
+        (0..s.leds.len())
+            .filter(|&i| s.output_color(&s.leds[i]) != Color32::BLACK)
+            .collect()
+    }
+
+    /// Two tracks at 480 ticks per quarter note, the tempo doubles after the first beat.
+    fn song_bytes() -> Vec<u8> {
+        let event = |delta: u32, kind| TrackEvent {
+            delta: u28::new(delta),
+            kind,
+        };
+        let midi = |key: u8, vel: u8| TrackEventKind::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::NoteOn {
+                key: u7::new(key),
+                vel: u7::new(vel),
+            },
+        };
+        let end = TrackEventKind::Meta(MetaMessage::EndOfTrack);
+        let tempo = vec![
+            event(
+                480,
+                TrackEventKind::Meta(MetaMessage::Tempo(u24::new(250_000))),
+            ),
+            event(0, end),
+        ];
+        let notes = vec![
+            event(0, midi(60, 100)),
+            event(480, midi(60, 0)),
+            event(0, midi(62, 127)),
+            event(960, midi(64, 64)),
+            event(0, end),
+        ];
+        let smf = Smf {
+            header: Header::new(Format::Parallel, Timing::Metrical(u15::new(480))),
+            tracks: vec![tempo, notes],
+        };
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn parses_songs_with_tempo_changes() {
+        let song = Song::parse(&song_bytes()).unwrap();
+        let times: Vec<f32> = song.events.iter().map(|(t, _)| t.as_secs_f32()).collect();
+        // the note off is left out, 960 ticks after the tempo change are two quarters of 0.25 s
+        assert_eq!(times, [0.0, 0.5, 1.0]);
+        assert_eq!(song.events[1].1, note(0, 62, 127));
+        assert!(Song::parse(b"not midi").is_err());
+
+        let song = song_bytes();
+        for timing in [
+            Timing::Metrical(u15::new(0)),
+            Timing::Timecode(Fps::Fps25, 0),
+        ] {
+            let smf = Smf {
+                header: Header::new(Format::Parallel, timing),
+                tracks: Smf::parse(&song).unwrap().tracks,
+            };
+            let mut bytes = Vec::new();
+            smf.write_std(&mut bytes).unwrap();
+            assert!(Song::parse(&bytes).is_err());
+        }
+    }
+
+    #[test]
+    fn bad_mappings_are_rejected() {
+        let config = |json: &str| MidiConfig {
+            mappings: mappings(json),
+            ..MidiConfig::default()
+        };
+        let good = r#"[{"note": 60, "action": "flash", "leds": [0, 9], "height": [0.0, 0.5]},
+            {"cc": 1, "action": "speed", "max": 4}]"#;
+        assert!(config(good).validate().is_ok());
+        for bad in [
+            r#"[{"action": "flash", "leds": [0, 18446744073709551615]}]"#,
+            r#"[{"action": "flash", "leds": [5, 2]}]"#,
+            r#"[{"action": "flash", "seconds": 1e30}]"#,
+            r#"[{"action": "flash", "seconds": -1}]"#,
+            r#"[{"action": "flash", "height": [0.5, 0.1]}]"#,
+            r#"[{"action": "speed", "max": 1e9}]"#,
+            r#"[{"channel": 16, "action": "stop"}]"#,
+            r#"[{"note": 128, "action": "stop"}]"#,
+        ] {
+            assert!(config(bad).validate().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn mappings_match_notes_and_controllers() {
+        let m = mappings(
+            r#"[
+                {"note": 60, "action": "stop"},
+                {"cc": 7, "channel": 2, "action": "brightness"},
+                {"action": "stop"}
+            ]"#,
+        );
+        assert!(m[0].matches(note(5, 60, 1)));
+        assert!(!m[0].matches(note(5, 61, 1)));
+        let control = |channel, controller| MidiEvent::Control {
+            channel,
+            controller,
+            value: 10,
+        };
+        assert!(!m[0].matches(control(0, 60)));
+        assert!(m[1].matches(control(2, 7)));
+        assert!(!m[1].matches(control(3, 7)));
+        assert!(m[2].matches(note(9, 1, 1)));
+        assert!(!m[2].matches(control(0, 1)));
+
+        let unknown = r#"[{"note": 1, "action": "effect", "effect": "fireworks"}]"#;
+        assert!(serde_json::from_str::<Vec<Mapping>>(unknown).is_err());
+    }
+
+    #[test]
+    fn events_drive_the_state() {
+        let mut s = test_state();
+        let m = mappings(
+            r#"[
+                {"note": 60, "action": "effect", "effect": "blink"},
+                {"cc": 1, "action": "brightness"},
+                {"cc": 2, "action": "speed", "max": 4.0},
+                {"cc": 3, "action": "stop"}
+            ]"#,
+        );
+        apply(&mut s, &m, note(0, 60, 1), Duration::ZERO);
+        assert!(s.effect == Effect::Blink);
+
+        let control = |controller, value| MidiEvent::Control {
+            channel: 0,
+            controller,
+            value,
+        };
+        apply(&mut s, &m, control(1, 127), Duration::ZERO);
+        assert_eq!(s.brightness, 255);
+        apply(&mut s, &m, control(2, 127), Duration::ZERO);
+        assert_eq!(s.clock.speed(), 4.0);
+        // releasing a button does nothing
+        apply(&mut s, &m, control(3, 0), Duration::ZERO);
+        assert!(s.effect == Effect::Blink);
+        apply(&mut s, &m, control(3, 127), Duration::ZERO);
+        assert!(s.effect == Effect::None);
+    }
+
+    #[test]
+    fn notes_flash_regions() {
+        let mut s = test_state();
+        let m = mappings(
+            r#"[
+                {"note": 60, "action": "flash", "leds": [2, 4], "color": [255, 0, 0], "seconds": 1.0},
+                {"note": 72, "action": "flash", "height": [0.8, 1.0]}
+            ]"#,
+        );
+        apply(&mut s, &m, note(0, 60, 127), Duration::ZERO);
+        update_effects(&mut s, Duration::from_millis(500));
+        assert_eq!(lit(&s), [2, 3, 4]);
+        // half way through it's at half brightness, and the effect's colors are left alone
+        assert_eq!(s.leds[2].flash, Color32::from_rgb(128, 0, 0));
+        assert_eq!(s.leds[2].color, Color32::BLACK);
+        update_effects(&mut s, Duration::from_secs(1));
+        assert!(lit(&s).is_empty());
+        assert!(s.flashes.is_empty());
+
+        apply(&mut s, &m, note(0, 72, 127), Duration::from_secs(1));
+        update_effects(&mut s, Duration::from_secs(1));
+        let top = lit(&s);
+        assert!(!top.is_empty() && top.len() < s.leds.len());
+        let min_z = top
+            .iter()
+            .map(|&i| s.leds[i].determined_position.z)
+            .fold(f32::INFINITY, f32::min);
+        assert!(s
+            .leds
+            .iter()
+            .all(|l| l.flash != Color32::BLACK || l.determined_position.z < min_z));
+    }
+
+    #[test]
+    fn songs_play_along_with_the_clock() {
+        let mut s = test_state();
+        let m = mappings(
+            r#"[
+                {"note": 60, "action": "effect", "effect": "allon"},
+                {"note": 62, "action": "effect", "effect": "blink"},
+                {"note": 64, "action": "flash", "seconds": 0.5}
+            ]"#,
+        );
+        s.midi = Some(Player::new(Song::parse(&song_bytes()).unwrap(), m));
+
+        let at = |s: &mut AppState, secs: f32| {
+            let now = Duration::from_secs_f32(secs);
+            s.clock.seek(now);
+            update_effects(s, now);
+        };
+        at(&mut s, 0.1);
+        assert!(s.effect == Effect::AllOn);
+        at(&mut s, 0.6);
+        assert!(s.effect == Effect::Blink);
+        at(&mut s, 1.1);
+        assert_eq!(lit(&s).len(), s.leds.len());
+        assert_eq!(s.flashes.len(), 1);
+
+        // back to the start, the song plays again
+        at(&mut s, 0.2);
+        assert!(s.effect == Effect::AllOn);
+        assert!(s.flashes.is_empty());
+    }
+}
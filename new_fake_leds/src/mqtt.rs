@@ -0,0 +1,294 @@
+use crate::state::{AppState, Effect};
+use egui::Color32;
+use parking_lot::Mutex;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+/// The `mqtt` section of the config file. The tree obeys anyone who may
+/// publish to `base_topic` on the broker, the tokens of `auth` aren't checked.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// also the id of the Home Assistant entity, so it has to be unique
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// all topics of the tree start with this
+    pub base_topic: String,
+    /// where Home Assistant looks for discovery messages
+    pub discovery_prefix: String,
+    /// name of the light in Home Assistant
+    pub name: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "led_sim".to_string(),
+            username: None,
+            password: None,
+            base_topic: "led_sim".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            name: "Christmas tree".to_string(),
+        }
+    }
+}
+
+impl MqttConfig {
+    fn command_topic(&self) -> String {
+        format!("{}/light/set", self.base_topic)
+    }
+
+    fn state_topic(&self) -> String {
+        format!("{}/light/state", self.base_topic)
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.base_topic)
+    }
+
+    fn discovery_topic(&self) -> String {
+        format!("{}/light/{}/config", self.discovery_prefix, self.client_id)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+/// A command of Home Assistant's JSON light schema, every field is optional.
+#[derive(Deserialize)]
+struct LightCommand {
+    /// `ON` or `OFF`
+    state: Option<String>,
+    brightness: Option<u8>,
+    color: Option<Rgb>,
+    effect: Option<String>,
+}
+
+/// Tells Home Assistant that the tree is a light with effects.
+fn discovery_payload(config: &MqttConfig) -> Value {
+    let effects: Vec<&str> = Effect::ALL
+        .iter()
+        .filter(|e| **e != Effect::None)
+        .map(|e| e.name())
+        .collect();
+    json!({
+        "name": config.name,
+        "unique_id": config.client_id,
+        "schema": "json",
+        "command_topic": config.command_topic(),
+        "state_topic": config.state_topic(),
+        "availability_topic": config.availability_topic(),
+        "brightness": true,
+        "supported_color_modes": ["rgb"],
+        "effect": true,
+        "effect_list": effects,
+        "device": {
+            "identifiers": [config.client_id],
+            "name": config.name,
+            "model": "LED simulator",
+        },
+    })
+}
+
+/// The tree is on while an effect is running.
+fn state_payload(s: &AppState) -> Value {
+    let on = s.effect != Effect::None;
+    let mut payload = json!({
+        "state": if on { "ON" } else { "OFF" },
+        "brightness": s.brightness,
+        "color_mode": "rgb",
+        "color": Rgb {
+            r: s.base_color.r(),
+            g: s.base_color.g(),
+            b: s.base_color.b(),
+        },
+    });
+    if on {
+        payload["effect"] = json!(s.effect.name());
+    }
+    payload
+}
+
+/// Applies a command from Home Assistant. Switching on without an effect
+/// lights all LEDs, switching off stops the effect.
+fn apply_command(s: &mut AppState, command: &LightCommand) -> Result<(), String> {
+    if let Some(brightness) = command.brightness {
+        s.set_brightness(brightness);
+    }
+    if let Some(Rgb { r, g, b }) = command.color {
+        s.set_base_color(Color32::from_rgb(r, g, b));
+    }
+    match (command.state.as_deref(), &command.effect) {
+        (Some("OFF"), _) => s.stop_effects(),
+        (_, Some(name)) => {
+            let effect =
+                Effect::from_name(name).ok_or_else(|| format!("unknown effect {name:?}"))?;
+            s.start_effect(effect);
+        }
+        (Some("ON"), None) if s.effect == Effect::None => s.start_effect(Effect::AllOn),
+        (Some("ON") | None, None) => {}
+        (Some(other), None) => return Err(format!("unknown state {other:?}")),
+    }
+    Ok(())
+}
+
+/// Connects to the broker, announces the tree to Home Assistant, follows its
+/// commands and publishes every change of the state. Reconnects forever.
+pub async fn run(state: Arc<Mutex<AppState>>, config: MqttConfig) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    options.set_last_will(LastWill::new(
+        config.availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let mut events = state.lock().events.subscribe();
+    let publish_state = |s: &AppState| {
+        let payload = state_payload(s).to_string();
+        if let Err(e) = client.try_publish(config.state_topic(), QoS::AtLeastOnce, true, payload) {
+            warn!("could not publish the state to MQTT: {e}");
+        }
+    };
+
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("connected to MQTT broker {}:{}", config.host, config.port);
+                    // the broker forgets everything when the session ends, so do this on every connect
+                    let announce = [
+                        (config.discovery_topic(), discovery_payload(&config).to_string()),
+                        (config.availability_topic(), "online".to_string()),
+                    ];
+                    for (topic, payload) in announce {
+                        let _ = client.try_publish(topic, QoS::AtLeastOnce, true, payload);
+                    }
+                    let _ = client.try_subscribe(config.command_topic(), QoS::AtLeastOnce);
+                    publish_state(&state.lock());
+                }
+                Ok(Event::Incoming(Packet::Publish(message))) => {
+                    debug!("mqtt {} {:?}", message.topic, message.payload);
+                    let result = serde_json::from_slice(&message.payload)
+                        .map_err(|e| e.to_string())
+                        .and_then(|command| apply_command(&mut state.lock(), &command));
+                    if let Err(msg) = result {
+                        warn!("ignoring MQTT command {:?}: {msg}", message.payload);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection failed: {e}, retrying in 5 s");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            },
+            event = events.recv() => match event {
+                Ok(_) | Err(RecvError::Lagged(_)) => publish_state(&state.lock()),
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::LayoutConfig;
+
+    fn test_state() -> AppState {
+        AppState::new(10, LayoutConfig::default(), 1).unwrap()
+    }
+
+    fn command(json: &str) -> LightCommand {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn discovery_lists_the_effects() {
+        let payload = discovery_payload(&MqttConfig::default());
+        assert_eq!(payload["command_topic"], "led_sim/light/set");
+        assert_eq!(payload["schema"], "json");
+        let effects = payload["effect_list"].as_array().unwrap();
+        assert_eq!(effects.len(), Effect::ALL.len() - 1);
+        assert!(effects.contains(&json!("blink")));
+    }
+
+    #[test]
+    fn commands_drive_the_state() {
+        let mut s = test_state();
+        assert_eq!(state_payload(&s)["state"], "OFF");
+
+        apply_command(&mut s, &command(r#"{"state": "ON"}"#)).unwrap();
+        assert!(s.effect == Effect::AllOn);
+        assert_eq!(state_payload(&s)["effect"], "allon");
+
+        let json = r#"{"state": "ON", "brightness": 128, "color": {"r": 255, "g": 0, "b": 10}, "effect": "blink"}"#;
+        apply_command(&mut s, &command(json)).unwrap();
+        assert!(s.effect == Effect::Blink);
+        assert_eq!(s.brightness, 128);
+        assert_eq!(s.base_color, Color32::from_rgb(255, 0, 10));
+        let payload = state_payload(&s);
+        assert_eq!(payload["color"], json!({"r": 255, "g": 0, "b": 10}));
+        assert_eq!(payload["brightness"], 128);
+
+        apply_command(&mut s, &command(r#"{"state": "OFF"}"#)).unwrap();
+        assert!(s.effect == Effect::None);
+        assert_eq!(state_payload(&s)["state"], "OFF");
+
+        assert!(apply_command(&mut s, &command(r#"{"effect": "fireworks"}"#)).is_err());
+        assert!(apply_command(&mut s, &command(r#"{"state": "DIM"}"#)).is_err());
+    }
+
+    /// Runs against a real broker, e.g. `mosquitto -p 1883`.
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    async fn against_local_broker() {
+        let state = Arc::new(Mutex::new(test_state()));
+        tokio::spawn(run(state.clone(), MqttConfig::default()));
+
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("led_sim_test", "localhost", 1883), 10);
+        client
+            .subscribe("led_sim/light/state", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        // give the simulator time to connect and subscribe
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        client
+            .publish(
+                "led_sim/light/set",
+                QoS::AtLeastOnce,
+                false,
+                r#"{"state": "ON", "effect": "blink"}"#,
+            )
+            .await
+            .unwrap();
+
+        loop {
+            if let Event::Incoming(Packet::Publish(p)) = eventloop.poll().await.unwrap() {
+                let payload: Value = serde_json::from_slice(&p.payload).unwrap();
+                if payload["effect"] == "blink" {
+                    break;
+                }
+            }
+        }
+        assert!(state.lock().effect == Effect::Blink);
+    }
+}
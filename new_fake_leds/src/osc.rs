@@ -0,0 +1,444 @@
+use crate::{
+    clock::MAX_SPEED,
+    state::{AppState, Effect},
+};
+use egui::Color32;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// The `osc` section of the config file, for controlling the tree live from
+/// TouchOSC layouts or music software.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct OscConfig {
+    /// address to receive on, all interfaces by default. OSC messages need no
+    /// token, bind to `127.0.0.1` or firewall the port if `auth` should hold.
+    pub bind: SocketAddr,
+    /// all addresses start with this
+    pub prefix: String,
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 9000)),
+            prefix: "/tree".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i64),
+    Float(f64),
+    String(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+    /// `N` (nil) and `I` (impulse), both carry no value
+    Nil,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+/// Reads `len` bytes, padded to a multiple of 4.
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    let padded = len.next_multiple_of(4);
+    if data.len() < padded {
+        return Err("packet ends too early".to_string());
+    }
+    let (value, rest) = data.split_at(padded);
+    *data = rest;
+    Ok(&value[..len])
+}
+
+fn take_array<const N: usize>(data: &mut &[u8]) -> Result<[u8; N], String> {
+    Ok(take(data, N)?.try_into().unwrap())
+}
+
+/// A zero terminated string, padded to a multiple of 4.
+fn take_string(data: &mut &[u8]) -> Result<String, String> {
+    let len = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or("string is not terminated")?;
+    let bytes = take(data, len + 1)?;
+    String::from_utf8(bytes[..len].to_vec()).map_err(|_| "string is not UTF-8".to_string())
+}
+
+fn parse_message(mut data: &[u8]) -> Result<OscMessage, String> {
+    let data = &mut data;
+    let address = take_string(data)?;
+    if !address.starts_with('/') {
+        return Err(format!("invalid address {address:?}"));
+    }
+    // old senders may leave out the type tags
+    if data.is_empty() {
+        return Ok(OscMessage {
+            address,
+            args: Vec::new(),
+        });
+    }
+    let tags = take_string(data)?;
+    let tags = tags.strip_prefix(',').ok_or("missing type tags")?;
+
+    let mut args = Vec::new();
+    for tag in tags.chars() {
+        let arg = match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(take_array(data)?) as i64),
+            'h' => OscArg::Int(i64::from_be_bytes(take_array(data)?)),
+            'f' => OscArg::Float(f32::from_be_bytes(take_array(data)?) as f64),
+            'd' => OscArg::Float(f64::from_be_bytes(take_array(data)?)),
+            's' | 'S' => OscArg::String(take_string(data)?),
+            'b' => {
+                let len = i32::from_be_bytes(take_array(data)?);
+                let len = usize::try_from(len).map_err(|_| "negative blob size")?;
+                OscArg::Blob(take(data, len)?.to_vec())
+            }
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            'N' | 'I' => OscArg::Nil,
+            // colors, MIDI messages and chars take 4 bytes, time tags 8, none of them are used here
+            'r' | 'm' | 'c' => {
+                take(data, 4)?;
+                OscArg::Nil
+            }
+            't' => {
+                take(data, 8)?;
+                OscArg::Nil
+            }
+            other => return Err(format!("unsupported type tag {other:?}")),
+        };
+        args.push(arg);
+    }
+    Ok(OscMessage { address, args })
+}
+
+/// All messages in a packet, which is a message or a bundle of packets.
+/// Bundles are applied right away, their time tag is ignored.
+pub fn parse_packet(data: &[u8]) -> Result<Vec<OscMessage>, String> {
+    let Some(mut rest) = data.strip_prefix(b"#bundle\0") else {
+        return Ok(vec![parse_message(data)?]);
+    };
+    let rest = &mut rest;
+    take(rest, 8)?; // time tag
+    let mut messages = Vec::new();
+    while !rest.is_empty() {
+        let len = i32::from_be_bytes(take_array(rest)?);
+        let len = usize::try_from(len).map_err(|_| "negative element size")?;
+        messages.extend(parse_packet(take(rest, len)?)?);
+    }
+    Ok(messages)
+}
+
+impl OscArg {
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            OscArg::Int(i) => Some(i as f64),
+            OscArg::Float(f) => Some(f),
+            OscArg::Bool(b) => Some(if b { 1.0 } else { 0.0 }),
+            _ => None,
+        }
+    }
+
+    /// A color channel or brightness: floats go from 0 to 1, ints from 0 to 255.
+    fn as_level(&self) -> Result<u8, String> {
+        let level = match *self {
+            OscArg::Float(f) if (0.0..=1.0).contains(&f) => (f * 255.0).round() as u8,
+            OscArg::Int(i) => u8::try_from(i).map_err(|_| format!("{i} is not in 0..=255"))?,
+            ref other => return Err(format!("{other:?} is not a level")),
+        };
+        Ok(level)
+    }
+}
+
+/// The single number argument of `message`.
+fn number(message: &OscMessage) -> Result<f64, String> {
+    match message.args.as_slice() {
+        [arg] => arg.as_f64(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{} takes one number", message.address))
+}
+
+/// Applies `message`, whose address has `prefix` removed already:
+///
+/// - `/effect/<name>` starts an effect, `/effect/stop` stops it. Buttons send 1
+///   when pressed and 0 when released, only the press counts.
+/// - `/color r g b` sets the base color, `/brightness x` the master brightness.
+/// - `/param/speed x`, `/param/paused x` and `/param/time x` drive the clock
+///   the effects run on, like `POST /clock`.
+fn apply(s: &mut AppState, address: &str, message: &OscMessage) -> Result<(), String> {
+    let pressed = message
+        .args
+        .first()
+        .is_none_or(|a| a.as_f64().is_none_or(|v| v != 0.0));
+    match address.split('/').collect::<Vec<_>>().as_slice() {
+        ["", "effect", "stop"] => {
+            if pressed {
+                s.stop_effects();
+            }
+        }
+        ["", "effect", name] => {
+            let effect = Effect::from_name(name)
+                .filter(|e| *e != Effect::None)
+                .ok_or_else(|| format!("unknown effect {name:?}"))?;
+            if pressed {
+                s.start_effect(effect);
+            }
+        }
+        ["", "color"] => {
+            let [r, g, b] = message.args.as_slice() else {
+                return Err("/color takes three levels".to_string());
+            };
+            s.set_base_color(Color32::from_rgb(
+                r.as_level()?,
+                g.as_level()?,
+                b.as_level()?,
+            ));
+        }
+        ["", "brightness"] => {
+            let [level] = message.args.as_slice() else {
+                return Err("/brightness takes one level".to_string());
+            };
+            s.set_brightness(level.as_level()?);
+        }
+        ["", "param", "speed"] => {
+            let speed = number(message)? as f32;
+            if !(0.0..=MAX_SPEED).contains(&speed) {
+                return Err(format!("speed must be between 0 and {MAX_SPEED}"));
+            }
+            s.clock.set_speed(speed);
+        }
+        ["", "param", "paused"] => {
+            if number(message)? != 0.0 {
+                s.clock.pause();
+            } else {
+                s.clock.resume();
+            }
+        }
+        ["", "param", "time"] => {
+            let time = Duration::try_from_secs_f64(number(message)?)
+                .map_err(|_| "time must be a positive number of seconds".to_string())?;
+            s.clock.seek(time);
+        }
+        _ => return Err(format!("unknown address {}", message.address)),
+    }
+    Ok(())
+}
+
+/// Applies every message of `packet` that starts with `prefix`.
+fn handle_packet(state: &Mutex<AppState>, prefix: &str, packet: &[u8]) -> Result<(), String> {
+    for message in parse_packet(packet)? {
+        debug!("osc {} {:?}", message.address, message.args);
+        // other software may share the port, leave their messages alone, also
+        // those of e.g. `/treehouse` when the prefix is `/tree`
+        let Some(address) = message
+            .address
+            .strip_prefix(prefix.trim_end_matches('/'))
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        else {
+            continue;
+        };
+        apply(&mut state.lock(), address, &message)?;
+    }
+    Ok(())
+}
+
+async fn receive(state: Arc<Mutex<AppState>>, socket: UdpSocket, prefix: String) {
+    let mut buffer = vec![0; 65536];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("could not receive OSC: {e}");
+                continue;
+            }
+        };
+        if let Err(msg) = handle_packet(&state, &prefix, &buffer[..len]) {
+            warn!("ignoring OSC packet from {from}: {msg}");
+        }
+    }
+}
+
+/// Receives OSC messages forever.
+pub async fn run(state: Arc<Mutex<AppState>>, config: OscConfig) {
+    let socket = match UdpSocket::bind(config.bind).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("could not listen for OSC on {}: {e}", config.bind);
+            return;
+        }
+    };
+    info!("listening for OSC on {}", config.bind);
+    receive(state, socket, config.prefix).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::LayoutConfig;
+
+    fn test_state() -> Mutex<AppState> {
+        Mutex::new(AppState::new(10, LayoutConfig::default(), 1).unwrap())
+    }
+
+    fn pad(bytes: &mut Vec<u8>) {
+        while !bytes.len().is_multiple_of(4) {
+            bytes.push(0);
+        }
+    }
+
+    /// Encodes a message the way TouchOSC does.
+    fn encode(address: &str, args: &[OscArg]) -> Vec<u8> {
+        let mut bytes = address.as_bytes().to_vec();
+        bytes.push(0);
+        pad(&mut bytes);
+        let mut tags = ",".to_string();
+        let mut data = Vec::new();
+        for arg in args {
+            match arg {
+                OscArg::Int(i) => {
+                    tags.push('i');
+                    data.extend((*i as i32).to_be_bytes());
+                }
+                OscArg::Float(f) => {
+                    tags.push('f');
+                    data.extend((*f as f32).to_be_bytes());
+                }
+                OscArg::String(s) => {
+                    tags.push('s');
+                    data.extend(s.as_bytes());
+                    data.push(0);
+                    pad(&mut data);
+                }
+                OscArg::Blob(b) => {
+                    tags.push('b');
+                    data.extend((b.len() as i32).to_be_bytes());
+                    data.extend(b);
+                    pad(&mut data);
+                }
+                OscArg::Bool(b) => tags.push(if *b { 'T' } else { 'F' }),
+                OscArg::Nil => tags.push('N'),
+            }
+        }
+        bytes.extend(tags.as_bytes());
+        bytes.push(0);
+        pad(&mut bytes);
+        bytes.extend(data);
+        bytes
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"#bundle\0".to_vec();
+        bytes.extend(1u64.to_be_bytes());
+        for element in elements {
+            bytes.extend((element.len() as i32).to_be_bytes());
+            bytes.extend(element);
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_messages_and_bundles() {
+        let args = vec![
+            OscArg::Int(-3),
+            OscArg::Float(0.5),
+            OscArg::String("hello".to_string()),
+            OscArg::Blob(vec![1, 2, 3]),
+            OscArg::Bool(true),
+            OscArg::Nil,
+        ];
+        let message = encode("/tree/test", &args);
+        assert_eq!(message.len() % 4, 0);
+        let expected = OscMessage {
+            address: "/tree/test".to_string(),
+            args,
+        };
+        assert_eq!(parse_packet(&message).unwrap(), vec![expected.clone()]);
+
+        let nested = bundle(&[bundle(std::slice::from_ref(&message)), message]);
+        assert_eq!(parse_packet(&nested).unwrap(), vec![expected; 2]);
+
+        let message = encode("/tree/test", &[OscArg::Int(1)]);
+        assert!(parse_packet(&message[..message.len() - 1]).is_err());
+        assert!(parse_packet(b"tree\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn messages_drive_the_state() {
+        let state = test_state();
+        let send =
+            |address: &str, args: &[OscArg]| handle_packet(&state, "/tree", &encode(address, args));
+
+        send("/tree/effect/sweepingplane", &[OscArg::Float(1.0)]).unwrap();
+        assert!(state.lock().effect == Effect::SweepingPlane);
+        // releasing the button changes nothing
+        send("/tree/effect/blink", &[OscArg::Float(0.0)]).unwrap();
+        assert!(state.lock().effect == Effect::SweepingPlane);
+        send("/tree/effect/blink", &[]).unwrap();
+        assert!(state.lock().effect == Effect::Blink);
+        send("/tree/effect/stop", &[OscArg::Int(1)]).unwrap();
+        assert!(state.lock().effect == Effect::None);
+        assert!(send("/tree/effect/fireworks", &[]).is_err());
+
+        let levels = [OscArg::Float(1.0), OscArg::Int(128), OscArg::Float(0.0)];
+        send("/tree/color", &levels).unwrap();
+        assert_eq!(state.lock().base_color, Color32::from_rgb(255, 128, 0));
+        send("/tree/brightness", &[OscArg::Float(0.5)]).unwrap();
+        assert_eq!(state.lock().brightness, 128);
+        assert!(send("/tree/brightness", &[OscArg::Int(300)]).is_err());
+        assert!(send("/tree/color", &[OscArg::Int(1)]).is_err());
+
+        send("/tree/param/speed", &[OscArg::Float(0.5)]).unwrap();
+        assert_eq!(state.lock().clock.speed(), 0.5);
+        assert!(send("/tree/param/speed", &[OscArg::Float(-1.0)]).is_err());
+        send("/tree/param/paused", &[OscArg::Bool(true)]).unwrap();
+        send("/tree/param/time", &[OscArg::Float(2.5)]).unwrap();
+        assert!(state.lock().clock.is_paused());
+        assert_eq!(state.lock().clock.now(), Duration::from_secs_f64(2.5));
+
+        // messages for other software are skipped
+        send("/mixer/fader1", &[OscArg::Float(0.3)]).unwrap();
+        send("/treehouse/effect/blink", &[]).unwrap();
+        assert!(state.lock().effect == Effect::None);
+        let packet = bundle(&[
+            encode("/treehouse/x", &[]),
+            encode("/tree/brightness", &[OscArg::Int(20)]),
+        ]);
+        handle_packet(&state, "/tree/", &packet).unwrap();
+        assert_eq!(state.lock().brightness, 20);
+        assert!(send("/tree/unknown", &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn receives_over_udp() {
+        let state = Arc::new(test_state());
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(receive(state.clone(), socket, "/tree".to_string()));
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let packet = bundle(&[
+            encode("/tree/effect/allon", &[OscArg::Float(1.0)]),
+            encode("/tree/brightness", &[OscArg::Int(10)]),
+        ]);
+        sender.send_to(&packet, addr).await.unwrap();
+
+        for _ in 0..100 {
+            if state.lock().brightness == 10 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let s = state.lock();
+        assert!(s.effect == Effect::AllOn);
+        assert_eq!(s.brightness, 10);
+    }
+}
@@ -0,0 +1,758 @@
+use crate::state::MAX_LEDS;
+use egui::Color32;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use utoipa::ToSchema;
+
+/// Gamma of the monitor the simulator runs on, used to preview the light of
+/// the LEDs, which is linear in the values they are sent.
+const DISPLAY_GAMMA: f32 = 2.2;
+
+/// What an LED is asked to show: a color and, for RGBW strips, the white LED.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgbw {
+    pub color: Color32,
+    pub white: u8,
+}
+
+impl Default for Rgbw {
+    fn default() -> Self {
+        Self {
+            color: Color32::BLACK,
+            white: 0,
+        }
+    }
+}
+
+/// Order in which a strip expects the channels of each LED.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorOrder {
+    Rgb,
+    /// WS2812 and most of its clones
+    #[default]
+    Grb,
+    Bgr,
+    /// SK6812 RGBW
+    Rgbw,
+    Grbw,
+}
+
+impl ColorOrder {
+    /// For each byte of an LED, the channel it holds: 0 red, 1 green, 2 blue, 3 white.
+    fn layout(self) -> &'static [usize] {
+        match self {
+            ColorOrder::Rgb => &[0, 1, 2],
+            ColorOrder::Grb => &[1, 0, 2],
+            ColorOrder::Bgr => &[2, 1, 0],
+            ColorOrder::Rgbw => &[0, 1, 2, 3],
+            ColorOrder::Grbw => &[1, 0, 2, 3],
+        }
+    }
+}
+
+/// How much of a color the white LED of an RGBW strip takes over from the
+/// color LEDs, on top of the white the effects ask for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhiteExtraction {
+    /// nothing, the white LED only shows the white of the effects
+    None,
+    /// the part all three channels have in common
+    #[default]
+    Min,
+    /// as much of the white LED's own color as fits into the color, so warm
+    /// white LEDs take over from red and green more than from blue
+    Temperature,
+}
+
+/// The color of a white LED of `kelvin`, from 0 to 1 with the strongest
+/// channel at 1. Tanner Helland's fit of the black body colors.
+fn kelvin_to_rgb(kelvin: f32) -> [f32; 3] {
+    let t = kelvin / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.699 * (t - 60.0).powf(-0.133_205)
+    };
+    let g = if t <= 66.0 {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_16 * (t - 60.0).powf(-0.075_514_85)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (t - 10.0).ln() - 305.044_8
+    };
+    [r, g, b].map(|c: f32| c.clamp(0.0, 255.0) / 255.0)
+}
+
+/// The `output` section of the config file: how the colors of the effects
+/// are turned into the bytes a strip is sent.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// exponent applied to every channel, 1 sends the colors as they are, about
+    /// 2.2 makes fades look even on WS2812 LEDs
+    pub gamma: f32,
+    /// factors for red, green and blue, to make white look white
+    pub white_balance: [f32; 3],
+    pub color_order: ColorOrder,
+    /// what RGBW strips light their white LED with
+    pub white: WhiteExtraction,
+    /// color temperature of the white LEDs, SK6812 come in about 2700, 4500 and 6500 K
+    pub white_kelvin: f32,
+    /// carry the rounding error of each channel over to the next frame, so
+    /// dim colors and slow fades don't step
+    pub dither: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            white_balance: [1.0, 1.0, 1.0],
+            color_order: ColorOrder::default(),
+            white: WhiteExtraction::default(),
+            white_kelvin: 6500.0,
+            dither: false,
+        }
+    }
+}
+
+impl OutputConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.gamma.is_finite() && self.gamma > 0.0) {
+            return Err(format!("gamma must be positive, not {}", self.gamma));
+        }
+        if self
+            .white_balance
+            .iter()
+            .any(|f| !(f.is_finite() && (0.0..=1.0).contains(f)))
+        {
+            return Err("the white balance factors must be between 0 and 1".to_string());
+        }
+        if !(1000.0..=40000.0).contains(&self.white_kelvin) {
+            return Err(format!(
+                "the white LEDs must be between 1000 and 40000 K, not {}",
+                self.white_kelvin
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Turns frames into the bytes of one strip.
+#[derive(Clone, Default)]
+pub struct Driver {
+    pub config: OutputConfig,
+    /// rounding error of every byte of the last frame, for dithering
+    residual: Vec<f32>,
+}
+
+impl Driver {
+    pub fn new(config: OutputConfig) -> Self {
+        Self {
+            config,
+            residual: Vec::new(),
+        }
+    }
+
+    /// The bytes to send for `pixels`, three or four per LED in `color_order`.
+    /// Strips without a white LED show the white with their color LEDs.
+    pub fn encode(&mut self, pixels: &[Rgbw]) -> Vec<u8> {
+        let config = &self.config;
+        let layout = config.color_order.layout();
+        let has_white = layout.len() == 4;
+        let len = pixels.len() * layout.len();
+        if self.residual.len() != len {
+            self.residual = vec![0.0; len];
+        }
+        // the light of the white LED, in the same units as the color LEDs after the gamma
+        let tint = kelvin_to_rgb(config.white_kelvin).map(|c| c.powf(DISPLAY_GAMMA));
+        let (unit, per_unit) = match config.white {
+            WhiteExtraction::None => ([1.0; 3], 0.0),
+            WhiteExtraction::Min => ([1.0; 3], 1.0),
+            WhiteExtraction::Temperature => (tint, 1.0),
+        };
+
+        let mut bytes = Vec::with_capacity(len);
+        for (pixel, residual) in pixels.iter().zip(self.residual.chunks_mut(layout.len())) {
+            let c = pixel.color;
+            let linear = |v: u8| (v as f32 / 255.0).powf(config.gamma);
+            let mut rgb = [c.r(), c.g(), c.b()].map(linear);
+            let mut white = linear(pixel.white);
+            if has_white {
+                let taken = (0..3).map(|i| rgb[i] / unit[i]).fold(1.0, f32::min) * per_unit;
+                rgb = [0, 1, 2].map(|i| (rgb[i] - taken * unit[i]).max(0.0));
+                white = (white + taken).min(1.0);
+            } else {
+                rgb = [0, 1, 2].map(|i| (rgb[i] + white * tint[i]).min(1.0));
+            }
+            let light = [0, 1, 2]
+                .map(|i| rgb[i] * config.white_balance[i])
+                .into_iter()
+                .chain([white])
+                .collect::<Vec<f32>>();
+
+            for (&channel, residual) in layout.iter().zip(residual) {
+                let wanted = light[channel] * 255.0 + *residual;
+                let sent = wanted.round().clamp(0.0, 255.0);
+                if config.dither {
+                    *residual = wanted - sent;
+                }
+                bytes.push(sent as u8);
+            }
+        }
+        bytes
+    }
+
+    /// What a strip shows for `bytes`, as colors for the monitor. The LEDs
+    /// are taken to be ideal, with light proportional to the value they get,
+    /// and the white LED in the color of `white_kelvin`.
+    pub fn preview(&self, bytes: &[u8]) -> Vec<Color32> {
+        let layout = self.config.color_order.layout();
+        let tint = kelvin_to_rgb(self.config.white_kelvin).map(|c| c.powf(DISPLAY_GAMMA));
+        bytes
+            .chunks_exact(layout.len())
+            .map(|led| {
+                let mut light = [0.0f32; 4];
+                for (&channel, &byte) in layout.iter().zip(led) {
+                    light[channel] = byte as f32 / 255.0;
+                }
+                let [r, g, b] = [0, 1, 2].map(|c| {
+                    let l = (light[c] + light[3] * tint[c]).min(1.0);
+                    (l.powf(1.0 / DISPLAY_GAMMA) * 255.0).round() as u8
+                });
+                Color32::from_rgb(r, g, b)
+            })
+            .collect()
+    }
+
+    /// `pixel` as one color for the monitor, the white mixed in in the color of
+    /// the white LEDs. Before the output stage, like the colors of the effects.
+    pub fn mix_white(&self, pixel: Rgbw) -> Color32 {
+        if pixel.white == 0 {
+            return pixel.color;
+        }
+        let tint = kelvin_to_rgb(self.config.white_kelvin);
+        let c = pixel.color;
+        let [r, g, b] = [(c.r(), tint[0]), (c.g(), tint[1]), (c.b(), tint[2])]
+            .map(|(v, t)| v.saturating_add((pixel.white as f32 * t).round() as u8));
+        Color32::from_rgb(r, g, b)
+    }
+}
+
+/// One strip in the `outputs` section of the config file, e.g. one GPIO pin,
+/// SPI bus or network controller.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DriverConfig {
+    pub name: String,
+    /// LEDs on the strip, by default up to the last one a segment uses
+    #[serde(default)]
+    pub leds: Option<usize>,
+    #[serde(flatten)]
+    pub output: OutputConfig,
+}
+
+/// One entry of the `segments` section of the config file: LEDs `start..end`
+/// of the tree are LEDs `offset..` of the strip `output`.
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+pub struct SegmentConfig {
+    pub output: String,
+    pub start: usize,
+    pub end: usize,
+    #[serde(default)]
+    pub offset: usize,
+    /// the strip runs the other way, so `start` is its last LED of the segment
+    #[serde(default)]
+    pub reversed: bool,
+}
+
+/// A strip with its driver and the last frame it was sent.
+#[derive(Clone)]
+pub struct Output {
+    pub name: String,
+    pub driver: Driver,
+    leds: Option<usize>,
+    pub frame: Vec<u8>,
+}
+
+/// What `GET /outputs` reports about a strip.
+#[derive(Serialize, ToSchema)]
+pub struct OutputInfo {
+    name: String,
+    leds: usize,
+    segments: Vec<SegmentConfig>,
+    /// bytes of the last frame, in the strip's color order
+    frame: Vec<u8>,
+}
+
+/// All strips of the tree. The LEDs of the tree are numbered in one index
+/// space, the segments say where on the strips they are.
+#[derive(Clone)]
+pub struct Outputs {
+    pub outputs: Vec<Output>,
+    /// with their outputs as indices into `outputs`
+    segments: Vec<(usize, SegmentConfig)>,
+}
+
+impl Default for Outputs {
+    fn default() -> Self {
+        Self::single(OutputConfig::default())
+    }
+}
+
+impl Outputs {
+    /// One strip with all LEDs in order.
+    pub fn single(config: OutputConfig) -> Self {
+        Self {
+            outputs: vec![Output {
+                name: "main".to_string(),
+                driver: Driver::new(config),
+                leds: None,
+                frame: Vec::new(),
+            }],
+            segments: Vec::new(),
+        }
+    }
+
+    /// Checks that the segments fit on their strips without overlapping.
+    /// Without segments there has to be exactly one strip, which gets all LEDs.
+    pub fn new(drivers: Vec<DriverConfig>, segments: Vec<SegmentConfig>) -> Result<Self, String> {
+        if segments.is_empty() && drivers.len() != 1 {
+            return Err("segments are needed to split the LEDs over several outputs".to_string());
+        }
+        let mut names = HashSet::new();
+        for d in &drivers {
+            if !names.insert(d.name.as_str()) {
+                return Err(format!("there are two outputs named {:?}", d.name));
+            }
+            if d.leds.is_some_and(|leds| leds > MAX_LEDS) {
+                return Err(format!(
+                    "output {:?} can have at most {MAX_LEDS} LEDs",
+                    d.name
+                ));
+            }
+            d.output
+                .validate()
+                .map_err(|e| format!("output {:?}: {e}", d.name))?;
+        }
+
+        let mut resolved = Vec::new();
+        let mut used = HashSet::new();
+        for seg in segments {
+            let output = drivers
+                .iter()
+                .position(|d| d.name == seg.output)
+                .ok_or_else(|| format!("there is no output named {:?}", seg.output))?;
+            if seg.start >= seg.end {
+                return Err(format!(
+                    "segment {}..{} on {:?} is empty",
+                    seg.start, seg.end, seg.output
+                ));
+            }
+            // bounded, as the LEDs of each strip are tracked one by one below
+            let end = seg
+                .offset
+                .checked_add(seg.end - seg.start)
+                .filter(|&end| end <= MAX_LEDS && seg.end <= MAX_LEDS)
+                .ok_or_else(|| {
+                    format!(
+                        "segment {}..{} on {:?} goes past {MAX_LEDS} LEDs",
+                        seg.start, seg.end, seg.output
+                    )
+                })?;
+            let physical = seg.offset..end;
+            if let Some(leds) = drivers[output].leds {
+                if physical.end > leds {
+                    return Err(format!(
+                        "segment {}..{} does not fit on {:?}, which has {leds} LEDs",
+                        seg.start, seg.end, seg.output
+                    ));
+                }
+            }
+            if physical.clone().any(|p| !used.insert((output, p))) {
+                return Err(format!(
+                    "segment {}..{} overlaps another one on {:?}",
+                    seg.start, seg.end, seg.output
+                ));
+            }
+            resolved.push((output, seg));
+        }
+
+        Ok(Self {
+            outputs: drivers
+                .into_iter()
+                .map(|d| Output {
+                    name: d.name,
+                    driver: Driver::new(d.output),
+                    leds: d.leds,
+                    frame: Vec::new(),
+                })
+                .collect(),
+            segments: resolved,
+        })
+    }
+
+    /// LEDs on the strip `output` while the tree has `num`.
+    fn len(&self, output: usize, num: usize) -> usize {
+        if self.segments.is_empty() {
+            return num;
+        }
+        self.outputs[output].leds.unwrap_or_else(|| {
+            self.segments
+                .iter()
+                .filter(|(o, _)| *o == output)
+                .map(|(_, s)| s.offset + s.end - s.start)
+                .max()
+                .unwrap_or(0)
+        })
+    }
+
+    /// For every LED of the tree that is on a strip: its index, the strip
+    /// and its index on the strip. Segments beyond the `num` LEDs are cut off.
+    fn placements(&self, num: usize) -> Vec<(usize, usize, usize)> {
+        if self.segments.is_empty() {
+            return (0..num).map(|i| (i, 0, i)).collect();
+        }
+        let mut placements = Vec::new();
+        for (output, seg) in &self.segments {
+            let len = seg.end - seg.start;
+            for k in 0..len {
+                let led = seg.start + k;
+                if led >= num {
+                    continue;
+                }
+                let on_strip = if seg.reversed { len - 1 - k } else { k };
+                placements.push((led, *output, seg.offset + on_strip));
+            }
+        }
+        placements
+    }
+
+    /// Splits the frame `pixels` over the strips and encodes it for each. Strip
+    /// LEDs that no segment covers stay off.
+    pub fn encode(&mut self, pixels: &[Rgbw]) {
+        let mut strips: Vec<Vec<Rgbw>> = (0..self.outputs.len())
+            .map(|o| vec![Rgbw::default(); self.len(o, pixels.len())])
+            .collect();
+        for (led, output, on_strip) in self.placements(pixels.len()) {
+            strips[output][on_strip] = pixels[led];
+        }
+        for (output, strip) in self.outputs.iter_mut().zip(strips) {
+            output.frame = output.driver.encode(&strip);
+        }
+    }
+
+    /// What the strips show for the last frame, in the order of the `num` LEDs
+    /// of the tree. LEDs that are on no strip are black.
+    pub fn preview(&self, num: usize) -> Vec<Color32> {
+        let strips: Vec<Vec<Color32>> = self
+            .outputs
+            .iter()
+            .map(|o| o.driver.preview(&o.frame))
+            .collect();
+        let mut colors = vec![Color32::BLACK; num];
+        for (led, output, on_strip) in self.placements(num) {
+            if let Some(&color) = strips[output].get(on_strip) {
+                colors[led] = color;
+            }
+        }
+        colors
+    }
+
+    /// `Driver::mix_white` of the first strip.
+    pub fn mix_white(&self, pixel: Rgbw) -> Color32 {
+        self.outputs[0].driver.mix_white(pixel)
+    }
+
+    /// The strips with their segments and last frames, while the tree has `num` LEDs.
+    pub fn info(&self, num: usize) -> Vec<OutputInfo> {
+        self.outputs
+            .iter()
+            .enumerate()
+            .map(|(i, o)| OutputInfo {
+                name: o.name.clone(),
+                leds: self.len(i, num),
+                segments: self
+                    .segments
+                    .iter()
+                    .filter(|(output, _)| *output == i)
+                    .map(|(_, s)| s.clone())
+                    .collect(),
+                frame: o.frame.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn driver(config: OutputConfig) -> Driver {
+        config.validate().unwrap();
+        Driver::new(config)
+    }
+
+    fn pixels(colors: &[Color32]) -> Vec<Rgbw> {
+        colors
+            .iter()
+            .map(|&color| Rgbw { color, white: 0 })
+            .collect()
+    }
+
+    #[test]
+    fn channels_are_sent_in_order() {
+        let colors = [Color32::from_rgb(1, 2, 3), Color32::from_rgb(4, 5, 6)];
+        let mut grb = driver(OutputConfig::default());
+        assert_eq!(grb.encode(&pixels(&colors)), [2, 1, 3, 5, 4, 6]);
+
+        let mut bgr = driver(OutputConfig {
+            color_order: ColorOrder::Bgr,
+            ..OutputConfig::default()
+        });
+        assert_eq!(bgr.encode(&pixels(&colors)), [3, 2, 1, 6, 5, 4]);
+
+        let mut rgbw = driver(OutputConfig {
+            color_order: ColorOrder::Rgbw,
+            white: WhiteExtraction::None,
+            ..OutputConfig::default()
+        });
+        let bytes = rgbw.encode(&pixels(&colors));
+        assert_eq!(bytes, [1, 2, 3, 0, 4, 5, 6, 0]);
+        assert_eq!(rgbw.preview(&bytes).len(), 2);
+    }
+
+    #[test]
+    fn gamma_and_white_balance() {
+        let mut d = driver(OutputConfig {
+            gamma: 2.0,
+            white_balance: [1.0, 0.5, 0.8],
+            color_order: ColorOrder::Rgb,
+            ..OutputConfig::default()
+        });
+        let bytes = d.encode(&pixels(&[Color32::WHITE, Color32::from_rgb(128, 128, 128)]));
+        // 0.502² * 255 = 64.25
+        assert_eq!(bytes, [255, 128, 204, 64, 32, 51]);
+
+        assert!(OutputConfig {
+            gamma: 0.0,
+            ..OutputConfig::default()
+        }
+        .validate()
+        .is_err());
+        assert!(OutputConfig {
+            white_balance: [1.0, 1.5, 1.0],
+            ..OutputConfig::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn dithering_keeps_the_average() {
+        // 12 / 255 with gamma 2.2 is 0.31 after scaling, which rounds to 0
+        // without dithering, so the LED would be off
+        let color = [Color32::from_rgb(12, 12, 12)];
+        let config = OutputConfig {
+            gamma: 2.2,
+            ..OutputConfig::default()
+        };
+        let wanted = (12.0f32 / 255.0).powf(2.2) * 255.0;
+
+        let mut plain = driver(config.clone());
+        let plain_sum: u32 = (0..100)
+            .map(|_| plain.encode(&pixels(&color))[0] as u32)
+            .sum();
+        assert_eq!(plain_sum, 0);
+
+        let mut dithered = driver(OutputConfig {
+            dither: true,
+            ..config
+        });
+        let frames: Vec<u8> = (0..100)
+            .map(|_| dithered.encode(&pixels(&color))[0])
+            .collect();
+        assert!(frames.contains(&0) && frames.contains(&1));
+        let average = frames.iter().map(|&b| b as f32).sum::<f32>() / 100.0;
+        assert!((average - wanted).abs() < 0.02, "{average} {wanted}");
+    }
+
+    #[test]
+    fn preview_undoes_the_gamma() {
+        let mut d = driver(OutputConfig {
+            gamma: DISPLAY_GAMMA,
+            ..OutputConfig::default()
+        });
+        let color = Color32::from_rgb(200, 100, 30);
+        let bytes = d.encode(&pixels(&[color]));
+        let shown = d.preview(&bytes)[0];
+        for (a, b) in [(shown.r(), 200), (shown.g(), 100), (shown.b(), 30)] {
+            assert!(a.abs_diff(b) <= 3, "{shown:?}");
+        }
+
+        // without correction the colors look washed out
+        let mut linear = driver(OutputConfig::default());
+        let bytes = linear.encode(&pixels(&[color]));
+        let shown = linear.preview(&bytes)[0];
+        assert!(shown.b() > 60, "{shown:?}");
+    }
+
+    #[test]
+    fn white_is_extracted_for_rgbw_strips() {
+        let color = Color32::from_rgb(200, 100, 50);
+        let mut min = driver(OutputConfig {
+            color_order: ColorOrder::Grbw,
+            ..OutputConfig::default()
+        });
+        assert_eq!(min.encode(&pixels(&[color])), [50, 150, 0, 50]);
+        // the effects' white adds to what is extracted
+        let pixel = Rgbw { color, white: 100 };
+        assert_eq!(min.encode(&[pixel]), [50, 150, 0, 150]);
+
+        // an orange is mostly warm white, with a bit of green and blue on top
+        let mut warm = driver(OutputConfig {
+            color_order: ColorOrder::Rgbw,
+            white: WhiteExtraction::Temperature,
+            white_kelvin: 3000.0,
+            ..OutputConfig::default()
+        });
+        let bytes = warm.encode(&pixels(&[color]));
+        assert_eq!(bytes[0], 0);
+        assert!(bytes[3] > 150, "{bytes:?}");
+        // the mix still looks like the color it was asked for
+        let shown = warm.preview(&bytes)[0];
+        let expected = warm.preview(&[200, 100, 50, 0])[0];
+        for (a, b) in [
+            (shown.r(), expected.r()),
+            (shown.g(), expected.g()),
+            (shown.b(), expected.b()),
+        ] {
+            assert!(a.abs_diff(b) <= 2, "{shown:?} {expected:?}");
+        }
+    }
+
+    #[test]
+    fn white_without_a_white_led() {
+        let pixel = Rgbw {
+            color: Color32::from_rgb(10, 0, 0),
+            white: 100,
+        };
+        let mut rgb = driver(OutputConfig::default());
+        // GRB, the white LEDs of 6500 K are very nearly neutral
+        let bytes = rgb.encode(&[pixel]);
+        assert_eq!(bytes[1], 110);
+        assert!(
+            bytes[0].abs_diff(100) <= 5 && bytes[2].abs_diff(100) <= 5,
+            "{bytes:?}"
+        );
+
+        let warm = Driver::new(OutputConfig {
+            white_kelvin: 2700.0,
+            ..OutputConfig::default()
+        });
+        let mixed = warm.mix_white(pixel);
+        assert_eq!(mixed.r(), 110);
+        assert!(mixed.g() < 90 && mixed.b() < mixed.g(), "{mixed:?}");
+        assert_eq!(warm.mix_white(Rgbw::default()), Color32::BLACK);
+    }
+
+    fn segment(
+        output: &str,
+        start: usize,
+        end: usize,
+        offset: usize,
+        reversed: bool,
+    ) -> SegmentConfig {
+        SegmentConfig {
+            output: output.to_string(),
+            start,
+            end,
+            offset,
+            reversed,
+        }
+    }
+
+    fn strip(name: &str, leds: Option<usize>) -> DriverConfig {
+        DriverConfig {
+            name: name.to_string(),
+            leds,
+            output: OutputConfig {
+                color_order: ColorOrder::Rgb,
+                ..OutputConfig::default()
+            },
+        }
+    }
+
+    #[test]
+    fn segments_split_the_frame() {
+        let mut outputs = Outputs::new(
+            vec![strip("left", None), strip("right", Some(5))],
+            vec![
+                segment("left", 0, 3, 0, false),
+                segment("right", 3, 6, 1, true),
+                segment("left", 6, 8, 3, false),
+            ],
+        )
+        .unwrap();
+        let colors: Vec<Color32> = (1..=8).map(|i| Color32::from_rgb(i, 0, 0)).collect();
+        outputs.encode(&pixels(&colors));
+
+        let reds = |o: &Output| o.frame.chunks(3).map(|c| c[0]).collect::<Vec<u8>>();
+        assert_eq!(reds(&outputs.outputs[0]), [1, 2, 3, 7, 8]);
+        // the first LED of the right strip is not used, the segment runs backwards
+        assert_eq!(reds(&outputs.outputs[1]), [0, 6, 5, 4, 0]);
+
+        let preview = outputs.preview(8);
+        assert!(preview
+            .iter()
+            .zip(&colors)
+            .all(|(p, c)| (p.r() > 0) == (c.r() > 0)));
+        assert!(preview[3].r() < preview[5].r());
+
+        let info = outputs.info(8);
+        assert_eq!(info[1].leds, 5);
+        assert_eq!(info[0].segments.len(), 2);
+
+        // a tree with fewer LEDs than the segments cover
+        outputs.encode(&pixels(&colors[..4]));
+        assert_eq!(reds(&outputs.outputs[1]), [0, 0, 0, 4, 0]);
+    }
+
+    #[test]
+    fn segments_are_checked() {
+        let two = || vec![strip("a", Some(4)), strip("b", None)];
+        assert!(Outputs::new(two(), vec![]).is_err());
+        assert!(Outputs::new(two(), vec![segment("c", 0, 2, 0, false)]).is_err());
+        assert!(Outputs::new(two(), vec![segment("a", 2, 2, 0, false)]).is_err());
+        assert!(Outputs::new(two(), vec![segment("a", 0, 5, 0, false)]).is_err());
+        let overlapping = vec![segment("b", 0, 5, 0, false), segment("b", 5, 8, 4, true)];
+        assert!(Outputs::new(two(), overlapping).is_err());
+        assert!(Outputs::new(vec![strip("a", None), strip("a", None)], vec![]).is_err());
+
+        // too long to keep track of
+        let huge = [
+            segment("b", 0, usize::MAX, 0, false),
+            segment("b", 0, 10, usize::MAX - 5, false),
+            segment("b", MAX_LEDS, MAX_LEDS + 1, 0, false),
+            segment("b", 0, 10, MAX_LEDS - 5, false),
+        ];
+        for seg in huge {
+            assert!(Outputs::new(two(), vec![seg]).is_err());
+        }
+        let full = segment("b", 0, MAX_LEDS, 0, false);
+        assert_eq!(
+            Outputs::new(two(), vec![full]).unwrap().len(1, 10),
+            MAX_LEDS
+        );
+        assert!(Outputs::new(vec![strip("a", Some(MAX_LEDS + 1))], vec![]).is_err());
+
+        let single = Outputs::new(vec![strip("a", None)], vec![]).unwrap();
+        assert_eq!(single.info(7)[0].leds, 7);
+    }
+}
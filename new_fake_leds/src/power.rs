@@ -0,0 +1,142 @@
+use crate::state::AppState;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// The `power` section of the config file: what the LEDs draw and what the
+/// supply can deliver.
+#[derive(Clone, Deserialize, Serialize, ToSchema)]
+#[serde(default)]
+pub struct PowerConfig {
+    /// current of one channel, color or white, at full brightness, about 20 mA for WS2812 LEDs
+    pub ma_per_channel: f32,
+    /// current of an LED that is off, for its controller
+    pub idle_ma_per_led: f32,
+    /// what the power supply delivers, the frames are not limited if not set
+    pub supply_amps: Option<f32>,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            ma_per_channel: 20.0,
+            idle_ma_per_led: 1.0,
+            supply_amps: None,
+        }
+    }
+}
+
+/// The current drawn by the last frame, all in mA.
+#[derive(Clone, Copy, Serialize, ToSchema)]
+pub struct PowerEstimate {
+    /// what the frame would draw without the limit
+    pub requested_ma: f32,
+    /// what it draws after the limit
+    pub drawn_ma: f32,
+    /// what the supply delivers, `null` if there is no limit
+    pub budget_ma: Option<f32>,
+    /// factor the colors are scaled by to stay within the budget, 1 if they are
+    pub scale: f32,
+}
+
+impl Default for PowerEstimate {
+    fn default() -> Self {
+        Self {
+            requested_ma: 0.0,
+            drawn_ma: 0.0,
+            budget_ma: None,
+            scale: 1.0,
+        }
+    }
+}
+
+/// Estimates the current of the frame the effects just rendered and sets the
+/// scale `AppState::output_color` applies, so the frame stays within the budget.
+/// Runs after the effects, on the colors after masks, flashes and brightness.
+pub fn limit(s: &mut AppState) {
+    let config = &s.power_config;
+    let idle_ma = config.idle_ma_per_led * s.leds.len() as f32;
+    let channels: u32 = s
+        .leds
+        .iter()
+        .map(|l| {
+            let p = s.unlimited_pixel(l);
+            p.color.r() as u32 + p.color.g() as u32 + p.color.b() as u32 + p.white as u32
+        })
+        .sum();
+    let color_ma = channels as f32 / 255.0 * config.ma_per_channel;
+    let budget_ma = config.supply_amps.map(|a| a * 1000.0);
+
+    let scale = match budget_ma {
+        Some(budget) if idle_ma + color_ma > budget => {
+            ((budget - idle_ma) / color_ma).clamp(0.0, 1.0)
+        }
+        _ => 1.0,
+    };
+    s.power = PowerEstimate {
+        requested_ma: idle_ma + color_ma,
+        drawn_ma: idle_ma + color_ma * scale,
+        budget_ma,
+        scale,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        effects::update_effects,
+        geometry::LayoutConfig,
+        state::{AppState, Effect},
+    };
+    use egui::Color32;
+    use std::time::Duration;
+
+    fn all_white(num: usize) -> AppState {
+        let mut s = AppState::new(num, LayoutConfig::default(), 1).unwrap();
+        s.base_color = Color32::WHITE;
+        s.effect = Effect::AllOn;
+        s
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let mut s = all_white(500);
+        update_effects(&mut s, Duration::ZERO);
+        // 500 LEDs with three channels of 20 mA each, and 1 mA idle
+        assert_eq!(s.power.requested_ma, 30500.0);
+        assert_eq!(s.power.drawn_ma, 30500.0);
+        assert_eq!(s.power.scale, 1.0);
+        assert_eq!(s.output_color(&s.leds[0]), Color32::WHITE);
+    }
+
+    #[test]
+    fn frames_are_scaled_into_the_budget() {
+        let mut s = all_white(500);
+        s.power_config.supply_amps = Some(10.0);
+        update_effects(&mut s, Duration::ZERO);
+        assert_eq!(s.power.requested_ma, 30500.0);
+        assert!((s.power.drawn_ma - 10000.0).abs() < 0.1);
+        assert!((s.power.scale - 9500.0 / 30000.0).abs() < 1e-6);
+        // the effect's colors stay as they are, only the output is dimmed
+        assert_eq!(s.leds[0].color, Color32::WHITE);
+        let out = s.output_color(&s.leds[0]);
+        assert_eq!(out, Color32::from_rgb(80, 80, 80));
+        let drawn: f32 = s
+            .output_colors()
+            .iter()
+            .map(|c| (c.r() as f32 + c.g() as f32 + c.b() as f32) / 255.0 * 20.0 + 1.0)
+            .sum();
+        assert!(drawn <= 10000.0, "{drawn}");
+
+        // dimmer frames need no limit
+        s.set_brightness(64);
+        update_effects(&mut s, Duration::ZERO);
+        assert_eq!(s.power.scale, 1.0);
+        assert_eq!(s.output_color(&s.leds[0]), Color32::from_rgb(64, 64, 64));
+
+        // not even the idle current fits
+        s.power_config.supply_amps = Some(0.1);
+        update_effects(&mut s, Duration::ZERO);
+        assert_eq!(s.power.scale, 0.0);
+        assert_eq!(s.output_color(&s.leds[0]), Color32::BLACK);
+    }
+}
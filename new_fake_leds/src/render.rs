@@ -0,0 +1,363 @@
+use crate::{
+    camera::{self, Image},
+    clock::Clock,
+    effects::update_effects,
+    rotate_point,
+    state::{AppState, Effect, Vec3},
+};
+use clap::Args;
+use egui::Color32;
+use std::{
+    fs,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Most frames per second of a GIF, viewers slow down frames shorter than 2/100 s.
+const MAX_GIF_FPS: u16 = 50;
+
+/// Renders an effect to an animation file instead of showing it in a window.
+#[derive(Args)]
+pub struct RenderArgs {
+    /// effect to render, as named in the `/effects/...` routes
+    #[arg(long, value_parser = parse_effect)]
+    pub effect: Effect,
+
+    /// length of the animation in seconds
+    #[arg(long, default_value_t = 10.0)]
+    pub duration: f32,
+
+    /// frames per second, at most 50 for GIFs
+    #[arg(long, default_value_t = 20)]
+    pub fps: u16,
+
+    #[arg(long, default_value_t = 480)]
+    pub width: u32,
+
+    #[arg(long, default_value_t = 480)]
+    pub height: u32,
+
+    /// view rotation around the x axis, as in the GUI, defaults to looking at the tree from the side
+    #[arg(long, default_value_t = -std::f32::consts::FRAC_PI_2, allow_hyphen_values = true)]
+    pub rotation_x: f32,
+
+    /// view rotation around the y axis, as in the GUI
+    #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    pub rotation_y: f32,
+
+    /// `.gif` for an animated GIF, `.png` for an animated PNG,
+    /// anything else is a directory that gets one PNG per frame
+    #[arg(long)]
+    pub output: PathBuf,
+}
+
+fn parse_effect(name: &str) -> Result<Effect, String> {
+    Effect::from_name(name).ok_or_else(|| {
+        let names: Vec<&str> = Effect::ALL.iter().map(|e| e.name()).collect();
+        format!("unknown effect, expected one of {}", names.join(", "))
+    })
+}
+
+/// Runs the effect on a manual clock as fast as possible and writes the frames.
+///
+/// The LEDs are assumed to be perfectly calibrated, the effects work on the
+/// actual positions of the simulated LEDs.
+pub fn run(mut state: AppState, args: &RenderArgs) -> Result<(), String> {
+    for led in &mut state.leds {
+        led.determined_position = led.actual_position;
+    }
+    state.clock = Clock::manual(Duration::ZERO);
+    state.effect = args.effect;
+    state.effect_start = Duration::ZERO;
+    state.rotation_x = args.rotation_x;
+    state.rotation_y = args.rotation_y;
+
+    let fps = args.fps.max(1);
+    let gif = args
+        .output
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("gif"));
+    if gif && fps > MAX_GIF_FPS {
+        return Err(format!(
+            "GIFs can't show more than {MAX_GIF_FPS} frames per second, render to .png instead"
+        ));
+    }
+    let num_frames = ((args.duration * fps as f32).round() as u32).max(1);
+    let frame_time = Duration::from_secs_f32(1.0 / fps as f32);
+    let view = View::fit(
+        &state,
+        args.width.clamp(1, 4096),
+        args.height.clamp(1, 4096),
+    );
+
+    let mut writer = Writer::create(&args.output, &view, num_frames, fps)
+        .map_err(|e| format!("could not write {}: {e}", args.output.display()))?;
+    for _ in 0..num_frames {
+        let now = state.clock.now();
+        update_effects(&mut state, now);
+        writer
+            .write(&view.draw(&state))
+            .map_err(|e| format!("could not write {}: {e}", args.output.display()))?;
+        state.clock.advance(frame_time);
+    }
+    writer
+        .finish()
+        .map_err(|e| format!("could not write {}: {e}", args.output.display()))?;
+
+    println!(
+        "rendered {num_frames} frames of {} to {}",
+        args.effect.name(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// The projection of the GUI (`rotate_point`), scaled and moved so the whole tree fits the image.
+struct View {
+    width: u32,
+    height: u32,
+    scale: f32,
+    offset_x: f32,
+    offset_y: f32,
+}
+
+impl View {
+    fn fit(state: &AppState, width: u32, height: u32) -> Self {
+        let projected: Vec<Vec3> = state
+            .leds
+            .iter()
+            .map(|l| rotate_point(l.actual_position, state.rotation_x, state.rotation_y))
+            .collect();
+        let (mut min_x, mut max_x) = (f32::INFINITY, f32::NEG_INFINITY);
+        let (mut min_y, mut max_y) = (f32::INFINITY, f32::NEG_INFINITY);
+        for p in &projected {
+            min_x = min_x.min(p.x);
+            max_x = max_x.max(p.x);
+            min_y = min_y.min(p.y);
+            max_y = max_y.max(p.y);
+        }
+        if projected.is_empty() {
+            (min_x, max_x, min_y, max_y) = (-1.0, 1.0, -1.0, 1.0);
+        }
+
+        // leave a margin of 10% on every side
+        let span = (max_x - min_x).max(max_y - min_y).max(1e-3);
+        let scale = 0.8 * width.min(height) as f32 / span;
+        Self {
+            width,
+            height,
+            scale,
+            offset_x: width as f32 / 2.0 - (min_x + max_x) / 2.0 * scale,
+            offset_y: height as f32 / 2.0 + (min_y + max_y) / 2.0 * scale,
+        }
+    }
+
+    fn draw(&self, state: &AppState) -> Image {
+        let mut image = Image {
+            width: self.width,
+            height: self.height,
+            pixels: vec![0; (self.width * self.height * 3) as usize],
+        };
+        let radius = (self.width.min(self.height) as f32 / 120.0).max(1.5);
+
+        for led in &state.leds {
+            let color = state.output_color(led);
+            if color == Color32::BLACK {
+                continue;
+            }
+            let rotated = rotate_point(led.actual_position, state.rotation_x, state.rotation_y);
+            let x = self.offset_x + rotated.x * self.scale;
+            let y = self.offset_y - rotated.y * self.scale;
+            fill_circle(&mut image, x, y, radius, color);
+        }
+        image
+    }
+}
+
+fn fill_circle(image: &mut Image, cx: f32, cy: f32, radius: f32, color: Color32) {
+    let reach = radius.ceil() as i64;
+    for py in (cy as i64 - reach)..=(cy as i64 + reach) {
+        for px in (cx as i64 - reach)..=(cx as i64 + reach) {
+            if px < 0 || py < 0 || px >= image.width as i64 || py >= image.height as i64 {
+                continue;
+            }
+            let dx = px as f32 + 0.5 - cx;
+            let dy = py as f32 + 0.5 - cy;
+            if dx * dx + dy * dy <= radius * radius {
+                let i = ((py as u32 * image.width + px as u32) * 3) as usize;
+                image.pixels[i..i + 3].copy_from_slice(&[color.r(), color.g(), color.b()]);
+            }
+        }
+    }
+}
+
+/// Where the frames go, each one is written as soon as it's drawn so a long
+/// animation doesn't have to fit in memory.
+enum Writer {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        delays: Box<dyn Iterator<Item = u16>>,
+    },
+    Apng(png::Writer<BufWriter<File>>),
+    Sequence {
+        dir: PathBuf,
+        next: usize,
+    },
+}
+
+impl Writer {
+    /// Picks the format from the extension of `path`, see [`RenderArgs::output`].
+    fn create(path: &Path, view: &View, num_frames: u32, fps: u16) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("gif") => {
+                let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+                let mut encoder =
+                    gif::Encoder::new(file, view.width as u16, view.height as u16, &[])
+                        .map_err(|e| e.to_string())?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(|e| e.to_string())?;
+                Ok(Writer::Gif {
+                    encoder,
+                    delays: Box::new(gif_delays(fps)),
+                })
+            }
+            Some("png") => {
+                let file = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+                let mut encoder = png::Encoder::new(file, view.width, view.height);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder
+                    .set_animated(num_frames, 0)
+                    .map_err(|e| e.to_string())?;
+                encoder.set_frame_delay(1, fps).map_err(|e| e.to_string())?;
+                let writer = encoder.write_header().map_err(|e| e.to_string())?;
+                Ok(Writer::Apng(writer))
+            }
+            _ => {
+                fs::create_dir_all(path).map_err(|e| e.to_string())?;
+                Ok(Writer::Sequence {
+                    dir: path.to_path_buf(),
+                    next: 0,
+                })
+            }
+        }
+    }
+
+    fn write(&mut self, image: &Image) -> Result<(), String> {
+        match self {
+            Writer::Gif { encoder, delays } => {
+                let (width, height) = (image.width as u16, image.height as u16);
+                let mut frame = gif::Frame::from_rgb_speed(width, height, &image.pixels, 10);
+                frame.delay = delays.next().unwrap_or(1);
+                encoder.write_frame(&frame).map_err(|e| e.to_string())
+            }
+            Writer::Apng(writer) => writer
+                .write_image_data(&image.pixels)
+                .map_err(|e| e.to_string()),
+            Writer::Sequence { dir, next } => {
+                let path = dir.join(format!("frame_{next:05}.png"));
+                *next += 1;
+                fs::write(&path, camera::encode_png(image)).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            Writer::Gif { encoder, .. } => encoder
+                .into_inner()
+                .and_then(|mut file| file.flush())
+                .map_err(|e| e.to_string()),
+            Writer::Apng(writer) => writer.finish().map_err(|e| e.to_string()),
+            Writer::Sequence { .. } => Ok(()),
+        }
+    }
+}
+
+/// Delays of the frames of a GIF, in the hundredths of a second the format
+/// counts in. They are rounded so that every frame starts at most half a
+/// hundredth early or late, e.g. 3, 4, 3, 3, 4, 3 at 30 fps.
+fn gif_delays(fps: u16) -> impl Iterator<Item = u16> {
+    let fps = fps as u64;
+    let start = move |frame: u64| (frame * 100 + fps / 2) / fps;
+    (0..).map(move |frame| (start(frame + 1) - start(frame)) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::LayoutConfig;
+
+    fn args(output: PathBuf, fps: u16) -> RenderArgs {
+        RenderArgs {
+            effect: Effect::ConcentricColor,
+            duration: 0.5,
+            fps,
+            width: 64,
+            height: 32,
+            rotation_x: 0.0,
+            rotation_y: 0.0,
+            output,
+        }
+    }
+
+    #[test]
+    fn renders_frames_to_a_directory() {
+        let dir = std::env::temp_dir().join(format!("led_sim_render_{}", std::process::id()));
+        let state = AppState::new(20, LayoutConfig::default(), 1).unwrap();
+        run(state, &args(dir.clone(), 10)).unwrap();
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 5);
+        assert!(files[4].ends_with("frame_00004.png"));
+        let png = fs::read(&files[0]).unwrap();
+        // width and height are the first fields of the IHDR chunk
+        assert_eq!(&png[16..24], &[0, 0, 0, 64, 0, 0, 0, 32]);
+        fs::remove_dir_all(dir).unwrap();
+
+        // the animations are complete files once run returns
+        let gif = std::env::temp_dir().join(format!("led_sim_render_{}.gif", std::process::id()));
+        let state = AppState::new(20, LayoutConfig::default(), 1).unwrap();
+        run(state, &args(gif.clone(), 10)).unwrap();
+        let bytes = fs::read(&gif).unwrap();
+        assert!(bytes.starts_with(b"GIF89a"));
+        assert_eq!(bytes.last(), Some(&0x3b));
+        fs::remove_file(gif).unwrap();
+
+        let apng = std::env::temp_dir().join(format!("led_sim_render_{}.png", std::process::id()));
+        let state = AppState::new(20, LayoutConfig::default(), 1).unwrap();
+        run(state, &args(apng.clone(), 10)).unwrap();
+        let bytes = fs::read(&apng).unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(bytes));
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().animation_control().unwrap().num_frames, 5);
+        fs::remove_file(apng).unwrap();
+
+        let gif = std::env::temp_dir().join("led_sim_render_too_fast.gif");
+        let state = AppState::new(20, LayoutConfig::default(), 1).unwrap();
+        assert!(run(state, &args(gif.clone(), 60)).is_err());
+        assert!(!gif.exists());
+    }
+
+    #[test]
+    fn gif_frames_keep_the_frame_rate() {
+        let delays = |fps: u16, n: usize| gif_delays(fps).take(n).collect::<Vec<_>>();
+        assert_eq!(delays(20, 3), [5, 5, 5]);
+        assert_eq!(delays(30, 6), [3, 4, 3, 3, 4, 3]);
+        assert_eq!(delays(50, 2), [2, 2]);
+        // a second of frames is a second long
+        assert_eq!(gif_delays(30).take(30).sum::<u16>(), 100);
+        assert_eq!(gif_delays(7).take(7).sum::<u16>(), 100);
+    }
+}
@@ -1,23 +1,116 @@
+use crate::{
+    audio::Audio,
+    clock::Clock,
+    effects::Flash,
+    geometry::{self, LayoutConfig},
+    groups::Group,
+    midi::Player,
+    output::{Outputs, Rgbw},
+    power::{PowerConfig, PowerEstimate},
+};
 use egui::{Color32, Context};
+use rand::{rngs::StdRng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+use tokio::sync::{broadcast, watch};
+use utoipa::ToSchema;
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+/// Most LEDs a tree can have, far more than fit on any real tree.
+pub const MAX_LEDS: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct Vec3 {
     pub x: f32,
     pub y: f32,
     pub z: f32,
 }
 
+impl Vec3 {
+    pub const ZERO: Vec3 = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    /// Average of the given points, zero if there are none.
+    pub fn centroid(points: impl Iterator<Item = Vec3>) -> Vec3 {
+        let mut sum = Vec3::ZERO;
+        let mut count = 0;
+        for p in points {
+            sum = sum + p;
+            count += 1;
+        }
+        if count == 0 {
+            return Vec3::ZERO;
+        }
+        sum * (1.0 / count as f32)
+    }
+}
+
+impl std::ops::Add for Vec3 {
+    type Output = Vec3;
+    fn add(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl std::ops::Sub for Vec3 {
+    type Output = Vec3;
+    fn sub(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
+impl std::ops::Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, factor: f32) -> Vec3 {
+        Vec3 {
+            x: self.x * factor,
+            y: self.y * factor,
+            z: self.z * factor,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Led {
     pub enabled: bool,
     pub color: Color32,
+    /// the white LED of RGBW strips, on top of `color`
+    pub white: u8,
+    /// drawn over `color` by the flashes, see `effects::Flash`
+    pub flash: Color32,
+    /// replaces `color` and `white` where a group has a color, see `groups::apply`
+    pub group_color: Option<Rgbw>,
     pub determined_position: Vec3,
     pub actual_position: Vec3,
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Effect {
     None,
     Blink,
@@ -27,17 +120,102 @@ pub enum Effect {
     SweepingPlaneY,
     SweepingPlaneZ,
     ConcentricColor,
+    BeatPulse,
+    BassPlane,
+}
+
+impl Effect {
+    pub const ALL: [Effect; 10] = [
+        Effect::None,
+        Effect::Blink,
+        Effect::AllOn,
+        Effect::SweepingPlane,
+        Effect::SweepingPlaneX,
+        Effect::SweepingPlaneY,
+        Effect::SweepingPlaneZ,
+        Effect::ConcentricColor,
+        Effect::BeatPulse,
+        Effect::BassPlane,
+    ];
+
+    /// Short name, as used in the `/effects/...` routes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Effect::None => "none",
+            Effect::Blink => "blink",
+            Effect::AllOn => "allon",
+            Effect::SweepingPlane => "sweepingplane",
+            Effect::SweepingPlaneX => "planex",
+            Effect::SweepingPlaneY => "planey",
+            Effect::SweepingPlaneZ => "planez",
+            Effect::ConcentricColor => "concentriccolor",
+            Effect::BeatPulse => "beatpulse",
+            Effect::BassPlane => "bassplane",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Effect> {
+        Effect::ALL.into_iter().find(|e| e.name() == name)
+    }
+}
+
+/// Changes of the state that clients may want to know about without polling.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateEvent {
+    EffectStarted { effect: &'static str },
+    EffectsStopped,
+    MasksChanged { masked: Vec<usize> },
+    NumLedsChanged { num: usize },
+    BaseColorChanged { r: u8, g: u8, b: u8, w: u8 },
+    BrightnessChanged { brightness: u8 },
+    PositionsChanged,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     pub egui_context: Option<Context>,
+    pub events: broadcast::Sender<StateEvent>,
+    /// id of the last frame whose colors were read by the driver (GUI or headless loop)
+    pub frame_started: u64,
+    /// id of the last frame the driver has actually shown
+    pub frame_shown: watch::Sender<u64>,
 
     pub leds: Vec<Led>,
+    pub layout: LayoutConfig,
     pub base_color: Color32,
+    /// white the effects add where they show the base color, for RGBW strips
+    pub base_white: u8,
+    /// master brightness, applied on output, `Led::color` stays at full brightness
+    pub brightness: u8,
+    pub power_config: PowerConfig,
+    /// current drawn by the last frame, set by `power::limit`
+    pub power: PowerEstimate,
+    /// the strips, which turn the output colors into what they are sent
+    pub outputs: Outputs,
 
+    /// all randomness of the effects comes from here, so runs can be repeated
+    pub seed: u64,
+    pub rng: StdRng,
+
+    pub clock: Clock,
+    /// the music for the audio effects
+    pub audio: Audio,
+    /// MIDI file played along with `clock`
+    pub midi: Option<Player>,
+    /// short bursts of light over the effect, triggered by MIDI
+    pub flashes: Vec<Flash>,
+    /// named parts of the tree
+    pub groups: Vec<Group>,
+    /// where `groups` are saved, they are lost on exit if not set
+    pub groups_file: Option<PathBuf>,
+    /// colors of the groups that don't show the effect
+    pub group_colors: BTreeMap<String, Rgbw>,
     pub effect: Effect,
-    pub effect_start: Instant,
+    /// the group the effect is confined to, the whole tree if not set
+    pub effect_group: Option<String>,
+    /// time of `clock` at which the current effect (or its current cycle) started
+    pub effect_start: Duration,
 
     // sweeping plane state
     pub sweeping_plane_z: Vec<f32>,
@@ -51,12 +229,59 @@ pub struct AppState {
 }
 
 impl AppState {
-    pub fn new(num: usize) -> Self {
+    /// Creates `num` LEDs with their actual positions generated from `layout`.
+    pub fn new(num: usize, layout: LayoutConfig, seed: u64) -> Result<Self, String> {
+        let mut state = Self {
+            egui_context: None,
+            events: broadcast::channel(64).0,
+            frame_started: 0,
+            frame_shown: watch::channel(0).0,
+            leds: Vec::new(),
+            layout,
+            base_color: egui::Color32::from_rgb(150, 150, 150),
+            base_white: 0,
+            brightness: 255,
+            power_config: PowerConfig::default(),
+            power: PowerEstimate::default(),
+            outputs: Outputs::default(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            clock: Clock::realtime(),
+            audio: Audio::Silent,
+            midi: None,
+            flashes: Vec::new(),
+            groups: Vec::new(),
+            groups_file: None,
+            group_colors: BTreeMap::new(),
+            effect: Effect::None,
+            effect_group: None,
+            effect_start: Duration::ZERO,
+            sweeping_plane_z: Vec::new(),
+            sweeping_plane_hue: 0.0,
+            concentric_color_hue: 0.0,
+            rotation_x: -std::f32::consts::FRAC_PI_2,
+            rotation_y: 0.0,
+            offset_x: 0.0,
+            offset_y: 0.0,
+        };
+        state.set_num_leds(num)?;
+        Ok(state)
+    }
+
+    /// Replaces all LEDs with `num` new ones and stops the running effect.
+    /// Layout, base color and random number generator are kept.
+    pub fn set_num_leds(&mut self, num: usize) -> Result<(), String> {
+        if num > MAX_LEDS {
+            return Err(format!("there can be at most {MAX_LEDS} LEDs, not {num}"));
+        }
         let mut leds = Vec::with_capacity(num);
-        for pos in super::generate_cone_leds(num) {
+        for pos in geometry::generate(&self.layout, num)? {
             leds.push(super::state::Led {
                 enabled: true,
                 color: egui::Color32::BLACK,
+                white: 0,
+                flash: egui::Color32::BLACK,
+                group_color: None,
                 determined_position: Vec3 {
                     x: 0.0,
                     y: 0.0,
@@ -66,19 +291,187 @@ impl AppState {
             });
         }
 
-        Self {
-            egui_context: None,
-            leds,
-            base_color: egui::Color32::from_rgb(150, 150, 150),
-            effect: Effect::None,
-            effect_start: std::time::Instant::now(),
-            sweeping_plane_z: Vec::new(),
-            sweeping_plane_hue: 0.0,
-            concentric_color_hue: 0.0,
-            rotation_x: -std::f32::consts::FRAC_PI_2,
-            rotation_y: 0.0,
-            offset_x: 0.0,
-            offset_y: 0.0,
+        self.leds = leds;
+        self.effect = Effect::None;
+        self.sweeping_plane_z = Vec::new();
+        self.notify(StateEvent::NumLedsChanged { num });
+        Ok(())
+    }
+
+    /// Tells everyone listening on `events`, it's fine if no one is.
+    pub fn notify(&self, event: StateEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Called by the driver when it reads the LED colors for a new frame, returns the frame's id.
+    pub fn begin_frame(&mut self) -> u64 {
+        self.outputs.encode(&self.output_pixels());
+        self.frame_started += 1;
+        self.frame_started
+    }
+
+    /// Called by the driver once the frame `id` has been shown.
+    pub fn end_frame(&self, id: u64) {
+        self.frame_shown.send_if_modified(|shown| {
+            let newer = id > *shown;
+            if newer {
+                *shown = id;
+            }
+            newer
+        });
+    }
+
+    /// Lights exactly the LEDs in `on` in the base color, for calibration.
+    /// Returns the id of the first frame that shows them.
+    pub fn set_pattern(&mut self, on: &[usize]) -> u64 {
+        for led in self.leds.iter_mut() {
+            led.color = Color32::BLACK;
+            led.white = 0;
+        }
+        for &idx in on {
+            self.leds[idx].color = self.base_color;
+            self.leds[idx].white = self.base_white;
+        }
+        if let Some(ctx) = &self.egui_context {
+            ctx.request_repaint();
+        }
+        self.frame_started + 1
+    }
+
+    /// Starts `effect` from its beginning, on the whole tree.
+    pub fn start_effect(&mut self, effect: Effect) {
+        self.effect = effect;
+        self.effect_group = None;
+        self.effect_start = self.clock.now();
+        self.sweeping_plane_z = Vec::new();
+        self.notify(StateEvent::EffectStarted {
+            effect: effect.name(),
+        });
+    }
+
+    /// Stops the running effect and turns all LEDs off.
+    pub fn stop_effects(&mut self) {
+        self.effect = Effect::None;
+        for led in self.leds.iter_mut() {
+            led.color = Color32::BLACK;
+            led.white = 0;
+        }
+        self.notify(StateEvent::EffectsStopped);
+    }
+
+    pub fn set_base_color(&mut self, color: Color32) {
+        self.base_color = color;
+        self.notify(StateEvent::BaseColorChanged {
+            r: color.r(),
+            g: color.g(),
+            b: color.b(),
+            w: self.base_white,
+        });
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+        self.notify(StateEvent::BrightnessChanged { brightness });
+    }
+
+    /// What `led` really shows: black if it's masked, otherwise the brighter of its
+    /// color and flash, and its white, dimmed by `brightness` and the power limit.
+    /// Everything that shows the LEDs goes through here, effects only set `Led::color`
+    /// and `Led::white`.
+    pub fn output_pixel(&self, led: &Led) -> Rgbw {
+        self.scaled_pixel(led, self.power.scale)
+    }
+
+    /// `output_pixel` without the power limit, what the effects ask for.
+    pub fn unlimited_pixel(&self, led: &Led) -> Rgbw {
+        self.scaled_pixel(led, 1.0)
+    }
+
+    fn scaled_pixel(&self, led: &Led, power_scale: f32) -> Rgbw {
+        if !led.enabled {
+            return Rgbw::default();
+        }
+        // multiplied first, so full power gives the same as integer math
+        let scale = |c: u8| (c as f32 * self.brightness as f32 / 255.0 * power_scale) as u8;
+        let Rgbw { color, white } = led.group_color.unwrap_or(Rgbw {
+            color: led.color,
+            white: led.white,
+        });
+        Rgbw {
+            color: Color32::from_rgb(
+                scale(color.r().max(led.flash.r())),
+                scale(color.g().max(led.flash.g())),
+                scale(color.b().max(led.flash.b())),
+            ),
+            white: scale(white),
         }
     }
+
+    /// `output_pixel` of every LED, what the driver is given.
+    pub fn output_pixels(&self) -> Vec<Rgbw> {
+        self.leds.iter().map(|l| self.output_pixel(l)).collect()
+    }
+
+    /// `output_pixel` as one color, with the white mixed in, for everything that draws the LEDs.
+    pub fn output_color(&self, led: &Led) -> Color32 {
+        self.outputs.mix_white(self.output_pixel(led))
+    }
+
+    /// `output_color` of every LED.
+    pub fn output_colors(&self) -> Vec<Color32> {
+        self.leds.iter().map(|l| self.output_color(l)).collect()
+    }
+
+    /// What the strips show for the last frame, after gamma, white balance and dithering.
+    pub fn preview_colors(&self) -> Vec<Color32> {
+        self.outputs.preview(self.leds.len())
+    }
+
+    pub fn set_enabled(&mut self, idx: usize, enabled: bool) {
+        self.leds[idx].enabled = enabled;
+        self.notify_masks();
+    }
+
+    pub fn enable_all(&mut self) {
+        self.leds.iter_mut().for_each(|l| l.enabled = true);
+        self.notify_masks();
+    }
+
+    fn notify_masks(&self) {
+        let masked = (0..self.leds.len())
+            .filter(|&i| !self.leds[i].enabled)
+            .collect();
+        self.notify(StateEvent::MasksChanged { masked });
+    }
+
+    /// Restarts the random number generator, effects started afterwards repeat
+    /// exactly what they did after the last reseed with the same seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Moves the LEDs to the positions generated from `layout`, everything else is kept.
+    pub fn set_layout(&mut self, layout: LayoutConfig) -> Result<(), String> {
+        let positions = geometry::generate(&layout, self.leds.len())?;
+        for (led, pos) in self.leds.iter_mut().zip(positions) {
+            led.actual_position = pos;
+        }
+        self.layout = layout;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brightness_only_changes_the_output() {
+        let mut s = AppState::new(10, LayoutConfig::default(), 1).unwrap();
+        s.leds[0].color = Color32::from_rgb(200, 100, 0);
+        s.set_brightness(128);
+        assert_eq!(s.leds[0].color, Color32::from_rgb(200, 100, 0));
+        assert_eq!(s.output_color(&s.leds[0]), Color32::from_rgb(100, 50, 0));
+    }
 }
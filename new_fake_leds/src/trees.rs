@@ -0,0 +1,288 @@
+use crate::{
+    audio::{Audio, AudioConfig, Track},
+    geometry::{Layout, LayoutConfig},
+    groups,
+    midi::{self, MidiConfig},
+    mqtt::MqttConfig,
+    osc::OscConfig,
+    output::{DriverConfig, OutputConfig, Outputs, SegmentConfig},
+    power::PowerConfig,
+    state::AppState,
+};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use utoipa::ToSchema;
+
+/// Settings of one tree. The top level of the config file is the first
+/// tree, `trees` lists the others. Every tree has music, MIDI, MQTT and OSC
+/// of its own, a tree without `audio` has silent audio effects.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct TreeConfig {
+    /// name of the tree in the `/trees/{id}/...` routes
+    pub id: String,
+    pub num_leds: usize,
+    /// defaults to a random cone generated from the seed
+    pub layout: Option<LayoutConfig>,
+    /// current limit of the power supply
+    pub power: PowerConfig,
+    /// gamma, white balance and color order of the strip
+    pub output: OutputConfig,
+    /// several strips instead of the one of `output`, each with its own settings
+    pub outputs: Vec<DriverConfig>,
+    /// which LEDs of the tree are on which of the `outputs`
+    pub segments: Vec<SegmentConfig>,
+    /// where the named groups of LEDs are kept, they are forgotten on exit if not set
+    pub groups_file: Option<PathBuf>,
+    /// music for the audio effects
+    pub audio: AudioConfig,
+    /// notes and controllers that drive the effects
+    pub midi: MidiConfig,
+    /// publishes the tree to Home Assistant if set, each tree needs a
+    /// `client_id` and `base_topic` of its own
+    pub mqtt: Option<MqttConfig>,
+    /// listens for OSC messages if set, each tree on an address of its own
+    pub osc: Option<OscConfig>,
+}
+
+impl Default for TreeConfig {
+    fn default() -> Self {
+        Self {
+            id: "main".to_string(),
+            num_leds: 50,
+            layout: None,
+            power: PowerConfig::default(),
+            output: OutputConfig::default(),
+            outputs: Vec::new(),
+            segments: Vec::new(),
+            groups_file: None,
+            audio: AudioConfig::default(),
+            midi: MidiConfig::default(),
+            mqtt: None,
+            osc: None,
+        }
+    }
+}
+
+impl TreeConfig {
+    /// The strips, `output` as the only one named `main` if there are no `outputs`.
+    pub fn outputs(&self) -> Result<Outputs, String> {
+        let drivers = if self.outputs.is_empty() {
+            vec![DriverConfig {
+                name: "main".to_string(),
+                leds: None,
+                output: self.output.clone(),
+            }]
+        } else {
+            self.outputs.clone()
+        };
+        Outputs::new(drivers, self.segments.clone()).map_err(|e| format!("invalid outputs: {e}"))
+    }
+
+    /// A new tree with its LEDs, outputs, saved groups, music and MIDI file.
+    pub fn create(&self, seed: u64) -> Result<AppState, String> {
+        let layout = self.layout.clone().unwrap_or(LayoutConfig {
+            layout: Layout::RandomCone,
+            seed,
+        });
+        let mut state = AppState::new(self.num_leds, layout, seed)?;
+        state.power_config = self.power.clone();
+        state.outputs = self.outputs()?;
+        if let Some(path) = &self.groups_file {
+            state.groups = groups::load(path)?;
+            state.groups_file = Some(path.clone());
+        }
+        if let Some(path) = &self.audio.wav {
+            state.audio = Audio::Track(Arc::new(Track::from_wav(path)?));
+        }
+        if let Some(path) = &self.midi.file {
+            let song = midi::Song::from_file(path)?;
+            state.midi = Some(midi::Player::new(song, self.midi.mappings.clone()));
+        }
+        Ok(state)
+    }
+}
+
+/// Checks that no two trees listen on the same address or show up in Home
+/// Assistant as the same light.
+pub fn check_connections<'a>(
+    trees: impl IntoIterator<Item = &'a TreeConfig>,
+) -> Result<(), String> {
+    let mut used = HashMap::new();
+    for tree in trees {
+        let connections = [
+            tree.osc.as_ref().map(|o| format!("OSC address {}", o.bind)),
+            tree.audio
+                .pcm
+                .as_ref()
+                .map(|p| format!("PCM address {}", p.bind)),
+            tree.mqtt
+                .as_ref()
+                .map(|m| format!("MQTT client id {:?}", m.client_id)),
+            tree.mqtt
+                .as_ref()
+                .map(|m| format!("MQTT base topic {:?}", m.base_topic)),
+        ];
+        for connection in connections.into_iter().flatten() {
+            if let Some(other) = used.insert(connection.clone(), &tree.id) {
+                return Err(format!(
+                    "the trees {other:?} and {:?} both use the {connection}",
+                    tree.id
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What `GET /trees` reports about a tree.
+#[derive(Serialize, ToSchema)]
+pub struct TreeInfo {
+    id: String,
+    num_leds: usize,
+    /// the running effect, `none` if there is none
+    effect: &'static str,
+}
+
+/// All trees the server manages, each with a state of its own, in the order
+/// of the config file. The first one is also served without `/trees/{id}`.
+#[derive(Clone)]
+pub struct Trees {
+    trees: Vec<(String, Arc<Mutex<AppState>>)>,
+}
+
+impl Trees {
+    /// Checks that the ids are unique and can be used in a path.
+    pub fn new(trees: Vec<(String, Arc<Mutex<AppState>>)>) -> Result<Self, String> {
+        if trees.is_empty() {
+            return Err("there has to be at least one tree".to_string());
+        }
+        for (i, (id, _)) in trees.iter().enumerate() {
+            // anything else could be taken for a path parameter or wildcard by the router
+            let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+            if id.is_empty() || !id.chars().all(allowed) {
+                return Err(format!(
+                    "{id:?} can't be the id of a tree, it may only have letters, digits, _ and -"
+                ));
+            }
+            if trees[..i].iter().any(|(other, _)| other == id) {
+                return Err(format!("there are two trees with the id {id:?}"));
+            }
+        }
+        Ok(Self { trees })
+    }
+
+    pub fn first(&self) -> &Arc<Mutex<AppState>> {
+        &self.trees[0].1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<Mutex<AppState>>)> {
+        self.trees.iter().map(|(id, s)| (id.as_str(), s))
+    }
+
+    pub fn len(&self) -> usize {
+        self.trees.len()
+    }
+
+    pub fn info(&self) -> Vec<TreeInfo> {
+        self.iter()
+            .map(|(id, state)| {
+                let s = state.lock();
+                TreeInfo {
+                    id: id.to_string(),
+                    num_leds: s.leds.len(),
+                    effect: s.effect.name(),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(num_leds: usize) -> Arc<Mutex<AppState>> {
+        let config = TreeConfig {
+            num_leds,
+            ..TreeConfig::default()
+        };
+        Arc::new(Mutex::new(config.create(1).unwrap()))
+    }
+
+    #[test]
+    fn ids_are_checked() {
+        let trees =
+            Trees::new(vec![("office".into(), tree(3)), ("lobby".into(), tree(5))]).unwrap();
+        assert_eq!(trees.len(), 2);
+        let info = trees.info();
+        assert_eq!(info[1].id, "lobby");
+        assert_eq!(info[1].num_leds, 5);
+
+        assert!(Trees::new(vec![]).is_err());
+        for id in ["", "a/b", ":x", "*x", "a b", "{x}", "ä"] {
+            assert!(Trees::new(vec![(id.into(), tree(1))]).is_err(), "{id}");
+        }
+        assert!(Trees::new(vec![("tree-2_B".into(), tree(1))]).is_ok());
+        assert!(Trees::new(vec![("a".into(), tree(1)), ("a".into(), tree(1))]).is_err());
+    }
+
+    #[test]
+    fn trees_have_their_own_settings() {
+        let config = TreeConfig {
+            id: "lobby".to_string(),
+            num_leds: 8,
+            segments: vec![SegmentConfig {
+                output: "main".to_string(),
+                start: 0,
+                end: 4,
+                offset: 0,
+                reversed: true,
+            }],
+            ..TreeConfig::default()
+        };
+        let mut state = config.create(3).unwrap();
+        assert_eq!(state.leds.len(), 8);
+        state.begin_frame();
+        assert_eq!(state.outputs.outputs[0].frame.len(), 4 * 3);
+
+        let bad = TreeConfig {
+            outputs: vec![],
+            segments: vec![SegmentConfig {
+                output: "left".to_string(),
+                start: 0,
+                end: 4,
+                offset: 0,
+                reversed: false,
+            }],
+            ..TreeConfig::default()
+        };
+        assert!(bad.create(3).is_err());
+    }
+
+    #[test]
+    fn trees_need_connections_of_their_own() {
+        let osc = |port: u16| TreeConfig {
+            osc: Some(OscConfig {
+                bind: ([0, 0, 0, 0], port).into(),
+                ..OscConfig::default()
+            }),
+            ..TreeConfig::default()
+        };
+        assert!(check_connections(&[osc(9000), osc(9001)]).is_ok());
+        assert!(check_connections(&[osc(9000), osc(9000)]).is_err());
+
+        let mqtt = |client_id: &str, base_topic: &str| TreeConfig {
+            mqtt: Some(MqttConfig {
+                client_id: client_id.to_string(),
+                base_topic: base_topic.to_string(),
+                ..MqttConfig::default()
+            }),
+            ..TreeConfig::default()
+        };
+        assert!(check_connections(&[mqtt("a", "a"), mqtt("b", "b"), osc(9000)]).is_ok());
+        assert!(check_connections(&[mqtt("a", "a"), mqtt("b", "a")]).is_err());
+        assert!(check_connections(&[mqtt("a", "a"), mqtt("a", "b")]).is_err());
+    }
+}
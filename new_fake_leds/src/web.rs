@@ -1,24 +1,300 @@
-use crate::state::{AppState, Effect, Vec3};
+// the handlers end in an explicit `return (StatusCode::OK, ...)`
+#![allow(clippy::needless_return)]
+
+use crate::{
+    assets::Assets,
+    auth::{self, ApiSecurity, AuthConfig},
+    calibration,
+    camera::{self, CameraParams},
+    clock::MAX_SPEED,
+    error::{ApiError, ApiJson, ApiQuery, ErrorBody},
+    geometry::{Layout, LayoutConfig},
+    groups::{self, Group, Selector},
+    output::{OutputInfo, Rgbw, SegmentConfig},
+    power::{PowerConfig, PowerEstimate},
+    state::{AppState, Effect, Led, StateEvent, Vec3, MAX_LEDS},
+    trees::{TreeInfo, Trees},
+    ws,
+};
 use axum::{
-    extract::State,
-    http::{header, Response, StatusCode},
+    body::Body,
+    extract::{Path, State},
+    http::{header, HeaderMap, Response, StatusCode},
+    middleware,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
 use egui::Color32;
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{fs, sync::Arc, thread, time::Duration};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::sync::watch;
 use tracing::debug;
+use utoipa::{OpenApi, ToSchema};
+
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "web interface",
+    responses(
+        (status = 200, description = "the calibration UI", content_type = "text/html"),
+    )
+)]
+async fn index_page(State(assets): State<Assets>, headers: HeaderMap) -> Response<Body> {
+    assets.response("templates/index.html", &headers)
+}
+
+#[utoipa::path(
+    get,
+    path = "/viewer",
+    tag = "web interface",
+    responses(
+        (status = 200, description = "the 3D viewer", content_type = "text/html"),
+    )
+)]
+async fn viewer_page(State(assets): State<Assets>, headers: HeaderMap) -> Response<Body> {
+    assets.response("templates/viewer.html", &headers)
+}
 
-fn file_response(path: &str, mime: &str) -> Response<axum::body::Body> {
-    let contents = fs::read_to_string(path).unwrap_or_else(|_| String::new());
-    ([(header::CONTENT_TYPE, mime)], contents).into_response()
+#[utoipa::path(
+    get,
+    path = "/docs",
+    tag = "web interface",
+    responses(
+        (status = 200, description = "interactive documentation of this API", content_type = "text/html"),
+    )
+)]
+async fn docs_page(State(assets): State<Assets>, headers: HeaderMap) -> Response<Body> {
+    assets.response("templates/docs.html", &headers)
 }
 
-pub async fn serve(state: Arc<Mutex<AppState>>) {
-    let app = Router::new()
+#[utoipa::path(
+    get,
+    path = "/static/{path}",
+    tag = "web interface",
+    params(
+        ("path" = String, Path, description = "file below `static/`"),
+    ),
+    responses(
+        (status = 200, description = "scripts and styles of the web interface"),
+        (status = 304, description = "the file has the ETag given in If-None-Match"),
+        (status = 404, description = "there is no such file", body = ErrorBody),
+    )
+)]
+async fn static_file(
+    State(assets): State<Assets>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    assets.response(&format!("static/{path}"), &headers)
+}
+
+/// Parses an LED index and checks that the LED exists.
+fn led_index(s: &AppState, key: &str) -> Result<usize, ApiError> {
+    let idx: usize = key
+        .parse()
+        .map_err(|_| ApiError::bad_request(format!("invalid LED index {key:?}")))?;
+    check_led(s, idx)?;
+    Ok(idx)
+}
+
+pub fn check_led(s: &AppState, idx: usize) -> Result<(), ApiError> {
+    if idx >= s.leds.len() {
+        return Err(ApiError::not_found(format!(
+            "there is no LED {idx}, there are {} LEDs",
+            s.leds.len()
+        )));
+    }
+    Ok(())
+}
+
+/// LED patterns can only be set while no effect overwrites them.
+pub fn check_no_effect(s: &AppState) -> Result<(), ApiError> {
+    if s.effect != Effect::None {
+        return Err(ApiError::conflict(
+            "an effect is running and would overwrite the LEDs, stop it first",
+        ));
+    }
+    Ok(())
+}
+
+/// How long to wait for the driver to show a frame before giving up.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits until the frame `frame` has been shown by the driver.
+pub async fn wait_for_frame(mut shown: watch::Receiver<u64>, frame: u64) -> Result<(), ApiError> {
+    match tokio::time::timeout(FRAME_TIMEOUT, shown.wait_for(|&id| id >= frame)).await {
+        Ok(Ok(_)) => Ok(()),
+        _ => Err(ApiError::unavailable(
+            "the LEDs were not updated in time, is the simulator window minimized?",
+        )),
+    }
+}
+
+/// `{"num": n}`, used for LED counts (at most 100000) and LED indices.
+#[derive(Deserialize, Serialize, ToSchema)]
+struct NumRequest {
+    num: usize,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ColorRequest {
+    r: u8,
+    g: u8,
+    b: u8,
+    /// white LED of RGBW strips, off if not given
+    #[serde(default)]
+    w: u8,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+struct SeedRequest {
+    seed: u64,
+}
+
+/// All fields are optional, times are in seconds.
+#[derive(Deserialize, ToSchema)]
+struct ClockRequest {
+    paused: Option<bool>,
+    /// from 0 to 1000
+    speed: Option<f32>,
+    /// jump to this time
+    time: Option<f64>,
+    /// move forward by this much
+    advance: Option<f64>,
+}
+
+/// From 0 (off) to 255 (full).
+#[derive(Deserialize, Serialize, ToSchema)]
+struct BrightnessRequest {
+    brightness: u8,
+}
+
+/// `{"effect": "blink"}`, with the name of the effect as in the `/effects/...` routes.
+#[derive(Deserialize, ToSchema)]
+struct EffectRequest {
+    effect: String,
+}
+
+/// Times are in seconds.
+#[derive(Serialize, ToSchema)]
+struct ClockState {
+    time: f64,
+    paused: bool,
+    speed: f32,
+}
+
+/// The API of the web server, served as `/openapi.json` and shown by `/docs`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "LED simulator",
+        description = "The API of the server on the Pi, plus the routes of the simulator. \
+            Every tree has the routes under `/trees/{id}`, the first one also without the prefix."
+    ),
+    paths(
+        configure_leds,
+        set_num_leds,
+        get_num_leds,
+        mask_led,
+        unmask_led,
+        unmask_all,
+        set_led_positions,
+        get_led_positions,
+        set_basecolor,
+        start_blink,
+        start_allon,
+        start_sweeping_plane,
+        start_sweeping_plane_x,
+        start_sweeping_plane_y,
+        start_sweeping_plane_z,
+        start_concentric_color,
+        start_beat_pulse,
+        start_bass_plane,
+        stop_effects,
+        sim_camera,
+        get_actual_positions,
+        get_layout,
+        set_layout,
+        get_seed,
+        set_seed,
+        get_clock,
+        set_clock,
+        get_brightness,
+        set_brightness,
+        get_power,
+        set_power,
+        get_outputs,
+        get_groups,
+        set_group,
+        delete_group,
+        set_group_color,
+        clear_group_color,
+        start_group_effect,
+        get_trees,
+        calibration_report,
+        ws::leds,
+        ws::control,
+        index_page,
+        viewer_page,
+        docs_page,
+        static_file,
+        openapi_json,
+    ),
+    components(schemas(
+        NumRequest,
+        ColorRequest,
+        SeedRequest,
+        ClockRequest,
+        ClockState,
+        BrightnessRequest,
+        PowerConfig,
+        PowerEstimate,
+        OutputInfo,
+        SegmentConfig,
+        Group,
+        Selector,
+        EffectRequest,
+        TreeInfo,
+        ErrorBody,
+        LayoutConfig,
+        Layout,
+        calibration::Report,
+        calibration::Similarity,
+        calibration::LedError,
+        calibration::HistogramBin,
+        Vec3,
+    )),
+    modifiers(&ApiSecurity)
+)]
+struct ApiDoc;
+
+/// All routes of the web server, without binding to a port. The API of each
+/// tree is under `/trees/{id}`, the first tree's also at the top.
+pub fn router(trees: &Trees, assets: Assets) -> Router {
+    let mut app = api(trees.first().clone()).merge(
+        Router::new()
+            .route("/trees", get(get_trees))
+            .with_state(trees.clone()),
+    );
+    for (id, state) in trees.iter() {
+        app = app.nest(&format!("/trees/{id}"), api(state.clone()));
+    }
+    app.route("/openapi.json", get(openapi_json)).merge(
+        Router::new()
+            .route("/", get(index_page))
+            .route("/viewer", get(viewer_page))
+            .route("/docs", get(docs_page))
+            .route("/static/*path", get(static_file))
+            .with_state(assets),
+    )
+}
+
+/// The API routes of one tree.
+fn api(state: Arc<Mutex<AppState>>) -> Router {
+    Router::new()
         // API routes
         .route("/configure_leds", post(configure_leds))
         .route("/set_num_leds", post(set_num_leds))
@@ -36,51 +312,37 @@ pub async fn serve(state: Arc<Mutex<AppState>>) {
         .route("/effects/planey", post(start_sweeping_plane_y))
         .route("/effects/planez", post(start_sweeping_plane_z))
         .route("/effects/concentriccolor", post(start_concentric_color))
+        .route("/effects/beatpulse", post(start_beat_pulse))
+        .route("/effects/bassplane", post(start_bass_plane))
         .route("/effects/stop", post(stop_effects))
-        // HTML
+        // simulator
+        .route("/sim/camera", get(sim_camera))
+        .route("/sim/actual_positions", get(get_actual_positions))
+        .route("/sim/layout", get(get_layout).post(set_layout))
+        .route("/sim/seed", get(get_seed).post(set_seed))
+        .route("/clock", get(get_clock).post(set_clock))
+        .route("/brightness", get(get_brightness).post(set_brightness))
+        .route("/power", get(get_power).post(set_power))
+        .route("/outputs", get(get_outputs))
+        .route("/groups", get(get_groups))
+        .route("/groups/:name", post(set_group).delete(delete_group))
         .route(
-            "/",
-            get(|| async { file_response("../templates/index.html", "text/html") }),
+            "/groups/:name/color",
+            post(set_group_color).delete(clear_group_color),
         )
-        // CSS
-        .route(
-            "/static/style.css",
-            get(|| async { file_response("../static/style.css", "text/css") }),
-        )
-        // JS
-        .route(
-            "/static/script/main.js",
-            get(|| async { file_response("../static/script/main.js", "application/javascript") }),
-        )
-        .route(
-            "/static/script/ui.js",
-            get(|| async { file_response("../static/script/ui.js", "application/javascript") }),
-        )
-        .route(
-            "/static/script/merge_directions.js",
-            get(|| async {
-                file_response(
-                    "../static/script/merge_directions.js",
-                    "application/javascript",
-                )
-            }),
-        )
-        .route(
-            "/static/script/capture_unidirectional.js",
-            get(|| async {
-                file_response(
-                    "../static/script/capture_unidirectional.js",
-                    "application/javascript",
-                )
-            }),
-        )
-        .route(
-            "/static/script/effects.js",
-            get(|| async {
-                file_response("../static/script/effects.js", "application/javascript")
-            }),
-        )
-        .with_state(state);
+        .route("/groups/:name/effect", post(start_group_effect))
+        .route("/calibration/report", get(calibration_report))
+        // live updates
+        .route("/ws/leds", get(ws::leds))
+        .route("/ws/control", get(ws::control))
+        .with_state(state)
+}
+
+pub async fn serve(trees: Trees, assets: Assets, auth: AuthConfig) {
+    let app = router(&trees, assets).layer(middleware::from_fn_with_state(
+        Arc::new(auth),
+        auth::require_role,
+    ));
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080")
         .await
@@ -91,193 +353,1704 @@ pub async fn serve(state: Arc<Mutex<AppState>>) {
     axum::serve(listener, app).await.unwrap();
 }
 
+/// Turns on exactly the LEDs given as `{"<index>": true}`, for calibration.
+#[utoipa::path(
+    post,
+    path = "/configure_leds",
+    tag = "leds",
+    request_body(content = BTreeMap<String, bool>, example = json!({"5": true})),
+    responses(
+        (status = 200, description = "the LEDs are lit, and shown by the simulator", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+        (status = 404, description = "there is no such LED", body = ErrorBody),
+        (status = 409, description = "an effect is running", body = ErrorBody),
+        (status = 503, description = "the simulator did not show the LEDs in time", body = ErrorBody),
+    )
+)]
 async fn configure_leds(
     State(state): State<Arc<Mutex<AppState>>>,
-    Json(body): Json<Value>,
-) -> impl IntoResponse {
+    ApiJson(body): ApiJson<BTreeMap<String, bool>>,
+) -> Result<impl IntoResponse, ApiError> {
     debug!("configure_leds {body:?}");
-    let mut s = state.lock();
-    for led in s.leds.iter_mut() {
-        led.color = Color32::from_rgb(0, 0, 0);
-    }
-    for (k, v) in body.as_object().unwrap() {
-        let idx: usize = k.parse().unwrap();
-        let val = v.as_bool().unwrap();
-        if val {
-            s.leds[idx].color = s.base_color;
-        } // if val is false, turn the LED off, but that has already happened
-    }
-    drop(s); // otherwise the gui can't refresh before this method returns
-
-    // wait until screen refreshed
-    let s = state.lock();
-    let opt_context = s.egui_context.clone();
-    drop(s); // free mutex again
-    if let Some(ctx) = opt_context {
-        let frame_num = ctx.cumulative_frame_nr();
-        while ctx.cumulative_frame_nr() <= frame_num + 2 {
-            thread::sleep(Duration::from_millis(10));
+    // the lock must be released before waiting, otherwise the GUI can't draw the frame
+    let (frame, shown) = {
+        let mut s = state.lock();
+        check_no_effect(&s)?;
+        let mut on = Vec::new();
+        for (k, v) in &body {
+            let idx = led_index(&s, k)?;
+            if *v {
+                on.push(idx);
+            } // if val is false, turn the LED off, but that happens anyway
         }
-    } else {
-        thread::sleep(Duration::from_millis(3000));
-    }
-
-    return (StatusCode::OK, "success");
+        (s.set_pattern(&on), s.frame_shown.subscribe())
+    };
+    wait_for_frame(shown, frame).await?;
+    return Ok((StatusCode::OK, "success"));
 }
 
+#[utoipa::path(
+    post,
+    path = "/set_num_leds",
+    tag = "leds",
+    request_body = NumRequest,
+    responses(
+        (status = 200, description = "the LEDs were replaced", body = String),
+        (status = 400, description = "the body is malformed, or there are too many LEDs", body = ErrorBody),
+        (status = 409, description = "the layout can't place this many LEDs", body = ErrorBody),
+    )
+)]
 async fn set_num_leds(
     State(state): State<Arc<Mutex<AppState>>>,
-    Json(body): Json<Value>,
-) -> impl IntoResponse {
-    debug!("set_num_leds {body:?}");
-    let n = body["num"].as_u64().unwrap() as usize;
-    *state.lock() = AppState::new(n);
-    return (StatusCode::OK, "success");
+    ApiJson(body): ApiJson<NumRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("set_num_leds {}", body.num);
+    if body.num > MAX_LEDS {
+        return Err(ApiError::bad_request(format!(
+            "there can be at most {MAX_LEDS} LEDs"
+        )));
+    }
+    state
+        .lock()
+        .set_num_leds(body.num)
+        .map_err(ApiError::conflict)?;
+    return Ok((StatusCode::OK, "success"));
 }
 
-async fn get_num_leds(State(state): State<Arc<Mutex<AppState>>>) -> Json<Value> {
+#[utoipa::path(
+    get,
+    path = "/get_num_leds",
+    tag = "leds",
+    responses(
+        (status = 200, description = "number of LEDs", body = NumRequest),
+    )
+)]
+async fn get_num_leds(State(state): State<Arc<Mutex<AppState>>>) -> Json<NumRequest> {
     debug!("get_num_leds");
-    Json(serde_json::json!({ "num": state.lock().leds.len() }))
+    Json(NumRequest {
+        num: state.lock().leds.len(),
+    })
 }
 
+#[utoipa::path(
+    post,
+    path = "/set_led_positions",
+    tag = "leds",
+    request_body(content = BTreeMap<String, Vec<f32>>, description = "`[x, y, z]` by LED index, LEDs not given keep their position", example = json!({"0": [0.1, -0.2, 0.5]})),
+    responses(
+        (status = 200, description = "the positions are saved", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+        (status = 404, description = "there is no such LED", body = ErrorBody),
+    )
+)]
 async fn set_led_positions(
     State(state): State<Arc<Mutex<AppState>>>,
-    Json(body): Json<Value>,
-) -> impl IntoResponse {
+    ApiJson(body): ApiJson<BTreeMap<String, [f32; 3]>>,
+) -> Result<impl IntoResponse, ApiError> {
     debug!("set_led_positions {body:?}");
     let mut s = state.lock();
-    for (k, v) in body.as_object().unwrap() {
-        let idx: usize = k.parse().unwrap();
-        let arr = v.as_array().unwrap();
-        s.leds[idx].determined_position = Vec3 {
-            x: arr[0].as_f64().unwrap() as f32,
-            y: arr[1].as_f64().unwrap() as f32,
-            z: arr[2].as_f64().unwrap() as f32,
-        };
+    let mut positions = Vec::with_capacity(body.len());
+    for (k, [x, y, z]) in body {
+        positions.push((led_index(&s, &k)?, Vec3 { x, y, z }));
+    }
+    for (idx, position) in positions {
+        s.leds[idx].determined_position = position;
     }
-    return (StatusCode::OK, "LED positions successfully saved");
+    s.notify(StateEvent::PositionsChanged);
+    return Ok((StatusCode::OK, "LED positions successfully saved"));
 }
 
+#[utoipa::path(
+    get,
+    path = "/get_saved_led_positions",
+    tag = "leds",
+    responses(
+        (status = 200, description = "`[x, y, z]` by LED index, as determined by the calibration", body = BTreeMap<String, Vec<f32>>),
+    )
+)]
 async fn get_led_positions(State(state): State<Arc<Mutex<AppState>>>) -> Json<Value> {
     debug!("get_led_positions");
-    let s = state.lock();
+    positions_json(&state.lock(), |led| led.determined_position)
+}
+
+/// Where the simulated LEDs really are, in the format of `/get_saved_led_positions`.
+#[utoipa::path(
+    get,
+    path = "/sim/actual_positions",
+    tag = "simulator",
+    responses(
+        (status = 200, description = "`[x, y, z]` by LED index, where the simulated LEDs really are", body = BTreeMap<String, Vec<f32>>),
+    )
+)]
+async fn get_actual_positions(State(state): State<Arc<Mutex<AppState>>>) -> Json<Value> {
+    debug!("get_actual_positions");
+    positions_json(&state.lock(), |led| led.actual_position)
+}
+
+fn positions_json(s: &AppState, position: impl Fn(&Led) -> Vec3) -> Json<Value> {
     let mut obj = serde_json::Map::new();
     for (i, led) in s.leds.iter().enumerate() {
-        obj.insert(
-            i.to_string(),
-            serde_json::json!([
-                led.determined_position.x,
-                led.determined_position.y,
-                led.determined_position.z
-            ]),
-        );
+        let p = position(led);
+        obj.insert(i.to_string(), serde_json::json!([p.x, p.y, p.z]));
     }
     Json(Value::Object(obj))
 }
 
+#[utoipa::path(
+    post,
+    path = "/mask_led",
+    tag = "leds",
+    request_body = NumRequest,
+    responses(
+        (status = 200, description = "the LED stays dark", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+        (status = 404, description = "there is no such LED", body = ErrorBody),
+    )
+)]
 async fn mask_led(
     State(state): State<Arc<Mutex<AppState>>>,
-    Json(body): Json<Value>,
-) -> impl IntoResponse {
-    debug!("mask_led {body:?}");
+    ApiJson(body): ApiJson<NumRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("mask_led {}", body.num);
     let mut s = state.lock();
-    let n = body["num"].as_u64().unwrap() as usize;
-    s.leds[n].enabled = false;
-    return (StatusCode::OK, "success");
+    check_led(&s, body.num)?;
+    s.set_enabled(body.num, false);
+    return Ok((StatusCode::OK, "success"));
 }
 
+#[utoipa::path(
+    post,
+    path = "/unmask_led",
+    tag = "leds",
+    request_body = NumRequest,
+    responses(
+        (status = 200, description = "the LED is used again", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+        (status = 404, description = "there is no such LED", body = ErrorBody),
+    )
+)]
 async fn unmask_led(
     State(state): State<Arc<Mutex<AppState>>>,
-    Json(body): Json<Value>,
-) -> impl IntoResponse {
-    debug!("unmask {body:?}");
+    ApiJson(body): ApiJson<NumRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("unmask {}", body.num);
     let mut s = state.lock();
-    let n = body["num"].as_u64().unwrap() as usize;
-    s.leds[n].enabled = true;
-    return (StatusCode::OK, "success");
+    check_led(&s, body.num)?;
+    s.set_enabled(body.num, true);
+    return Ok((StatusCode::OK, "success"));
 }
 
+#[utoipa::path(
+    post,
+    path = "/unmask_all",
+    tag = "leds",
+    responses(
+        (status = 200, description = "all LEDs are used again", body = String),
+    )
+)]
 async fn unmask_all(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("unmask all");
-    let mut s = state.lock();
-    s.leds.iter_mut().for_each(|l| l.enabled = true);
+    state.lock().enable_all();
     return (StatusCode::OK, "success");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/basecolor",
+    tag = "effects",
+    request_body = ColorRequest,
+    responses(
+        (status = 200, description = "the color is set", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+    )
+)]
 async fn set_basecolor(
     State(state): State<Arc<Mutex<AppState>>>,
-    Json(body): Json<Value>,
+    ApiJson(body): ApiJson<ColorRequest>,
 ) -> impl IntoResponse {
-    debug!("set_basecolor {body:?}");
-    let dict = body.as_object().unwrap();
-    let r = dict["r"].as_u64().unwrap() as u8;
-    let g = dict["g"].as_u64().unwrap() as u8;
-    let b = dict["b"].as_u64().unwrap() as u8;
+    debug!("set_basecolor {} {} {} {}", body.r, body.g, body.b, body.w);
+    let color = Color32::from_rgb(body.r, body.g, body.b);
     let mut s = state.lock();
-    s.base_color = Color32::from_rgb(r, g, b);
+    s.base_white = body.w;
+    s.set_base_color(color);
     return (StatusCode::OK, "color updated");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/blink",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_blink(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_blink");
-    let mut s = state.lock();
-    s.effect = Effect::Blink;
-    s.effect_start = std::time::Instant::now();
+    state.lock().start_effect(Effect::Blink);
     return (StatusCode::OK, "blink effect started");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/allon",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_allon(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_allon");
-    let mut s = state.lock();
-    s.effect = Effect::AllOn;
+    state.lock().start_effect(Effect::AllOn);
     return (StatusCode::OK, "all on effect started");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/sweepingplane",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_sweeping_plane(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_sweeping_plane");
-    let mut s = state.lock();
-    s.sweeping_plane_z = Vec::new();
-    s.effect = Effect::SweepingPlane;
+    state.lock().start_effect(Effect::SweepingPlane);
     return (StatusCode::OK, "sweeping plane effect started");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/planex",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_sweeping_plane_x(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_sweeping_planex");
-    let mut s = state.lock();
-    s.sweeping_plane_z = Vec::new();
-    s.effect = Effect::SweepingPlaneX;
+    state.lock().start_effect(Effect::SweepingPlaneX);
     return (StatusCode::OK, "sweeping plane effect started");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/planey",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_sweeping_plane_y(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_sweeping_planey");
-    let mut s = state.lock();
-    s.sweeping_plane_z = Vec::new();
-    s.effect = Effect::SweepingPlaneY;
+    state.lock().start_effect(Effect::SweepingPlaneY);
     return (StatusCode::OK, "sweeping plane effect started");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/planez",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_sweeping_plane_z(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_sweeping_planez");
-    let mut s = state.lock();
-    s.sweeping_plane_z = Vec::new();
-    s.effect = Effect::SweepingPlaneZ;
+    state.lock().start_effect(Effect::SweepingPlaneZ);
     return (StatusCode::OK, "sweeping plane effect started");
 }
 
+#[utoipa::path(
+    post,
+    path = "/effects/concentriccolor",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
 async fn start_concentric_color(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("start_concentric_color");
-    let mut s = state.lock();
-    s.effect = Effect::ConcentricColor;
+    state.lock().start_effect(Effect::ConcentricColor);
     return (StatusCode::OK, "sweeping plane effect started");
 }
 
+/// Pulses on the beats of the music from the `audio` section of the config file.
+#[utoipa::path(
+    post,
+    path = "/effects/beatpulse",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
+async fn start_beat_pulse(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
+    debug!("start_beat_pulse");
+    state.lock().start_effect(Effect::BeatPulse);
+    return (StatusCode::OK, "beat pulse effect started");
+}
+
+/// A plane that rises with the bass of the music from the `audio` section of the config file.
+#[utoipa::path(
+    post,
+    path = "/effects/bassplane",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect started", body = String),
+    )
+)]
+async fn start_bass_plane(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
+    debug!("start_bass_plane");
+    state.lock().start_effect(Effect::BassPlane);
+    return (StatusCode::OK, "bass plane effect started");
+}
+
+#[utoipa::path(
+    post,
+    path = "/effects/stop",
+    tag = "effects",
+    responses(
+        (status = 200, description = "the effect is stopped and all LEDs are off", body = String),
+    )
+)]
 async fn stop_effects(State(state): State<Arc<Mutex<AppState>>>) -> impl IntoResponse {
     debug!("stop_effects");
+    state.lock().stop_effects();
+    return (StatusCode::OK, "effects stopped");
+}
+
+#[utoipa::path(
+    get,
+    path = "/sim/camera",
+    tag = "simulator",
+    params(CameraParams),
+    responses(
+        (status = 200, description = "what a camera would see of the simulated tree", content_type = "image/png"),
+        (status = 400, description = "the query is malformed, or a parameter is out of range", body = ErrorBody),
+    )
+)]
+async fn sim_camera(
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiQuery(params): ApiQuery<CameraParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("sim_camera");
+    params.validate().map_err(ApiError::bad_request)?;
+    let scene = camera::Scene::new(&mut state.lock(), &params);
+    // rendering takes a while for big images, so neither the state nor the
+    // runtime are blocked meanwhile
+    let png =
+        tokio::task::spawn_blocking(move || camera::encode_png(&camera::render(&scene, &params)))
+            .await
+            .map_err(|e| ApiError::internal(format!("rendering failed: {e}")))?;
+    return Ok((
+        [
+            (header::CONTENT_TYPE, "image/png"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        png,
+    ));
+}
+
+#[utoipa::path(
+    get,
+    path = "/sim/layout",
+    tag = "simulator",
+    responses(
+        (status = 200, description = "the layout of the simulated LEDs", body = LayoutConfig),
+    )
+)]
+async fn get_layout(State(state): State<Arc<Mutex<AppState>>>) -> Json<LayoutConfig> {
+    debug!("get_layout");
+    Json(state.lock().layout.clone())
+}
+
+#[utoipa::path(
+    post,
+    path = "/sim/layout",
+    tag = "simulator",
+    request_body = LayoutConfig,
+    responses(
+        (status = 200, description = "the LEDs are moved", body = String),
+        (status = 400, description = "the body is malformed, the layout is too big or a point file, or it can't be generated", body = ErrorBody),
+    )
+)]
+async fn set_layout(
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiJson(layout): ApiJson<LayoutConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("set_layout");
+    if let Layout::PointFile { .. } = layout.layout {
+        // the server would open any path it is given
+        return Err(ApiError::bad_request(
+            "point files can only be given in the config file",
+        ));
+    }
+    state
+        .lock()
+        .set_layout(layout)
+        .map_err(ApiError::bad_request)?;
+    return Ok((StatusCode::OK, "layout updated"));
+}
+
+#[utoipa::path(
+    get,
+    path = "/sim/seed",
+    tag = "simulator",
+    responses(
+        (status = 200, description = "the seed of all randomness", body = SeedRequest),
+    )
+)]
+async fn get_seed(State(state): State<Arc<Mutex<AppState>>>) -> Json<SeedRequest> {
+    debug!("get_seed");
+    Json(SeedRequest {
+        seed: state.lock().seed,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/sim/seed",
+    tag = "simulator",
+    request_body = SeedRequest,
+    responses(
+        (status = 200, description = "the random number generator is restarted", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+    )
+)]
+async fn set_seed(
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiJson(body): ApiJson<SeedRequest>,
+) -> impl IntoResponse {
+    debug!("set_seed {}", body.seed);
+    state.lock().reseed(body.seed);
+    return (StatusCode::OK, "seed updated");
+}
+
+#[utoipa::path(
+    get,
+    path = "/clock",
+    tag = "clock",
+    responses(
+        (status = 200, description = "the clock the effects run on", body = ClockState),
+    )
+)]
+async fn get_clock(State(state): State<Arc<Mutex<AppState>>>) -> Json<ClockState> {
+    debug!("get_clock");
+    let s = state.lock();
+    Json(ClockState {
+        time: s.clock.now().as_secs_f64(),
+        paused: s.clock.is_paused(),
+        speed: s.clock.speed(),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/clock",
+    tag = "clock",
+    request_body = ClockRequest,
+    responses(
+        (status = 200, description = "the clock is updated", body = String),
+        (status = 400, description = "the body is malformed, or the time is out of range", body = ErrorBody),
+    )
+)]
+async fn set_clock(
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiJson(body): ApiJson<ClockRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let seconds = |name: &str, value: Option<f64>| {
+        value
+            .map(|v| {
+                Duration::try_from_secs_f64(v).map_err(|_| {
+                    ApiError::bad_request(format!("{name} must be a positive number of seconds"))
+                })
+            })
+            .transpose()
+    };
+    let time = seconds("time", body.time)?;
+    let advance = seconds("advance", body.advance)?;
+    if body
+        .speed
+        .is_some_and(|speed| !(0.0..=MAX_SPEED).contains(&speed))
+    {
+        return Err(ApiError::bad_request(format!(
+            "speed must be between 0 and {MAX_SPEED}"
+        )));
+    }
+
+    debug!("set_clock");
     let mut s = state.lock();
-    s.effect = Effect::None;
-    for led in s.leds.iter_mut() {
-        led.color = Color32::from_rgb(0, 0, 0);
+    let target = time.unwrap_or_else(|| s.clock.now());
+    if advance.is_some_and(|advance| target.checked_add(advance).is_none()) {
+        return Err(ApiError::bad_request(
+            "the clock can't be advanced that far",
+        ));
+    }
+    match body.paused {
+        Some(true) => s.clock.pause(),
+        Some(false) => s.clock.resume(),
+        None => {}
+    }
+    if let Some(speed) = body.speed {
+        s.clock.set_speed(speed);
+    }
+    if let Some(time) = time {
+        s.clock.seek(time);
+    }
+    if let Some(advance) = advance {
+        s.clock.advance(advance);
+    }
+    return Ok((StatusCode::OK, "clock updated"));
+}
+
+#[utoipa::path(
+    get,
+    path = "/brightness",
+    tag = "output",
+    responses(
+        (status = 200, description = "the master brightness", body = BrightnessRequest),
+    )
+)]
+async fn get_brightness(State(state): State<Arc<Mutex<AppState>>>) -> Json<BrightnessRequest> {
+    debug!("get_brightness");
+    Json(BrightnessRequest {
+        brightness: state.lock().brightness,
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/brightness",
+    tag = "output",
+    request_body = BrightnessRequest,
+    responses(
+        (status = 200, description = "the brightness is set", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+    )
+)]
+async fn set_brightness(
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiJson(body): ApiJson<BrightnessRequest>,
+) -> impl IntoResponse {
+    debug!("set_brightness {}", body.brightness);
+    state.lock().set_brightness(body.brightness);
+    return (StatusCode::OK, "brightness updated");
+}
+
+/// The current the last frame draws, estimated from its colors.
+#[utoipa::path(
+    get,
+    path = "/power",
+    tag = "output",
+    responses(
+        (status = 200, description = "the estimated current", body = PowerEstimate),
+    )
+)]
+async fn get_power(State(state): State<Arc<Mutex<AppState>>>) -> Json<PowerEstimate> {
+    debug!("get_power");
+    Json(state.lock().power)
+}
+
+/// Changes the budget, it applies from the next frame on.
+#[utoipa::path(
+    post,
+    path = "/power",
+    tag = "output",
+    request_body = PowerConfig,
+    responses(
+        (status = 200, description = "the budget is set", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+    )
+)]
+async fn set_power(
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiJson(body): ApiJson<PowerConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    let values = [body.ma_per_channel, body.idle_ma_per_led];
+    if values
+        .into_iter()
+        .chain(body.supply_amps)
+        .any(|v| !v.is_finite() || v < 0.0)
+    {
+        return Err(ApiError::bad_request("currents must be positive numbers"));
+    }
+    debug!("set_power");
+    state.lock().power_config = body;
+    return Ok((StatusCode::OK, "power budget updated"));
+}
+
+/// The strips the LEDs are split over, with what they were sent for the last frame.
+#[utoipa::path(
+    get,
+    path = "/outputs",
+    tag = "output",
+    responses(
+        (status = 200, description = "the strips", body = Vec<OutputInfo>),
+    )
+)]
+async fn get_outputs(State(state): State<Arc<Mutex<AppState>>>) -> Json<Vec<OutputInfo>> {
+    debug!("get_outputs");
+    let s = state.lock();
+    Json(s.outputs.info(s.leds.len()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/groups",
+    tag = "groups",
+    responses(
+        (status = 200, description = "all named groups of LEDs", body = Vec<Group>),
+    )
+)]
+async fn get_groups(State(state): State<Arc<Mutex<AppState>>>) -> Json<Vec<Group>> {
+    debug!("get_groups");
+    Json(state.lock().groups.clone())
+}
+
+/// Writes the groups of the tree to its groups file, if it has one, without
+/// holding the state lock while the file is written. Saves take turns and each
+/// writes the groups as they are when its turn comes, so the last one wins.
+async fn save_groups(state: Arc<Mutex<AppState>>) -> Result<(), ApiError> {
+    static SAVING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    tokio::task::spawn_blocking(move || {
+        let _turn = SAVING.lock().unwrap_or_else(|e| e.into_inner());
+        let (path, groups) = {
+            let s = state.lock();
+            match &s.groups_file {
+                Some(path) => (path.clone(), s.groups.clone()),
+                None => return Ok(()),
+            }
+        };
+        groups::save(&path, &groups)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("saving the groups failed: {e}")))?
+    .map_err(ApiError::internal)
+}
+
+fn check_group(s: &AppState, name: &str) -> Result<(), ApiError> {
+    if !s.groups.iter().any(|g| g.name == name) {
+        return Err(ApiError::not_found(format!("there is no group {name:?}")));
+    }
+    Ok(())
+}
+
+/// Defines the group `name`, or changes which LEDs it has.
+#[utoipa::path(
+    post,
+    path = "/groups/{name}",
+    tag = "groups",
+    params(
+        ("name" = String, Path, description = "name of the group"),
+    ),
+    request_body = Selector,
+    responses(
+        (status = 200, description = "the group is saved", body = String),
+        (status = 400, description = "the body is malformed or selects nothing", body = ErrorBody),
+        (status = 500, description = "the groups file could not be written, the change only lasts until the simulator stops", body = ErrorBody),
+    )
+)]
+async fn set_group(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(name): Path<String>,
+    ApiJson(selector): ApiJson<Selector>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("set_group {name}");
+    selector.validate().map_err(ApiError::bad_request)?;
+    groups::upsert(&mut state.lock().groups, Group { name, selector });
+    save_groups(state).await?;
+    return Ok((StatusCode::OK, "group saved"));
+}
+
+#[utoipa::path(
+    delete,
+    path = "/groups/{name}",
+    tag = "groups",
+    params(
+        ("name" = String, Path, description = "name of the group"),
+    ),
+    responses(
+        (status = 200, description = "the group is deleted", body = String),
+        (status = 404, description = "there is no such group", body = ErrorBody),
+        (status = 500, description = "the groups file could not be written, the change only lasts until the simulator stops", body = ErrorBody),
+    )
+)]
+async fn delete_group(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("delete_group {name}");
+    {
+        let mut s = state.lock();
+        check_group(&s, &name)?;
+        s.groups.retain(|g| g.name != name);
+        groups::set_color(&mut s, &name, None);
+        if s.effect_group.as_ref() == Some(&name) {
+            s.effect_group = None;
+        }
+    }
+    save_groups(state).await?;
+    return Ok((StatusCode::OK, "group deleted"));
+}
+
+/// Shows the group in one color instead of the effect, until the color is deleted.
+#[utoipa::path(
+    post,
+    path = "/groups/{name}/color",
+    tag = "groups",
+    params(
+        ("name" = String, Path, description = "name of the group"),
+    ),
+    request_body = ColorRequest,
+    responses(
+        (status = 200, description = "the group shows the color", body = String),
+        (status = 400, description = "the body is malformed", body = ErrorBody),
+        (status = 404, description = "there is no such group", body = ErrorBody),
+    )
+)]
+async fn set_group_color(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(name): Path<String>,
+    ApiJson(body): ApiJson<ColorRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!(
+        "set_group_color {name} {} {} {} {}",
+        body.r, body.g, body.b, body.w
+    );
+    let mut s = state.lock();
+    check_group(&s, &name)?;
+    let color = Rgbw {
+        color: Color32::from_rgb(body.r, body.g, body.b),
+        white: body.w,
+    };
+    groups::set_color(&mut s, &name, Some(color));
+    return Ok((StatusCode::OK, "group color updated"));
+}
+
+#[utoipa::path(
+    delete,
+    path = "/groups/{name}/color",
+    tag = "groups",
+    params(
+        ("name" = String, Path, description = "name of the group"),
+    ),
+    responses(
+        (status = 200, description = "the group shows the effect again", body = String),
+        (status = 404, description = "there is no such group", body = ErrorBody),
+    )
+)]
+async fn clear_group_color(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("clear_group_color {name}");
+    let mut s = state.lock();
+    check_group(&s, &name)?;
+    groups::set_color(&mut s, &name, None);
+    return Ok((StatusCode::OK, "group color removed"));
+}
+
+/// Starts an effect that only lights the group, the rest of the tree stays dark.
+#[utoipa::path(
+    post,
+    path = "/groups/{name}/effect",
+    tag = "groups",
+    params(
+        ("name" = String, Path, description = "name of the group"),
+    ),
+    request_body = EffectRequest,
+    responses(
+        (status = 200, description = "the effect is started", body = String),
+        (status = 400, description = "there is no such effect", body = ErrorBody),
+        (status = 404, description = "there is no such group", body = ErrorBody),
+    )
+)]
+async fn start_group_effect(
+    State(state): State<Arc<Mutex<AppState>>>,
+    Path(name): Path<String>,
+    ApiJson(body): ApiJson<EffectRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    debug!("start_group_effect {name} {}", body.effect);
+    let effect = Effect::from_name(&body.effect)
+        .filter(|e| *e != Effect::None)
+        .ok_or_else(|| ApiError::bad_request(format!("unknown effect {:?}", body.effect)))?;
+    let mut s = state.lock();
+    check_group(&s, &name)?;
+    s.start_effect(effect);
+    s.effect_group = Some(name);
+    return Ok((StatusCode::OK, "effect started"));
+}
+
+#[utoipa::path(
+    get,
+    path = "/trees",
+    tag = "trees",
+    responses(
+        (status = 200, description = "all trees, each with its API under `/trees/{id}`", body = Vec<TreeInfo>),
+    )
+)]
+async fn get_trees(State(trees): State<Trees>) -> Json<Vec<TreeInfo>> {
+    debug!("get_trees");
+    Json(trees.info())
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    tag = "web interface",
+    responses((status = 200, description = "this document"))
+)]
+async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[utoipa::path(
+    get,
+    path = "/calibration/report",
+    tag = "calibration",
+    responses(
+        (status = 200, description = "how far the determined positions are from the actual ones", body = calibration::Report),
+        (status = 409, description = "too few LEDs have been calibrated", body = ErrorBody),
+    )
+)]
+async fn calibration_report(
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> Result<Json<calibration::Report>, ApiError> {
+    debug!("calibration_report");
+    let report = calibration::report(&state.lock()).map_err(ApiError::conflict)?;
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effects::update_effects;
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+    use tower::ServiceExt;
+
+    const NUM_LEDS: usize = 10;
+
+    fn test_state() -> Arc<Mutex<AppState>> {
+        let layout = LayoutConfig {
+            layout: Layout::Helix { turns: 2.0 },
+            seed: 1,
+        };
+        Arc::new(Mutex::new(AppState::new(NUM_LEDS, layout, 1).unwrap()))
+    }
+
+    fn single_tree(state: Arc<Mutex<AppState>>) -> Trees {
+        Trees::new(vec![("main".to_string(), state)]).unwrap()
+    }
+
+    async fn send(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        body: Option<&str>,
+    ) -> (StatusCode, Vec<u8>) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if body.is_some() {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+        }
+        let request = request
+            .body(Body::from(body.unwrap_or_default().to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        (status, bytes.to_vec())
+    }
+
+    /// Stands in for the GUI, which shows every frame right away.
+    fn spawn_driver(state: Arc<Mutex<AppState>>) {
+        tokio::spawn(async move {
+            loop {
+                {
+                    let mut s = state.lock();
+                    let frame = s.begin_frame();
+                    s.end_frame(frame);
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+    }
+
+    /// Serves `app` on a random local port, for the WebSocket routes.
+    async fn spawn_server(app: Router) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn get_json(app: &Router, uri: &str) -> Value {
+        let (status, body) = send(app, "GET", uri, None).await;
+        assert_eq!(status, StatusCode::OK, "GET {uri}");
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn post(app: &Router, uri: &str, body: &str) -> StatusCode {
+        send(app, "POST", uri, Some(body)).await.0
+    }
+
+    #[tokio::test]
+    async fn configure_leds() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+        spawn_driver(state.clone());
+        state.lock().base_color = Color32::from_rgb(1, 2, 3);
+
+        assert_eq!(
+            post(&app, "/configure_leds", r#"{"2": true, "3": false}"#).await,
+            StatusCode::OK
+        );
+        let s = state.lock();
+        assert_eq!(s.leds[2].color, Color32::from_rgb(1, 2, 3));
+        assert_eq!(s.leds[3].color, Color32::BLACK);
+    }
+
+    #[tokio::test]
+    async fn configure_leds_rejects_bad_input() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        assert_eq!(
+            post(&app, "/configure_leds", r#"{"99": true}"#).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            post(&app, "/configure_leds", r#"{"two": true}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/configure_leds", r#"{"2": "yes"}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/configure_leds", r#"[2]"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/configure_leds", r#"{"2": tru"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        let (status, _) = send(&app, "POST", "/configure_leds", None).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn configure_leds_conflicts_with_running_effect() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+        state.lock().effect = Effect::AllOn;
+
+        let (status, body) = send(&app, "POST", "/configure_leds", Some(r#"{"2": true}"#)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "conflict");
+    }
+
+    #[tokio::test]
+    async fn errors_are_json() {
+        let app = router(&single_tree(test_state()), Assets::default());
+
+        for (method, uri, body, status, kind) in [
+            (
+                "POST",
+                "/mask_led",
+                Some(r#"{"num": 10}"#),
+                404,
+                "not_found",
+            ),
+            (
+                "POST",
+                "/mask_led",
+                Some(r#"{"num": true}"#),
+                400,
+                "bad_request",
+            ),
+            (
+                "POST",
+                "/configure_leds",
+                Some(r#"{"2": tru"#),
+                400,
+                "bad_request",
+            ),
+            (
+                "POST",
+                "/effects/basecolor",
+                None,
+                415,
+                "unsupported_media_type",
+            ),
+            ("GET", "/sim/camera?w=-1", None, 400, "bad_request"),
+            ("GET", "/calibration/report", None, 409, "conflict"),
+        ] {
+            let (actual, body) = send(&app, method, uri, body).await;
+            assert_eq!(actual.as_u16(), status, "{uri}");
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"], kind, "{uri}");
+            assert!(!body["message"].as_str().unwrap().is_empty(), "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn num_leds() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        assert_eq!(get_json(&app, "/get_num_leds").await["num"], NUM_LEDS);
+        assert_eq!(
+            post(&app, "/set_num_leds", r#"{"num": 25}"#).await,
+            StatusCode::OK
+        );
+        assert_eq!(get_json(&app, "/get_num_leds").await["num"], 25);
+
+        assert_eq!(
+            post(&app, "/set_num_leds", r#"{"num": -1}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        for num in ["100001", "18446744073709551615"] {
+            let body = format!(r#"{{"num": {num}}}"#);
+            assert_eq!(
+                post(&app, "/set_num_leds", &body).await,
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert_eq!(get_json(&app, "/get_num_leds").await["num"], 25);
+        assert_eq!(
+            post(&app, "/set_num_leds", r#"{"count": 3}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(get_json(&app, "/get_num_leds").await["num"], 25);
+    }
+
+    #[tokio::test]
+    async fn set_num_leds_conflicts_with_a_short_point_file() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+        let path = std::env::temp_dir().join(format!("led_sim_points_{}.txt", std::process::id()));
+        std::fs::write(&path, "0 0 0\n1 1 1\n").unwrap();
+        let layout = serde_json::json!({"kind": "point_file", "path": path});
+
+        // point files can't be read over HTTP, only from the config file
+        assert_eq!(
+            post(&app, "/sim/layout", &layout.to_string()).await,
+            StatusCode::BAD_REQUEST
+        );
+        let layout: LayoutConfig = serde_json::from_value(layout).unwrap();
+        assert!(state.lock().set_layout(layout.clone()).is_err());
+        state.lock().set_num_leds(2).unwrap();
+        state.lock().set_layout(layout).unwrap();
+        assert_eq!(
+            post(&app, "/set_num_leds", r#"{"num": 3}"#).await,
+            StatusCode::CONFLICT
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn masking() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        assert_eq!(
+            post(&app, "/mask_led", r#"{"num": 4}"#).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            post(&app, "/mask_led", r#"{"num": 5}"#).await,
+            StatusCode::OK
+        );
+        assert!(!state.lock().leds[4].enabled);
+        assert_eq!(
+            post(&app, "/unmask_led", r#"{"num": 4}"#).await,
+            StatusCode::OK
+        );
+        assert!(state.lock().leds[4].enabled);
+        assert!(!state.lock().leds[5].enabled);
+        assert_eq!(
+            send(&app, "POST", "/unmask_all", None).await.0,
+            StatusCode::OK
+        );
+        assert!(state.lock().leds.iter().all(|l| l.enabled));
+
+        for uri in ["/mask_led", "/unmask_led"] {
+            assert_eq!(
+                post(&app, uri, r#"{"num": 10}"#).await,
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(
+                post(&app, uri, r#"{"num": "x"}"#).await,
+                StatusCode::BAD_REQUEST
+            );
+            assert_eq!(post(&app, uri, r#"{}"#).await, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn led_positions() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        let body = r#"{"0": [0.5, -0.5, 1.0], "9": [1, 2, 3]}"#;
+        assert_eq!(post(&app, "/set_led_positions", body).await, StatusCode::OK);
+        let positions = get_json(&app, "/get_saved_led_positions").await;
+        assert_eq!(positions["0"], serde_json::json!([0.5, -0.5, 1.0]));
+        assert_eq!(positions["9"], serde_json::json!([1.0, 2.0, 3.0]));
+        assert_eq!(positions.as_object().unwrap().len(), NUM_LEDS);
+
+        assert_eq!(
+            post(&app, "/set_led_positions", r#"{"10": [0, 0, 0]}"#).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            post(&app, "/set_led_positions", r#"{"1": [0, 0]}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/set_led_positions", r#"{"1": [0, "a", 0]}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        // a rejected request changes nothing
+        assert_eq!(
+            post(&app, "/set_led_positions", r#"{"0": [7, 7, 7], "1": 3}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(state.lock().leds[0].determined_position.x, 0.5);
+
+        // saving determined positions doesn't move the simulated LEDs
+        let actual = get_json(&app, "/sim/actual_positions").await;
+        let p = state.lock().leds[9].actual_position;
+        assert_eq!(actual["9"], serde_json::json!([p.x, p.y, p.z]));
+        assert_eq!(actual.as_object().unwrap().len(), NUM_LEDS);
+    }
+
+    #[tokio::test]
+    async fn basecolor() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        assert_eq!(
+            post(&app, "/effects/basecolor", r#"{"r": 10, "g": 20, "b": 30}"#).await,
+            StatusCode::OK
+        );
+        assert_eq!(state.lock().base_color, Color32::from_rgb(10, 20, 30));
+        assert_eq!(state.lock().base_white, 0);
+
+        let body = r#"{"r": 10, "g": 20, "b": 30, "w": 40}"#;
+        assert_eq!(post(&app, "/effects/basecolor", body).await, StatusCode::OK);
+        assert_eq!(state.lock().base_white, 40);
+
+        assert_eq!(
+            post(&app, "/effects/basecolor", r#"{"r": 256, "g": 0, "b": 0}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/effects/basecolor", r#"{"r": 1, "g": 2}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/effects/basecolor", r#""red""#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(state.lock().base_color, Color32::from_rgb(10, 20, 30));
+    }
+
+    #[tokio::test]
+    async fn effects() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        for (uri, effect) in [
+            ("/effects/blink", Effect::Blink),
+            ("/effects/allon", Effect::AllOn),
+            ("/effects/sweepingplane", Effect::SweepingPlane),
+            ("/effects/planex", Effect::SweepingPlaneX),
+            ("/effects/planey", Effect::SweepingPlaneY),
+            ("/effects/planez", Effect::SweepingPlaneZ),
+            ("/effects/concentriccolor", Effect::ConcentricColor),
+            ("/effects/beatpulse", Effect::BeatPulse),
+            ("/effects/bassplane", Effect::BassPlane),
+        ] {
+            assert_eq!(
+                send(&app, "POST", uri, None).await.0,
+                StatusCode::OK,
+                "{uri}"
+            );
+            assert!(state.lock().effect == effect, "{uri}");
+        }
+
+        state.lock().leds[0].color = Color32::WHITE;
+        assert_eq!(
+            send(&app, "POST", "/effects/stop", None).await.0,
+            StatusCode::OK
+        );
+        let s = state.lock();
+        assert!(s.effect == Effect::None);
+        assert!(s.leds.iter().all(|l| l.color == Color32::BLACK));
+    }
+
+    #[tokio::test]
+    async fn sim_camera() {
+        let app = router(&single_tree(test_state()), Assets::default());
+
+        let (status, body) = send(&app, "GET", "/sim/camera?w=64&h=48&yaw=90&noise=0", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body[1..4], b"PNG");
+
+        let (status, _) = send(&app, "GET", "/sim/camera?w=big", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        for query in [
+            "blur=1e6",
+            "blur=inf",
+            "fov=0",
+            "fov=180",
+            "distance=0",
+            "exposure=-1",
+        ] {
+            let (status, _) = send(&app, "GET", &format!("/sim/camera?{query}"), None).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn layout_and_seed() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        assert_eq!(get_json(&app, "/sim/layout").await["kind"], "helix");
+        assert_eq!(
+            post(&app, "/sim/layout", r#"{"kind": "sphere", "seed": 3}"#).await,
+            StatusCode::OK
+        );
+        assert_eq!(get_json(&app, "/sim/layout").await["kind"], "sphere");
+        assert_eq!(
+            post(&app, "/sim/layout", r#"{"kind": "pyramid"}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(
+                &app,
+                "/sim/layout",
+                r#"{"kind": "spiral_cone", "turns": 1e12}"#
+            )
+            .await,
+            StatusCode::BAD_REQUEST
+        );
+
+        assert_eq!(
+            post(&app, "/sim/seed", r#"{"seed": 5}"#).await,
+            StatusCode::OK
+        );
+        assert_eq!(get_json(&app, "/sim/seed").await["seed"], 5);
+        assert_eq!(
+            post(&app, "/sim/seed", r#"{"seed": -5}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn clock() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        let body = r#"{"paused": true, "time": 12.5, "speed": 2}"#;
+        assert_eq!(post(&app, "/clock", body).await, StatusCode::OK);
+        assert_eq!(
+            post(&app, "/clock", r#"{"advance": 0.5}"#).await,
+            StatusCode::OK
+        );
+        let clock = get_json(&app, "/clock").await;
+        assert_eq!(clock["time"], 13.0);
+        assert_eq!(clock["paused"], true);
+        assert_eq!(clock["speed"], 2.0);
+
+        assert_eq!(
+            post(&app, "/clock", r#"{"time": -1}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/clock", r#"{"paused": 1}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/clock", r#"{"speed": "fast"}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            post(&app, "/clock", r#"{"time": 1e19, "advance": 1e19}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(get_json(&app, "/clock").await["time"], 13.0);
+    }
+
+    #[tokio::test]
+    async fn brightness_and_power() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        let body = r#"{"brightness": 128}"#;
+        assert_eq!(post(&app, "/brightness", body).await, StatusCode::OK);
+        assert_eq!(get_json(&app, "/brightness").await["brightness"], 128);
+        assert_eq!(
+            post(&app, "/brightness", r#"{"brightness": 300}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        let body = r#"{"ma_per_channel": 20, "idle_ma_per_led": 0, "supply_amps": 0.01}"#;
+        assert_eq!(post(&app, "/power", body).await, StatusCode::OK);
+        {
+            let mut s = state.lock();
+            s.base_color = Color32::WHITE;
+            s.start_effect(Effect::AllOn);
+            update_effects(&mut s, Duration::ZERO);
+        }
+        let power = get_json(&app, "/power").await;
+        assert_eq!(power["budget_ma"], 10.0);
+        assert!(power["requested_ma"].as_f64().unwrap() > 10.0);
+        assert!(power["drawn_ma"].as_f64().unwrap() <= 10.0 + 1e-3);
+        assert!(power["scale"].as_f64().unwrap() < 1.0);
+
+        assert_eq!(
+            post(&app, "/power", r#"{"supply_amps": -1}"#).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn outputs() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+        {
+            let mut s = state.lock();
+            s.leds[0].color = Color32::from_rgb(1, 2, 3);
+            s.begin_frame();
+        }
+        let outputs = get_json(&app, "/outputs").await;
+        assert_eq!(outputs[0]["name"], "main");
+        assert_eq!(outputs[0]["leds"], NUM_LEDS);
+        let frame = outputs[0]["frame"].as_array().unwrap();
+        assert_eq!(frame.len(), NUM_LEDS * 3);
+        // green first
+        assert_eq!(frame[..3], [2, 1, 3]);
+    }
+
+    #[tokio::test]
+    async fn groups() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+        let path =
+            std::env::temp_dir().join(format!("led_sim_web_groups_{}.json", std::process::id()));
+        state.lock().groups_file = Some(path.clone());
+
+        let body = r#"{"kind": "range", "start": 8, "end": 10}"#;
+        assert_eq!(post(&app, "/groups/star", body).await, StatusCode::OK);
+        let body = r#"{"kind": "leds", "leds": [0, 1, 2]}"#;
+        assert_eq!(post(&app, "/groups/base", body).await, StatusCode::OK);
+        let groups = get_json(&app, "/groups").await;
+        assert_eq!(groups[0]["name"], "star");
+        assert_eq!(groups[0]["kind"], "range");
+        assert_eq!(groups::load(&path).unwrap().len(), 2);
+
+        // saves running at the same time leave the file as the state ends up
+        let saves = (0..8).map(|i| {
+            let app = app.clone();
+            async move {
+                let body = format!(r#"{{"kind": "leds", "leds": [{i}]}}"#);
+                post(&app, &format!("/groups/g{i}"), &body).await
+            }
+        });
+        for status in futures_util::future::join_all(saves).await {
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(groups::load(&path).unwrap().len(), 10);
+        for i in 0..8 {
+            let (status, _) = send(&app, "DELETE", &format!("/groups/g{i}"), None).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        assert_eq!(groups::load(&path).unwrap().len(), 2);
+
+        let body = r#"{"r": 255, "g": 200, "b": 0}"#;
+        assert_eq!(post(&app, "/groups/star/color", body).await, StatusCode::OK);
+        let body = r#"{"effect": "allon"}"#;
+        assert_eq!(
+            post(&app, "/groups/base/effect", body).await,
+            StatusCode::OK
+        );
+        {
+            let mut s = state.lock();
+            s.base_color = Color32::WHITE;
+            update_effects(&mut s, Duration::ZERO);
+            assert_eq!(s.output_color(&s.leds[0]), Color32::WHITE);
+            assert_eq!(s.output_color(&s.leds[5]), Color32::BLACK);
+            assert_eq!(s.output_color(&s.leds[9]), Color32::from_rgb(255, 200, 0));
+        }
+
+        let (status, _) = send(&app, "DELETE", "/groups/star/color", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&app, "DELETE", "/groups/base", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(state.lock().effect_group.is_none());
+        assert_eq!(groups::load(&path).unwrap().len(), 1);
+
+        let body = r#"{"r": 1, "g": 2, "b": 3}"#;
+        assert_eq!(
+            post(&app, "/groups/base/color", body).await,
+            StatusCode::NOT_FOUND
+        );
+        let body = r#"{"effect": "fireworks"}"#;
+        assert_eq!(
+            post(&app, "/groups/star/effect", body).await,
+            StatusCode::BAD_REQUEST
+        );
+        let body = r#"{"kind": "range", "start": 3, "end": 1}"#;
+        assert_eq!(
+            post(&app, "/groups/empty", body).await,
+            StatusCode::BAD_REQUEST
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn trees() {
+        let office = test_state();
+        let lobby = Arc::new(Mutex::new(
+            AppState::new(3, LayoutConfig::default(), 2).unwrap(),
+        ));
+        let trees = Trees::new(vec![
+            ("office".to_string(), office.clone()),
+            ("lobby".to_string(), lobby.clone()),
+        ])
+        .unwrap();
+        let app = router(&trees, Assets::default());
+
+        let list = get_json(&app, "/trees").await;
+        assert_eq!(list[0]["id"], "office");
+        assert_eq!(list[1]["num_leds"], 3);
+
+        assert_eq!(
+            post(&app, "/trees/lobby/effects/blink", "").await,
+            StatusCode::OK
+        );
+        assert!(lobby.lock().effect == Effect::Blink);
+        assert!(office.lock().effect == Effect::None);
+        assert_eq!(get_json(&app, "/trees/lobby/get_num_leds").await["num"], 3);
+
+        // the first tree is also served without the prefix
+        assert_eq!(get_json(&app, "/get_num_leds").await["num"], NUM_LEDS);
+        assert_eq!(post(&app, "/effects/allon", "").await, StatusCode::OK);
+        assert!(office.lock().effect == Effect::AllOn);
+        assert!(lobby.lock().effect == Effect::Blink);
+
+        let (status, _) = send(&app, "GET", "/trees/attic/get_num_leds", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn calibration_report() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+
+        let (status, _) = send(&app, "GET", "/calibration/report", None).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        for led in state.lock().leds.iter_mut() {
+            led.determined_position = led.actual_position * 3.0;
+        }
+        let report = get_json(&app, "/calibration/report").await;
+        assert!(report["rms_error"].as_f64().unwrap() < 1e-4);
+        assert_eq!(report["num_leds"], NUM_LEDS);
+        assert_eq!(report["undetermined"], 0);
+    }
+
+    #[tokio::test]
+    async fn static_files() {
+        let app = router(&single_tree(test_state()), Assets::default());
+
+        for uri in [
+            "/",
+            "/static/script/main.js",
+            "/static/script/effects.js",
+            "/viewer",
+            "/static/script/viewer.js",
+            "/docs",
+            "/static/script/docs.js",
+        ] {
+            let (status, body) = send(&app, "GET", uri, None).await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+            assert!(!body.is_empty(), "{uri}");
+        }
+
+        for uri in ["/static/script/missing.js", "/static/../Cargo.toml"] {
+            let (status, _) = send(&app, "GET", uri, None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn static_files_have_mime_types_and_etags() {
+        let app = router(&single_tree(test_state()), Assets::default());
+        let get = |uri: &str, etag: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(etag) = etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get("/", None).await.unwrap();
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let response = get("/static/script/ui.js", None).await.unwrap();
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/javascript"));
+
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = get("/static/script/ui.js", Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = get("/static/script/main.js", Some(&etag)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn openapi_describes_the_routes() {
+        let app = router(&single_tree(test_state()), Assets::default());
+        let spec = get_json(&app, "/openapi.json").await;
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+        assert!(spec["components"]["schemas"]["LayoutConfig"].is_object());
+        assert_eq!(
+            spec["components"]["securitySchemes"]["bearer"]["scheme"],
+            "bearer"
+        );
+        let set_num_leds = &spec["paths"]["/set_num_leds"]["post"];
+        assert_eq!(set_num_leds["security"][0]["bearer"], serde_json::json!([]));
+        assert_eq!(
+            set_num_leds["responses"]["403"]["description"],
+            "the token doesn't have the role Admin"
+        );
+        assert!(
+            spec["paths"]["/groups/{name}/color"]["post"]["responses"]["403"]["description"]
+                .as_str()
+                .unwrap()
+                .ends_with("Operator")
+        );
+        assert!(spec["paths"]["/get_num_leds"]["get"]["responses"]["401"].is_object());
+        assert!(spec["paths"]["/viewer"]["get"]["security"].is_null());
+        assert!(spec["paths"]["/viewer"]["get"]["responses"]["401"].is_null());
+
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/configure_leds",
+            "/effects/blink",
+            "/sim/camera",
+            "/ws/leds",
+        ] {
+            assert!(paths.contains_key(path), "{path}");
+        }
+        // everything documented exists, axum answers routes it doesn't know with an empty 404
+        for (path, operations) in paths {
+            let uri = path.replace("{path}", "script/main.js");
+            for method in operations.as_object().unwrap().keys() {
+                let (status, body) = send(&app, &method.to_uppercase(), &uri, None).await;
+                assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{method} {path}");
+                assert!(
+                    status != StatusCode::NOT_FOUND || !body.is_empty(),
+                    "{method} {path}"
+                );
+            }
+        }
+    }
+
+    /// Skips everything but text messages, which are all JSON.
+    async fn next_json<S>(socket: &mut S) -> Value
+    where
+        S: futures_util::Stream<Item = tokio_tungstenite::tungstenite::Result<WsMessage>> + Unpin,
+    {
+        use futures_util::StreamExt;
+        loop {
+            if let WsMessage::Text(text) = socket.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_leds_streams_frames_and_events() {
+        let state = test_state();
+        let app = router(&single_tree(state.clone()), Assets::default());
+        let addr = spawn_server(app.clone()).await;
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/ws/leds?fps=60"))
+                .await
+                .unwrap();
+        for fps in ["nan", "inf"] {
+            let url = format!("ws://{addr}/ws/leds?fps={fps}");
+            match tokio_tungstenite::connect_async(url).await {
+                Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                    assert_eq!(response.status(), StatusCode::BAD_REQUEST)
+                }
+                _ => panic!("fps={fps} was accepted"),
+            }
+        }
+
+        let frame = next_json(&mut socket).await;
+        assert_eq!(frame["type"], "frame");
+        assert_eq!(frame["colors"].as_array().unwrap().len(), NUM_LEDS);
+
+        assert_eq!(post(&app, "/effects/blink", "").await, StatusCode::OK);
+        loop {
+            let message = next_json(&mut socket).await;
+            if message["type"] == "effect_started" {
+                assert_eq!(message["effect"], "blink");
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ws_control_acknowledges_shown_patterns() {
+        use futures_util::SinkExt;
+
+        let state = test_state();
+        spawn_driver(state.clone());
+        let addr = spawn_server(router(&single_tree(state.clone()), Assets::default())).await;
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/ws/control"))
+            .await
+            .unwrap();
+
+        let command = r#"{"type": "set_pattern", "id": 1, "on": [2, 5]}"#;
+        socket.send(WsMessage::text(command)).await.unwrap();
+        let reply = next_json(&mut socket).await;
+        assert_eq!(reply["type"], "shown");
+        assert_eq!(reply["id"], 1);
+        {
+            let s = state.lock();
+            assert!(*s.frame_shown.borrow() >= reply["frame"].as_u64().unwrap());
+            let lit: Vec<usize> = (0..NUM_LEDS)
+                .filter(|&i| s.leds[i].color != Color32::BLACK)
+                .collect();
+            assert_eq!(lit, [2, 5]);
+        }
+
+        let command = r#"{"type": "set_pattern", "id": 2, "on": [99]}"#;
+        socket.send(WsMessage::text(command)).await.unwrap();
+        let reply = next_json(&mut socket).await;
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["id"], 2);
+        assert_eq!(reply["error"], "not_found");
+
+        socket.send(WsMessage::text("set pattern 3")).await.unwrap();
+        let reply = next_json(&mut socket).await;
+        assert_eq!(reply["error"], "bad_request");
     }
-    return (StatusCode::OK, "effects stopped");
 }
@@ -0,0 +1,189 @@
+use crate::{
+    error::{ApiError, ApiQuery},
+    state::AppState,
+    web::{check_led, check_no_effect, wait_for_frame},
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::broadcast::error::RecvError, time::MissedTickBehavior};
+use tracing::debug;
+use utoipa::{IntoParams, ToSchema};
+
+#[derive(Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameFormat {
+    /// `{"type": "frame", "colors": [[r, g, b], ...]}`
+    Json,
+    /// three bytes r, g, b per LED
+    Binary,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[serde(default)]
+#[into_params(parameter_in = Query)]
+pub struct LiveParams {
+    /// frames per second, from 1 to 60
+    pub fps: f32,
+    pub format: FrameFormat,
+}
+
+impl Default for LiveParams {
+    fn default() -> Self {
+        Self {
+            fps: 20.0,
+            format: FrameFormat::Json,
+        }
+    }
+}
+
+/// `/ws/leds`: pushes the LED colors at a fixed rate, and every `StateEvent`
+/// as a JSON text message as it happens.
+#[utoipa::path(
+    get,
+    path = "/ws/leds",
+    tag = "live",
+    params(LiveParams),
+    responses(
+        (status = 101, description = "WebSocket of LED colors and `StateEvent`s"),
+        (status = 400, description = "the query is malformed, or `fps` is not a number", body = crate::error::ErrorBody),
+    )
+)]
+pub async fn leds(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<Mutex<AppState>>>,
+    ApiQuery(params): ApiQuery<LiveParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    if !params.fps.is_finite() {
+        return Err(ApiError::bad_request("fps must be a number"));
+    }
+    Ok(ws.on_upgrade(move |socket| stream_leds(socket, state, params)))
+}
+
+async fn stream_leds(mut socket: WebSocket, state: Arc<Mutex<AppState>>, params: LiveParams) {
+    debug!("ws/leds client connected");
+    let mut events = state.lock().events.subscribe();
+    let period = Duration::from_secs_f32(1.0 / params.fps.clamp(1.0, 60.0));
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        let message = tokio::select! {
+            _ = interval.tick() => frame_message(&state.lock(), params.format),
+            event = events.recv() => match event {
+                Ok(event) => Message::Text(serde_json::to_string(&event).unwrap()),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue, // this socket only talks
+            },
+        };
+        if socket.send(message).await.is_err() {
+            break;
+        }
+    }
+    debug!("ws/leds client disconnected");
+}
+
+fn frame_message(s: &AppState, format: FrameFormat) -> Message {
+    match format {
+        FrameFormat::Binary => Message::Binary(
+            s.output_colors()
+                .iter()
+                .flat_map(|c| [c.r(), c.g(), c.b()])
+                .collect(),
+        ),
+        FrameFormat::Json => {
+            let colors: Vec<[u8; 3]> = s
+                .output_colors()
+                .iter()
+                .map(|c| [c.r(), c.g(), c.b()])
+                .collect();
+            Message::Text(serde_json::json!({ "type": "frame", "colors": colors }).to_string())
+        }
+    }
+}
+
+/// Commands of the `/ws/control` channel. Every command carries an `id` chosen
+/// by the client, which is repeated in the reply.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ControlCommand {
+    /// Lights exactly the LEDs in `on`, like `/configure_leds`.
+    SetPattern { id: u64, on: Vec<usize> },
+}
+
+/// `/ws/control`: a faster `/configure_leds` for calibration.
+///
+/// Commands are handled in order. The reply to a command is only sent once the
+/// driver has shown the first frame with the new pattern, as
+/// `{"type": "shown", "id": <id>, "frame": <frame id>}`, or
+/// `{"type": "error", "id": <id>, "error": <kind>, "message": ...}` if it failed.
+#[utoipa::path(
+    get,
+    path = "/ws/control",
+    tag = "live",
+    responses(
+        (status = 101, description = "WebSocket taking `set_pattern` commands"),
+    )
+)]
+pub async fn control(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<Mutex<AppState>>>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| run_control(socket, state))
+}
+
+async fn run_control(mut socket: WebSocket, state: Arc<Mutex<AppState>>) {
+    debug!("ws/control client connected");
+    while let Some(Ok(message)) = socket.recv().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let reply = match serde_json::from_str(&text) {
+            Ok(command) => execute(&state, command).await,
+            Err(e) => error_reply(None, ApiError::bad_request(e.to_string())),
+        };
+        if socket.send(Message::Text(reply.to_string())).await.is_err() {
+            break;
+        }
+    }
+    debug!("ws/control client disconnected");
+}
+
+async fn execute(state: &Mutex<AppState>, command: ControlCommand) -> Value {
+    match command {
+        ControlCommand::SetPattern { id, on } => {
+            debug!("ws/control set_pattern {id} {on:?}");
+            let (frame, shown) = {
+                let mut s = state.lock();
+                let checked = check_no_effect(&s)
+                    .and_then(|_| on.iter().try_for_each(|&idx| check_led(&s, idx)));
+                if let Err(e) = checked {
+                    return error_reply(Some(id), e);
+                }
+                (s.set_pattern(&on), s.frame_shown.subscribe())
+            };
+            match wait_for_frame(shown, frame).await {
+                Ok(()) => json!({ "type": "shown", "id": id, "frame": frame }),
+                Err(e) => error_reply(Some(id), e),
+            }
+        }
+    }
+}
+
+fn error_reply(id: Option<u64>, error: ApiError) -> Value {
+    json!({ "type": "error", "id": id, "error": error.kind(), "message": error.message })
+}
@@ -1,4 +1,4 @@
-import {configure_leds, allOn, stop} from "./effects.js";
+import {setPattern, allOn, stop} from "./effects.js";
 
 /**
  * Helper to wait for the next camera frame.
@@ -85,13 +85,13 @@ export async function start_capturing(
 
     for (let i = 0; i < num_leds; i++) {
         // 1. Capture "ON" state
-        configure_leds({ [i]: true });
+        await setPattern([i]);
         await waitForNextCameraFrame(video);
         ctx.drawImage(video, 0, 0, width, height);
         const brightMapOn = get_brightness_map(ctx.getImageData(0, 0, width, height));
 
         // 2. Capture "OFF" state
-        configure_leds({ [i]: false });
+        await setPattern([]);
         await waitForNextCameraFrame(video);
         ctx.drawImage(video, 0, 0, width, height);
         const brightMapOff = get_brightness_map(ctx.getImageData(0, 0, width, height));
@@ -0,0 +1,188 @@
+// Renders `openapi.json` as a list of routes that can be tried out right here.
+
+const operations_element = document.getElementById("operations");
+
+let spec = null;
+
+// follows `{"$ref": "#/components/schemas/Name"}`
+function resolve(schema) {
+    while (schema && schema.$ref) {
+        const name = schema.$ref.split("/").pop();
+        schema = spec.components.schemas[name];
+    }
+    return schema ?? {};
+}
+
+// a value that fits `schema`, to fill the request body with
+function example(schema, depth = 0) {
+    schema = resolve(schema);
+    if (schema.example !== undefined) return schema.example;
+    if (depth > 5) return null;
+    if (schema.enum) return schema.enum[0];
+    if (schema.oneOf) return example(schema.oneOf[0], depth + 1);
+    if (schema.allOf) {
+        return Object.assign({}, ...schema.allOf.map((s) => example(s, depth + 1)));
+    }
+    switch (schema.type) {
+        case "object": {
+            const result = {};
+            for (const [name, property] of Object.entries(schema.properties ?? {})) {
+                result[name] = example(property, depth + 1);
+            }
+            if (schema.additionalProperties && Object.keys(result).length === 0) {
+                result["0"] = example(schema.additionalProperties, depth + 1);
+            }
+            return result;
+        }
+        case "array":
+            return [example(schema.items, depth + 1)];
+        case "integer":
+            return 0;
+        case "number":
+            return 0.0;
+        case "boolean":
+            return false;
+        case "string":
+            return "";
+        default:
+            return null;
+    }
+}
+
+// the fields of a schema as a table, so it's clear what can be sent
+function schema_table(schema) {
+    const table = document.createElement("table");
+    const add_rows = (schema) => {
+        schema = resolve(schema);
+        for (const part of [...(schema.oneOf ?? []), ...(schema.allOf ?? [])]) {
+            add_rows(part);
+        }
+        for (const [name, property] of Object.entries(schema.properties ?? {})) {
+            const p = resolve(property);
+            const row = table.insertRow();
+            row.insertCell().textContent = name;
+            row.insertCell().textContent = p.enum ? p.enum.join(" | ") : (p.type ?? "object");
+            row.insertCell().textContent = p.description ?? "";
+        }
+    };
+    add_rows(schema);
+    return table.rows.length > 0 ? table : null;
+}
+
+function element(tag, text, class_name) {
+    const e = document.createElement(tag);
+    if (text !== undefined) e.textContent = text;
+    if (class_name !== undefined) e.className = class_name;
+    return e;
+}
+
+function operation_element(path, method, operation) {
+    const details = document.createElement("details");
+    const summary = document.createElement("summary");
+    summary.append(
+        element("span", method, `method ${method}`),
+        element("span", path, "path"),
+        element("span", operation.summary ?? "", "summary-text"),
+    );
+    details.append(summary);
+
+    const body = element("div", undefined, "operation");
+    details.append(body);
+    if (operation.description) {
+        body.append(element("p", operation.description));
+    }
+
+    const inputs = {};
+    for (const parameter of operation.parameters ?? []) {
+        const label = element("label", `${parameter.name} (${parameter.in})`);
+        if (parameter.description) label.title = parameter.description;
+        const input = document.createElement("input");
+        input.placeholder = parameter.description ?? resolve(parameter.schema).type ?? "";
+        inputs[parameter.name] = { parameter, input };
+        body.append(label, input);
+    }
+
+    let body_input = null;
+    const content = operation.requestBody?.content?.["application/json"];
+    if (content) {
+        const table = schema_table(content.schema);
+        if (table) body.append(table);
+        body_input = document.createElement("textarea");
+        const value = content.example ?? example(content.schema);
+        body_input.value = JSON.stringify(value, null, 2);
+        body.append(element("label", "request body"), body_input);
+    }
+
+    const responses = element("table");
+    for (const [status, response] of Object.entries(operation.responses ?? {})) {
+        const row = responses.insertRow();
+        row.insertCell().textContent = status;
+        row.insertCell().textContent = response.description ?? "";
+    }
+    body.append(element("p", "responses"), responses);
+
+    if (path.startsWith("/ws/")) {
+        body.append(element("p", "This is a WebSocket, connect to it with a WebSocket client."));
+        return details;
+    }
+
+    const button = element("button", "Send");
+    const output = element("div");
+    body.append(button, output);
+    button.addEventListener("click", async () => {
+        let url = path;
+        const query = new URLSearchParams();
+        for (const { parameter, input } of Object.values(inputs)) {
+            if (input.value === "") continue;
+            if (parameter.in === "path") {
+                url = url.replace(`{${parameter.name}}`, encodeURIComponent(input.value));
+            } else {
+                query.set(parameter.name, input.value);
+            }
+        }
+        if ([...query].length > 0) url += `?${query}`;
+
+        const options = { method: method.toUpperCase() };
+        if (body_input) {
+            options.headers = { "Content-Type": "application/json" };
+            options.body = body_input.value;
+        }
+        output.replaceChildren(element("p", "..."));
+        // relative, so it also works if the API is served below some prefix
+        const response = await fetch(`.${url}`, options);
+        const type = response.headers.get("Content-Type") ?? "";
+        const result = [element("p", `${response.status} ${response.statusText}`)];
+        if (type.startsWith("image/")) {
+            const image = document.createElement("img");
+            image.src = URL.createObjectURL(await response.blob());
+            result.push(image);
+        } else if (type.startsWith("application/json")) {
+            result.push(element("pre", JSON.stringify(await response.json(), null, 2)));
+        } else {
+            result.push(element("pre", await response.text()));
+        }
+        output.replaceChildren(...result);
+    });
+    return details;
+}
+
+async function load() {
+    spec = await (await fetch("openapi.json")).json();
+    document.getElementById("title").textContent = spec.info.title;
+    document.getElementById("description").textContent = spec.info.description ?? "";
+
+    // group by the first tag, in the order the tags first appear
+    const groups = new Map();
+    for (const [path, methods] of Object.entries(spec.paths)) {
+        for (const [method, operation] of Object.entries(methods)) {
+            const tag = operation.tags?.[0] ?? "other";
+            if (!groups.has(tag)) groups.set(tag, []);
+            groups.get(tag).push(operation_element(path, method, operation));
+        }
+    }
+    for (const [tag, elements] of groups) {
+        operations_element.append(element("h2", tag), ...elements);
+    }
+}
+
+load();
@@ -9,6 +9,71 @@ export function configure_leds(dict) {
     xhr.send(JSON.stringify(dict));
 }
 
+// the `ws/control` socket, as a promise that resolves to null if the server has none
+let control = null;
+let controlUnavailable = false;
+// replies we are waiting for, by command id
+const pending = new Map();
+let nextCommandId = 1;
+
+function connectControl() {
+    if (controlUnavailable) {
+        return Promise.resolve(null);
+    }
+    if (control === null) {
+        control = new Promise((resolve) => {
+            const url = new URL("ws/control", window.location.href);
+            url.protocol = url.protocol === "https:" ? "wss:" : "ws:";
+            const socket = new WebSocket(url);
+            let opened = false;
+            socket.onopen = () => {
+                opened = true;
+                resolve(socket);
+            };
+            socket.onclose = () => {
+                // never opened: the server doesn't have the route, use configure_leds from now on
+                controlUnavailable = !opened;
+                control = null;
+                for (const { reject } of pending.values()) {
+                    reject(new Error("ws/control was closed"));
+                }
+                pending.clear();
+                resolve(null);
+            };
+            socket.onmessage = (event) => {
+                const reply = JSON.parse(event.data);
+                const waiting = pending.get(reply.id);
+                if (waiting === undefined) return;
+                pending.delete(reply.id);
+                if (reply.type === "shown") {
+                    waiting.resolve(reply.frame);
+                } else {
+                    waiting.reject(new Error(reply.message));
+                }
+            };
+        });
+    }
+    return control;
+}
+
+/**
+ * Lights exactly the LEDs in `on` and resolves once they are actually shown.
+ * Uses the `ws/control` WebSocket if the server has it, `configure_leds` otherwise.
+ * to be used like `await setPattern([5])`
+ */
+export async function setPattern(on) {
+    const socket = await connectControl();
+    if (socket === null) {
+        configure_leds(Object.fromEntries(on.map((i) => [i, true])));
+        return;
+    }
+    const id = nextCommandId++;
+    return new Promise((resolve, reject) => {
+        pending.set(id, { resolve, reject });
+        socket.send(JSON.stringify({ type: "set_pattern", id, on }));
+    });
+}
+
 export function blink() {
     var xhr = new XMLHttpRequest();
     xhr.open("POST", "effects/blink", false);
@@ -117,3 +182,17 @@ export function concentricColor() {
     xhr.setRequestHeader('Content-Type', 'application/json');
     xhr.send();
 }
+
+export function beatPulse() {
+    var xhr = new XMLHttpRequest();
+    xhr.open("POST", "effects/beatpulse", false);
+    xhr.setRequestHeader('Content-Type', 'application/json');
+    xhr.send();
+}
+
+export function bassPlane() {
+    var xhr = new XMLHttpRequest();
+    xhr.open("POST", "effects/bassplane", false);
+    xhr.setRequestHeader('Content-Type', 'application/json');
+    xhr.send();
+}
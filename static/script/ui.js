@@ -1,6 +1,6 @@
 import {start_capturing} from "./capture_unidirectional.js";
 import { merge_and_transmit, centerLEDBetweenNeighbors} from "./merge_directions.js";
-import {blink, allOn, sweepingPlane, stop, setBaseColor, maskLed, unmaskLed, unmaskAll, planeX, planeY, planeZ, concentricColor, configure_leds, setNumLeds} from "./effects.js";
+import {blink, allOn, sweepingPlane, stop, setBaseColor, maskLed, unmaskLed, unmaskAll, planeX, planeY, planeZ, concentricColor, beatPulse, bassPlane, configure_leds, setNumLeds} from "./effects.js";
 
 let current_led_index = 0;
 
@@ -64,12 +64,54 @@ export function visualize_led_positions(
 }
 
 
+// Feed the video element from the simulator's /sim/camera endpoint instead of a real camera.
+// Enabled by opening the page with ?simcam, extra query parameters (blur, noise, ...) are passed on.
+function startSimulatedCamera(video) {
+    const canvas = document.createElement('canvas');
+    canvas.width = 640;
+    canvas.height = 480;
+    const ctx = canvas.getContext('2d');
+    const extra_params = new URLSearchParams(window.location.search);
+    extra_params.delete('simcam');
+
+    async function draw_next_frame() {
+        // the X tab looks along the y axis, the Y tab along the x axis
+        const yaw = isTabYActive() ? 90 : 0;
+        const params = new URLSearchParams(extra_params);
+        params.set('yaw', yaw);
+        params.set('w', canvas.width);
+        params.set('h', canvas.height);
+        try {
+            const response = await fetch(`sim/camera?${params}`);
+            const bitmap = await createImageBitmap(await response.blob());
+            ctx.drawImage(bitmap, 0, 0);
+        } catch (error) {
+            console.error("Error fetching simulated camera frame: ", error);
+        }
+        setTimeout(draw_next_frame, 50);
+    }
+    draw_next_frame();
+
+    video.srcObject = canvas.captureStream(20);
+}
+
 // Function to start the camera
 function startCamera(
     video,
     diff_canvas,
     math_canvas,
 ) {
+    if (new URLSearchParams(window.location.search).has('simcam')) {
+        video.addEventListener('loadedmetadata', () => {
+            diff_canvas.width = video.videoWidth;
+            diff_canvas.height = video.videoHeight;
+            math_canvas.width = video.videoWidth;
+            math_canvas.height = video.videoHeight;
+            console.log(`Set sizes to ${video.videoWidth}×${video.videoHeight}`);
+        });
+        startSimulatedCamera(video);
+        return;
+    }
     // TODO: ideally we let the user pick. there is a navigator.mediaDevices.enumerateDevices(); method for that
     navigator.mediaDevices.getUserMedia({
         video: {
@@ -352,6 +394,14 @@ export function setup_ui(
     effectConcentricColorButton.addEventListener('click', () => {
         concentricColor();
     });
+    const effectBeatPulseButton = document.getElementById('effect-beat-pulse-btn');
+    effectBeatPulseButton.addEventListener('click', () => {
+        beatPulse();
+    });
+    const effectBassPlaneButton = document.getElementById('effect-bass-plane-btn');
+    effectBassPlaneButton.addEventListener('click', () => {
+        bassPlane();
+    });
     const effectStopButton = document.getElementById('effect-stop-btn');
     effectStopButton.addEventListener('click', () => {
         stop();
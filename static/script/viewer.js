@@ -0,0 +1,404 @@
+// 3D view of the tree in the browser, for everyone who isn't sitting at the
+// simulator's window. Shows the LED positions (determined by the calibration,
+// or the actual ones of the simulator) with the live colors from `ws/leds`.
+//
+// plain WebGL, so there is nothing to download besides this file.
+
+const tree_canvas = document.getElementById("tree");
+const label_canvas = document.getElementById("labels");
+const positions_select = document.getElementById("positions-select");
+const labels_checkbox = document.getElementById("labels-checkbox");
+const selection_panel = document.getElementById("selection");
+const status_text = document.getElementById("status");
+
+const gl = tree_canvas.getContext("webgl", { antialias: true });
+const label_context = label_canvas.getContext("2d");
+
+// LEDs that are off are still drawn, dimly, so the shape of the tree is visible
+const OFF_COLOR = [0.15, 0.15, 0.18];
+const POINT_SIZE = 0.08; // in units of the normalized tree
+const DEFAULT_VIEW = { yaw: 0.4, pitch: 0.3, distance: 3.5 };
+
+let positions = [];  // [[x, y, z], ...] normalized to fit into [-1, 1]
+let raw_positions = [];  // as sent by the server
+let colors = new Uint8Array(0);  // r, g, b per LED
+let selected = null;
+let view = { ...DEFAULT_VIEW };
+let mvp = identity();
+
+// --- small matrix helpers, column major like WebGL wants them ---
+
+function identity() {
+    return [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1];
+}
+
+function multiply(a, b) {
+    const out = new Array(16).fill(0);
+    for (let col = 0; col < 4; col++) {
+        for (let row = 0; row < 4; row++) {
+            for (let k = 0; k < 4; k++) {
+                out[col * 4 + row] += a[k * 4 + row] * b[col * 4 + k];
+            }
+        }
+    }
+    return out;
+}
+
+function perspective(fov, aspect, near, far) {
+    const f = 1 / Math.tan(fov / 2);
+    return [
+        f / aspect, 0, 0, 0,
+        0, f, 0, 0,
+        0, 0, (far + near) / (near - far), -1,
+        0, 0, (2 * far * near) / (near - far), 0,
+    ];
+}
+
+// camera at `eye` looking at the origin, z is up
+function look_at(eye) {
+    const normalize = (v) => {
+        const l = Math.hypot(...v);
+        return v.map((x) => x / l);
+    };
+    const cross = (a, b) => [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+    const dot = (a, b) => a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+
+    const forward = normalize(eye.map((x) => -x));
+    const right = normalize(cross(forward, [0, 0, 1]));
+    const up = cross(right, forward);
+    return [
+        right[0], up[0], -forward[0], 0,
+        right[1], up[1], -forward[1], 0,
+        right[2], up[2], -forward[2], 0,
+        -dot(right, eye), -dot(up, eye), dot(forward, eye), 1,
+    ];
+}
+
+// position in CSS pixels of the canvas, or null if behind the camera
+function project(p) {
+    const x = mvp[0] * p[0] + mvp[4] * p[1] + mvp[8] * p[2] + mvp[12];
+    const y = mvp[1] * p[0] + mvp[5] * p[1] + mvp[9] * p[2] + mvp[13];
+    const w = mvp[3] * p[0] + mvp[7] * p[1] + mvp[11] * p[2] + mvp[15];
+    if (w <= 0) return null;
+    return {
+        x: (x / w + 1) / 2 * tree_canvas.clientWidth,
+        y: (1 - y / w) / 2 * tree_canvas.clientHeight,
+    };
+}
+
+// --- WebGL setup ---
+
+const VERTEX_SHADER = `
+    attribute vec3 position;
+    attribute vec3 color;
+    uniform mat4 mvp;
+    uniform float point_size;
+    varying vec3 v_color;
+    void main() {
+        gl_Position = mvp * vec4(position, 1.0);
+        gl_PointSize = point_size / gl_Position.w;
+        v_color = color;
+    }
+`;
+
+const FRAGMENT_SHADER = `
+    precision mediump float;
+    uniform bool ring;
+    varying vec3 v_color;
+    void main() {
+        float r = length(gl_PointCoord - vec2(0.5));
+        if (r > 0.5 || (ring && r < 0.35)) discard;
+        // a bit of glow towards the center
+        float glow = ring ? 1.0 : 1.0 - 0.6 * r;
+        gl_FragColor = vec4(v_color * glow, 1.0);
+    }
+`;
+
+function compile(type, source) {
+    const shader = gl.createShader(type);
+    gl.shaderSource(shader, source);
+    gl.compileShader(shader);
+    if (!gl.getShaderParameter(shader, gl.COMPILE_STATUS)) {
+        throw new Error(gl.getShaderInfoLog(shader));
+    }
+    return shader;
+}
+
+const program = gl.createProgram();
+gl.attachShader(program, compile(gl.VERTEX_SHADER, VERTEX_SHADER));
+gl.attachShader(program, compile(gl.FRAGMENT_SHADER, FRAGMENT_SHADER));
+gl.linkProgram(program);
+gl.useProgram(program);
+
+const position_attribute = gl.getAttribLocation(program, "position");
+const color_attribute = gl.getAttribLocation(program, "color");
+const mvp_uniform = gl.getUniformLocation(program, "mvp");
+const point_size_uniform = gl.getUniformLocation(program, "point_size");
+const ring_uniform = gl.getUniformLocation(program, "ring");
+const position_buffer = gl.createBuffer();
+const color_buffer = gl.createBuffer();
+const selection_position_buffer = gl.createBuffer();
+const selection_color_buffer = gl.createBuffer();
+
+gl.enable(gl.DEPTH_TEST);
+gl.clearColor(0.063, 0.063, 0.094, 1);
+
+function resize() {
+    const ratio = window.devicePixelRatio || 1;
+    for (const canvas of [tree_canvas, label_canvas]) {
+        canvas.width = canvas.clientWidth * ratio;
+        canvas.height = canvas.clientHeight * ratio;
+    }
+    label_context.setTransform(ratio, 0, 0, ratio, 0, 0);
+    gl.viewport(0, 0, tree_canvas.width, tree_canvas.height);
+}
+
+function draw_points(position_data, color_data, count, size, ring) {
+    gl.bindBuffer(gl.ARRAY_BUFFER, position_data);
+    gl.enableVertexAttribArray(position_attribute);
+    gl.vertexAttribPointer(position_attribute, 3, gl.FLOAT, false, 0, 0);
+    gl.bindBuffer(gl.ARRAY_BUFFER, color_data);
+    gl.enableVertexAttribArray(color_attribute);
+    gl.vertexAttribPointer(color_attribute, 3, gl.FLOAT, false, 0, 0);
+    gl.uniform1f(point_size_uniform, size);
+    gl.uniform1i(ring_uniform, ring ? 1 : 0);
+    gl.drawArrays(gl.POINTS, 0, count);
+}
+
+function render() {
+    const aspect = tree_canvas.clientWidth / Math.max(tree_canvas.clientHeight, 1);
+    const eye = [
+        view.distance * Math.cos(view.pitch) * Math.sin(view.yaw),
+        -view.distance * Math.cos(view.pitch) * Math.cos(view.yaw),
+        view.distance * Math.sin(view.pitch),
+    ];
+    mvp = multiply(perspective(Math.PI / 4, aspect, 0.1, 100), look_at(eye));
+
+    gl.clear(gl.COLOR_BUFFER_BIT | gl.DEPTH_BUFFER_BIT);
+    gl.uniformMatrix4fv(mvp_uniform, false, new Float32Array(mvp));
+    // gl_PointSize is in device pixels
+    const pixels_per_unit = tree_canvas.height / 2 / Math.tan(Math.PI / 8);
+    const size = POINT_SIZE * pixels_per_unit;
+
+    const color_data = new Float32Array(positions.length * 3);
+    for (let i = 0; i < positions.length; i++) {
+        const rgb = colors.subarray(i * 3, i * 3 + 3);
+        const off = rgb.length < 3 || rgb[0] + rgb[1] + rgb[2] === 0;
+        for (let c = 0; c < 3; c++) {
+            color_data[i * 3 + c] = off ? OFF_COLOR[c] : rgb[c] / 255;
+        }
+    }
+    gl.bindBuffer(gl.ARRAY_BUFFER, color_buffer);
+    gl.bufferData(gl.ARRAY_BUFFER, color_data, gl.DYNAMIC_DRAW);
+    draw_points(position_buffer, color_buffer, positions.length, size, false);
+
+    if (selected !== null && selected < positions.length) {
+        gl.bindBuffer(gl.ARRAY_BUFFER, selection_position_buffer);
+        gl.bufferData(gl.ARRAY_BUFFER, new Float32Array(positions[selected]), gl.DYNAMIC_DRAW);
+        gl.bindBuffer(gl.ARRAY_BUFFER, selection_color_buffer);
+        gl.bufferData(gl.ARRAY_BUFFER, new Float32Array([1, 1, 1]), gl.DYNAMIC_DRAW);
+        gl.disable(gl.DEPTH_TEST);
+        draw_points(selection_position_buffer, selection_color_buffer, 1, size * 2, true);
+        gl.enable(gl.DEPTH_TEST);
+    }
+
+    draw_labels();
+    update_selection_panel();
+    requestAnimationFrame(render);
+}
+
+function draw_labels() {
+    label_context.clearRect(0, 0, label_canvas.clientWidth, label_canvas.clientHeight);
+    label_context.font = "11px Arial";
+    label_context.fillStyle = "#e0e0e0";
+    for (let i = 0; i < positions.length; i++) {
+        if (!labels_checkbox.checked && i !== selected) continue;
+        const p = project(positions[i]);
+        if (p !== null) {
+            label_context.fillText(String(i), p.x + 6, p.y - 6);
+        }
+    }
+}
+
+function update_selection_panel() {
+    if (selected === null || selected >= positions.length) {
+        selection_panel.classList.add("hidden");
+        return;
+    }
+    const [x, y, z] = raw_positions[selected].map((v) => v.toFixed(3));
+    const rgb = Array.from(colors.subarray(selected * 3, selected * 3 + 3));
+    const hex = rgb.map((c) => c.toString(16).padStart(2, "0")).join("");
+    selection_panel.textContent = `LED ${selected}\nposition ${x}, ${y}, ${z}\ncolor #${hex}`;
+    selection_panel.classList.remove("hidden");
+}
+
+// --- positions ---
+
+function positions_url() {
+    return positions_select.value === "actual" ? "sim/actual_positions" : "get_saved_led_positions";
+}
+
+// `{"0": [x, y, z], ...}` to `[[x, y, z], ...]`
+async function fetch_positions(url) {
+    const response = await fetch(url);
+    if (!response.ok) return null;
+    const data = await response.json();
+    const result = [];
+    for (const [key, value] of Object.entries(data)) {
+        result[Number(key)] = value;
+    }
+    return result;
+}
+
+// moves the tree to the origin and scales it to fit into [-1, 1]
+function normalize_positions(raw) {
+    if (raw.length === 0) return [];
+    const min = [Infinity, Infinity, Infinity];
+    const max = [-Infinity, -Infinity, -Infinity];
+    for (const p of raw) {
+        for (let c = 0; c < 3; c++) {
+            min[c] = Math.min(min[c], p[c]);
+            max[c] = Math.max(max[c], p[c]);
+        }
+    }
+    const center = min.map((v, c) => (v + max[c]) / 2);
+    const extent = Math.max(...max.map((v, c) => v - min[c]), 1e-6);
+    return raw.map((p) => p.map((v, c) => ((v - center[c]) / extent) * 2));
+}
+
+async function load_positions() {
+    const raw = await fetch_positions(positions_url());
+    if (raw === null) return;
+    raw_positions = raw;
+    positions = normalize_positions(raw);
+    gl.bindBuffer(gl.ARRAY_BUFFER, position_buffer);
+    gl.bufferData(gl.ARRAY_BUFFER, new Float32Array(positions.flat()), gl.STATIC_DRAW);
+}
+
+// picks the positions to start with: the actual ones if nothing is calibrated yet
+async function choose_positions() {
+    const actual = await fetch_positions("sim/actual_positions");
+    if (actual === null) {
+        // not the simulator, there are no actual positions
+        document.getElementById("actual-option").remove();
+        return;
+    }
+    const determined = await fetch_positions("get_saved_led_positions");
+    const calibrated = determined !== null && determined.some((p) => p.some((v) => v !== 0));
+    positions_select.value = calibrated ? "determined" : "actual";
+}
+
+// --- live colors ---
+
+function connect() {
+    const url = new URL("ws/leds?format=binary&fps=30", window.location.href);
+    url.protocol = url.protocol === "https:" ? "wss:" : "ws:";
+    const socket = new WebSocket(url);
+    socket.binaryType = "arraybuffer";
+    socket.onopen = () => {
+        status_text.textContent = "live";
+    };
+    socket.onmessage = (event) => {
+        if (event.data instanceof ArrayBuffer) {
+            colors = new Uint8Array(event.data);
+            return;
+        }
+        const message = JSON.parse(event.data);
+        if (message.type === "positions_changed" || message.type === "num_leds_changed") {
+            load_positions();
+        }
+    };
+    socket.onclose = () => {
+        status_text.textContent = "disconnected, retrying...";
+        setTimeout(connect, 1000);
+    };
+}
+
+// --- orbit controls and selection ---
+
+const pointers = new Map();
+let pinch_distance = null;
+let dragged = false;
+
+function pinch_span() {
+    const [a, b] = [...pointers.values()];
+    return Math.hypot(a.x - b.x, a.y - b.y);
+}
+
+function zoom(factor) {
+    view.distance = Math.min(Math.max(view.distance * factor, 1), 20);
+}
+
+tree_canvas.addEventListener("pointerdown", (e) => {
+    tree_canvas.setPointerCapture(e.pointerId);
+    pointers.set(e.pointerId, { x: e.clientX, y: e.clientY });
+    dragged = false;
+    pinch_distance = pointers.size === 2 ? pinch_span() : null;
+});
+
+tree_canvas.addEventListener("pointermove", (e) => {
+    const last = pointers.get(e.pointerId);
+    if (last === undefined) return;
+    const dx = e.clientX - last.x;
+    const dy = e.clientY - last.y;
+    pointers.set(e.pointerId, { x: e.clientX, y: e.clientY });
+    if (Math.abs(dx) + Math.abs(dy) > 2) dragged = true;
+
+    if (pointers.size === 1) {
+        view.yaw -= dx * 0.01;
+        view.pitch = Math.min(Math.max(view.pitch + dy * 0.01, -1.5), 1.5);
+    } else if (pointers.size === 2 && pinch_distance !== null) {
+        const span = pinch_span();
+        zoom(pinch_distance / Math.max(span, 1));
+        pinch_distance = span;
+    }
+});
+
+function pointer_released(e) {
+    pointers.delete(e.pointerId);
+    pinch_distance = null;
+}
+
+tree_canvas.addEventListener("pointerup", (e) => {
+    if (pointers.size === 1 && !dragged) {
+        select_at(e.clientX, e.clientY);
+    }
+    pointer_released(e);
+});
+tree_canvas.addEventListener("pointercancel", pointer_released);
+
+tree_canvas.addEventListener("wheel", (e) => {
+    e.preventDefault();
+    zoom(Math.exp(e.deltaY * 0.001));
+}, { passive: false });
+
+// selects the LED closest to the click, or nothing if there is none close by
+function select_at(client_x, client_y) {
+    const rect = tree_canvas.getBoundingClientRect();
+    const x = client_x - rect.left;
+    const y = client_y - rect.top;
+    let best = null;
+    let best_distance = 20;  // pixels
+    for (let i = 0; i < positions.length; i++) {
+        const p = project(positions[i]);
+        if (p === null) continue;
+        const d = Math.hypot(p.x - x, p.y - y);
+        if (d < best_distance) {
+            best = i;
+            best_distance = d;
+        }
+    }
+    selected = best;
+}
+
+positions_select.addEventListener("change", load_positions);
+document.getElementById("reset-view-btn").addEventListener("click", () => {
+    view = { ...DEFAULT_VIEW };
+});
+window.addEventListener("resize", resize);
+
+resize();
+await choose_positions();
+await load_positions();
+connect();
+requestAnimationFrame(render);